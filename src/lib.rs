@@ -3,15 +3,24 @@ use eframe::egui;
 use image::imageops::FilterType;
 use image::ImageEncoder;
 use rayon::prelude::*;
-use std::collections::HashMap;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::{Arc, RwLock};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 use std::fs;
 use std::io::{self, Write};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::WalkDir;
 
+pub mod wallpaper;
+
+use wallpaper::{Backend, FitMode, Monitor, Runner, SpanPlan, WallpaperSetter};
+
 #[derive(Debug, thiserror::Error)]
 pub enum BackgroundPickerError {
     #[error("Failed to create thread pool: {0}")]
@@ -29,23 +38,82 @@ pub enum BackgroundPickerError {
     #[error("Failed to save selected image path: {0}")]
     SaveSelectedImage(std::io::Error),
     
+    #[error("Failed to save state file: {0}")]
+    SaveState(std::io::Error),
+    
+    #[error("Failed to read history file: {0}")]
+    LoadHistory(std::io::Error),
+    
+    #[error("Failed to save history file: {0}")]
+    SaveHistory(std::io::Error),
+    
     #[error("Command execution failed: {0}")]
     CommandExecution(String),
     
+    #[error("Command failed: {0}")]
+    CommandFailed(CommandFailure),
+    
     #[error("Invalid image file: {0}")]
     InvalidImageFile(PathBuf),
     
+    #[error("Failed to split {path} across monitors: {source}")]
+    SpanSlicing {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    
+    #[error("No image for monitor {0}; the backend sets every monitor at once")]
+    IncompleteSelection(String),
+    
+    #[error("No wallpaper recorded and no images found in {0}")]
+    NothingToRestore(PathBuf),
+    
     #[error("Lock acquisition failed")]
     LockAcquisition,
 }
 
+/// What went wrong when running the wallpaper command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandFailure {
+    pub command_line: String,
+    /// Exit status, or why the command could not be started
+    pub status: String,
+    pub stderr: String,
+}
+
+impl CommandFailure {
+    /// Everything about the failure as plain text, for pasting into a bug report
+    pub fn details(&self) -> String {
+        format!("Command: {}\nStatus: {}\nStderr:\n{}", self.command_line, self.status, self.stderr.trim_end())
+    }
+}
+
+impl std::fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` {}", self.command_line, self.status)?;
+        match self.stderr.lines().find(|line| !line.trim().is_empty()) {
+            Some(line) => write!(f, ": {}", line.trim()),
+            None => Ok(()),
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, BackgroundPickerError>;
 
 const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "webp"];
-const DEFAULT_PRELOAD_COUNT: usize = 8;
+/// Rows laid out (and thumbnails requested) beyond each edge of the viewport
+const PREFETCH_ROWS: usize = 2;
 const CHUNK_SIZE: usize = 100;
 const MIN_THREAD_COUNT: usize = 4;
 const PROGRESS_THRESHOLD: usize = 50;
+const ASPECT_RATIO_TOLERANCE: f32 = 0.03;
+const RECENTLY_USED_LIMIT: usize = 100;
+/// History entries kept; the oldest are dropped beyond this
+pub const HISTORY_LIMIT: usize = 500;
+pub const DEFAULT_THUMBNAIL_SIZE: u32 = 150;
+pub const MIN_THUMBNAIL_SIZE: u32 = 64;
+pub const MAX_THUMBNAIL_SIZE: u32 = 1024;
+const ZOOM_SAVE_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Parser, Clone)]
 #[command(name = "background-picker")]
@@ -54,11 +122,14 @@ pub struct Args {
     #[arg(short, long, default_value = ".")]
     pub directory: PathBuf,
     
-    #[arg(short, long, default_value = "150")]
-    pub thumbnail_size: u32,
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(MIN_THUMBNAIL_SIZE as i64..=MAX_THUMBNAIL_SIZE as i64), help = "Thumbnail size in pixels, 64 to 1024 [default: 150, or the remembered zoom]")]
+    pub thumbnail_size: Option<u32>,
     
-    #[arg(short, long, default_value = "feh --bg-max")]
-    pub command: String,
+    #[arg(short, long, help = "Custom command to set the background, used instead of a built-in backend")]
+    pub command: Option<String>,
+    
+    #[arg(long, value_enum, help = "Built-in wallpaper backend [default: detected from the desktop session]")]
+    pub backend: Option<Backend>,
     
     #[arg(short, long, default_value = "selected-background.txt")]
     pub selected_image_file: PathBuf,
@@ -66,187 +137,1723 @@ pub struct Args {
     #[arg(long, help = "Enable debug output")]
     pub debug: bool,
     
-    #[arg(long, help = "Pre-generate all thumbnails and exit (don't show GUI)")]
+    #[arg(long, group = "mode", help = "Pre-generate all thumbnails and exit (don't show GUI)")]
     pub pregenerate: bool,
+    
+    #[arg(long, group = "mode", help = "Reapply the last selected wallpaper and exit (don't show GUI); picks a random image if none was recorded")]
+    pub restore: bool,
+    
+    #[arg(long, group = "mode", help = "Go back to the wallpaper set before the last one and exit (don't show GUI)")]
+    pub undo: bool,
+    
+    #[arg(long, group = "mode", help = "Reapply what the last --undo took back and exit (don't show GUI)")]
+    pub redo: bool,
+    
+    #[arg(long, group = "mode", value_name = "N", num_args = 0..=1, help = "List applied wallpapers, newest first, and exit; with N, reapply entry N")]
+    pub history: Option<Option<usize>>,
+    
+    #[arg(long, default_value = "background-picker-history.yaml", help = "File logging every applied wallpaper")]
+    pub history_file: PathBuf,
+    
+    #[arg(long, help = "Hide images narrower than this many pixels")]
+    pub min_width: Option<u32>,
+    
+    #[arg(long, help = "Hide images shorter than this many pixels")]
+    pub min_height: Option<u32>,
+    
+    #[arg(long, help = "Only show images with this aspect ratio, e.g. 21:9, 1.78 or 16:9-21:9")]
+    pub aspect_ratio: Option<AspectRatioRange>,
+    
+    #[arg(long, help = "Only show images at least as large as the screen and of the same shape")]
+    pub fit_screen: bool,
+    
+    #[arg(long, value_enum, help = "Order of images within a folder [default: last used, or name]")]
+    pub sort: Option<SortMode>,
+    
+    #[arg(long, default_value = "background-picker-state.yaml", help = "File used to remember settings between sessions")]
+    pub state_file: PathBuf,
+    
+    #[arg(long, help = "Keep the picker open after applying a wallpaper")]
+    pub stay_open: bool,
+    
+    #[arg(long, value_enum, help = "Thumbnail resampling quality [default: last used, or balanced]")]
+    pub quality: Option<ThumbnailQuality>,
+    
+    #[arg(long, value_enum, help = "How the wallpaper is scaled to the screen [default: last used, or fit]")]
+    pub fit: Option<FitMode>,
+    
+    #[arg(long, help = "Stretch each wallpaper across all monitors")]
+    pub span: bool,
+    
+    #[arg(long, default_value_t = 0, help = "Pixels of image hidden behind the bezels between monitors when spanning")]
+    pub bezel: u32,
+    
+    #[arg(long, default_value_t = wallpaper::DEFAULT_COMMAND_TIMEOUT.as_secs(), help = "Seconds to wait for the wallpaper command before killing it (0 waits forever)")]
+    pub timeout: u64,
+    
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var, help = "Extra environment variable for the wallpaper command (repeatable)")]
+    pub env: Vec<(String, String)>,
+    
+    #[arg(long, help = "Working directory for the wallpaper command")]
+    pub working_dir: Option<PathBuf>,
+    
+    #[arg(long = "hook", value_name = "[POLICY:]COMMAND", value_parser = parse_hook, help = "Command run after a wallpaper is applied (repeatable); POLICY is ignore, warn (default) or stop")]
+    pub hooks: Vec<Hook>,
 }
 
+impl Args {
+    /// Whether a mode that does its work and exits, rather than opening the picker, was chosen
+    pub fn runs_without_gui(&self) -> bool {
+        self.pregenerate || self.restore || self.undo || self.redo || self.history.is_some()
+    }
+}
 
-#[derive(Clone)]
-pub struct ImageInfo {
-    pub path: PathBuf,
-    pub thumbnail: Option<egui::TextureHandle>,
-    pub relative_path: String,
-    pub loading: bool,
+/// What happens when a post-apply hook fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HookPolicy {
+    /// Only shown in debug output
+    Ignore,
+    /// Reported, and the remaining hooks still run
+    #[default]
+    Warn,
+    /// Reported, and the remaining hooks are skipped
+    Stop,
 }
 
-pub struct BackgroundPickerApp {
-    pub args: Args,
-    pub images: Arc<RwLock<Vec<ImageInfo>>>,
-    pub folder_tree: HashMap<String, Vec<usize>>,
-    pub loading: bool,
-    pub thumbnail_sender: std::sync::mpsc::Sender<(usize, egui::ColorImage)>,
-    pub thumbnail_receiver: std::sync::mpsc::Receiver<(usize, egui::ColorImage)>,
-    pub thread_pool: rayon::ThreadPool,
-    pub cache_dir: PathBuf,
+/// Command run after a wallpaper has been applied, such as pywal or a notification
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hook {
+    pub command: String,
+    pub policy: HookPolicy,
 }
 
-impl BackgroundPickerApp {
-    pub fn new(_cc: &eframe::CreationContext<'_>, args: Args) -> Result<Self> {
-        let (thumbnail_sender, thumbnail_receiver) = std::sync::mpsc::channel();
-        
-        // Create thread pool with optimal number of threads
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(num_cpus::get().max(MIN_THREAD_COUNT))
-            .build()?;
-        
-        // Set up thumbnail cache directory (freedesktop.org spec)
-        let cache_dir = Self::get_thumbnail_cache_dir()?;
-        if args.debug {
-            println!("Using thumbnail cache directory: {:?}", cache_dir);
+/// How images are ordered within a folder. Folders themselves always sort by name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortMode {
+    #[default]
+    Name,
+    Modified,
+    Size,
+    Resolution,
+    AspectRatio,
+    RecentlyUsed,
+    Random,
+}
+
+impl SortMode {
+    pub const ALL: [SortMode; 7] = [
+        SortMode::Name,
+        SortMode::Modified,
+        SortMode::Size,
+        SortMode::Resolution,
+        SortMode::AspectRatio,
+        SortMode::RecentlyUsed,
+        SortMode::Random,
+    ];
+    
+    pub fn label(self) -> &'static str {
+        match self {
+            SortMode::Name => "Name",
+            SortMode::Modified => "Newest first",
+            SortMode::Size => "Largest file first",
+            SortMode::Resolution => "Highest resolution first",
+            SortMode::AspectRatio => "Widest first",
+            SortMode::RecentlyUsed => "Recently used first",
+            SortMode::Random => "Random",
         }
-        
-        let mut app = Self {
-            args,
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: HashMap::new(),
-            loading: true,
-            thumbnail_sender,
-            thumbnail_receiver,
-            thread_pool,
-            cache_dir,
+    }
+}
+
+/// Compares images according to a `SortMode`, falling back to natural name
+/// order for ties and for images missing the relevant metadata.
+pub struct ImageSorter {
+    mode: SortMode,
+    recently_used: HashMap<PathBuf, usize>,
+    seed: u64,
+}
+
+impl ImageSorter {
+    pub fn new(mode: SortMode, recently_used: &[PathBuf], seed: u64) -> Self {
+        Self {
+            mode,
+            recently_used: recently_used.iter()
+                .enumerate()
+                .map(|(rank, path)| (path.clone(), rank))
+                .collect(),
+            seed,
+        }
+    }
+    
+    pub fn compare(&self, a: &ImageInfo, b: &ImageInfo) -> Ordering {
+        let by_mode = match self.mode {
+            SortMode::Name => Ordering::Equal,
+            SortMode::Modified => b.modified.cmp(&a.modified),
+            SortMode::Size => b.file_size.cmp(&a.file_size),
+            SortMode::Resolution => {
+                let pixels = |image: &ImageInfo| image.dimensions.map(|(w, h)| w as u64 * h as u64);
+                pixels(b).cmp(&pixels(a))
+            }
+            SortMode::AspectRatio => {
+                let ratio = |image: &ImageInfo| image.dimensions
+                    .filter(|&(_, h)| h > 0)
+                    .map(|(w, h)| w as f32 / h as f32);
+                ratio(b).partial_cmp(&ratio(a)).unwrap_or(Ordering::Equal)
+            }
+            SortMode::RecentlyUsed => {
+                // Unused images have no rank and sort after all used ones
+                let rank = |image: &ImageInfo| self.recently_used.get(&image.path).copied().unwrap_or(usize::MAX);
+                rank(a).cmp(&rank(b))
+            }
+            SortMode::Random => self.shuffle_key(a).cmp(&self.shuffle_key(b)),
         };
-        
-        app.scan_images()?;
-        
-        if app.args.pregenerate {
-            app.pregenerate_all_thumbnails()?;
-            // Exit after pregeneration, don't show GUI
-            std::process::exit(0);
+        by_mode.then_with(|| natural_cmp(&a.relative_path, &b.relative_path))
+    }
+    
+    fn shuffle_key(&self, image: &ImageInfo) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.seed.hash(&mut hasher);
+        image.path.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// How an image is fitted into its square grid cell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TileMode {
+    /// Show the whole image, with bars on the short sides
+    #[default]
+    Letterbox,
+    /// Fill the cell, cutting off the edges of the long side
+    Crop,
+}
+
+impl TileMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            TileMode::Letterbox => "Fit",
+            TileMode::Crop => "Crop",
         }
-        
-        Ok(app)
     }
+}
+
+/// Largest rect with the given aspect ratio (width / height) centred in `cell`
+pub fn letterbox_rect(cell: egui::Rect, aspect: f32) -> egui::Rect {
+    if aspect <= 0.0 || !aspect.is_finite() {
+        return cell;
+    }
+    let size = if aspect >= cell.aspect_ratio() {
+        egui::vec2(cell.width(), cell.width() / aspect)
+    } else {
+        egui::vec2(cell.height() * aspect, cell.height())
+    };
+    egui::Rect::from_center_size(cell.center(), size)
+}
+
+/// Texture coordinates of the centred square of an image with the given aspect ratio
+pub fn crop_uv(aspect: f32) -> egui::Rect {
+    if aspect <= 0.0 || !aspect.is_finite() {
+        return egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+    }
+    let (width, height) = if aspect >= 1.0 { (1.0 / aspect, 1.0) } else { (1.0, aspect) };
+    egui::Rect::from_center_size(egui::pos2(0.5, 0.5), egui::vec2(width, height))
+}
+
+/// Resampling quality for thumbnails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThumbnailQuality {
+    /// Triangle filter after an area-averaging pre-shrink
+    Fast,
+    /// Catmull-Rom after an area-averaging pre-shrink
+    #[default]
+    Balanced,
+    /// Lanczos3 straight from the full image
+    High,
+}
+
+impl ThumbnailQuality {
+    pub const ALL: [ThumbnailQuality; 3] = [ThumbnailQuality::Fast, ThumbnailQuality::Balanced, ThumbnailQuality::High];
     
-    pub fn get_thumbnail_cache_dir() -> Result<PathBuf> {
-        // Use freedesktop.org thumbnail specification
-        let cache_home = dirs::cache_dir()
-            .or_else(|| dirs::home_dir().map(|h| h.join(".cache")))
-            .unwrap_or_else(|| PathBuf::from(".cache"));
-            
-        let normal_dir = cache_home.join("thumbnails").join("normal");
-        
-        // Create the directory structure if it doesn't exist
-        fs::create_dir_all(&normal_dir)
-            .map_err(BackgroundPickerError::CacheDirectoryCreation)?;
-        
-        Ok(normal_dir)
+    pub fn label(self) -> &'static str {
+        match self {
+            ThumbnailQuality::Fast => "Fast",
+            ThumbnailQuality::Balanced => "Balanced",
+            ThumbnailQuality::High => "High",
+        }
     }
     
-    pub fn find_existing_thumbnail(file_path: &Path) -> Option<PathBuf> {
-        // Look for existing thumbnails in multiple sizes
-        let cache_home = dirs::cache_dir()?;
-        let thumbnails_dir = cache_home.join("thumbnails");
-        
-        let hash = Self::get_thumbnail_hash(file_path)?;
-        let thumbnail_name = format!("{}.png", hash);
-        
-        // Check in order of preference: normal (128x128), large (256x256), then fail
-        for size_dir in &["normal", "large"] {
-            let thumbnail_path = thumbnails_dir.join(size_dir).join(&thumbnail_name);
-            if thumbnail_path.exists() && Self::is_thumbnail_cache_valid_static(file_path, &thumbnail_path) {
-                return Some(thumbnail_path);
-            }
+    pub fn filter(self) -> FilterType {
+        match self {
+            ThumbnailQuality::Fast => FilterType::Triangle,
+            ThumbnailQuality::Balanced => FilterType::CatmullRom,
+            ThumbnailQuality::High => FilterType::Lanczos3,
         }
-        
-        None
     }
     
-    pub fn get_thumbnail_hash(file_path: &Path) -> Option<String> {
-        // Generate SHA1 hash of file URI as per freedesktop.org thumbnail spec
-        // This matches exactly what pcmanfm and other file managers use
-        let canonicalized = fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
-        let file_uri = format!("file://{}", canonicalized.to_string_lossy());
-        
-        use sha1::{Digest, Sha1};
-        let mut hasher = Sha1::new();
-        hasher.update(file_uri.as_bytes());
-        let result = hasher.finalize();
-        Some(format!("{:x}", result))
+    /// Suffix for cached files. The default quality uses the plain spec name so
+    /// thumbnails are shared with file managers; the others get their own files.
+    pub fn cache_suffix(self) -> &'static str {
+        match self {
+            ThumbnailQuality::Fast => "-fast",
+            ThumbnailQuality::Balanced => "",
+            ThumbnailQuality::High => "-high",
+        }
     }
+}
+
+/// Thumbnail sizes from the freedesktop.org thumbnail spec, each with its own cache directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThumbnailBucket {
+    Normal,
+    Large,
+    XLarge,
+    XxLarge,
+}
+
+impl ThumbnailBucket {
+    pub const ALL: [ThumbnailBucket; 4] = [
+        ThumbnailBucket::Normal,
+        ThumbnailBucket::Large,
+        ThumbnailBucket::XLarge,
+        ThumbnailBucket::XxLarge,
+    ];
     
+    /// Smallest bucket that holds tiles of `size` pixels without upscaling
+    pub fn for_size(size: u32) -> Self {
+        Self::ALL.into_iter()
+            .find(|bucket| bucket.pixels() >= size)
+            .unwrap_or(ThumbnailBucket::XxLarge)
+    }
     
+    pub fn pixels(self) -> u32 {
+        match self {
+            ThumbnailBucket::Normal => 128,
+            ThumbnailBucket::Large => 256,
+            ThumbnailBucket::XLarge => 512,
+            ThumbnailBucket::XxLarge => 1024,
+        }
+    }
     
-    pub fn scan_images(&mut self) -> Result<()> {
-        let base_path = &self.args.directory;
-        
-        // Clear existing data
-        {
-            let mut images = self.images.write()
-                .map_err(|_| BackgroundPickerError::LockAcquisition)?;
-            images.clear();
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            ThumbnailBucket::Normal => "normal",
+            ThumbnailBucket::Large => "large",
+            ThumbnailBucket::XLarge => "x-large",
+            ThumbnailBucket::XxLarge => "xx-large",
         }
-        self.folder_tree.clear();
-        
-        if self.args.debug {
-            println!("Scanning directory: {:?}", base_path);
+    }
+}
+
+/// Settings remembered between sessions, stored as YAML in `--state-file`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PickerState {
+    pub sort_mode: SortMode,
+    /// Seed for the random sort, kept so the shuffle is stable until reshuffled
+    pub shuffle_seed: u64,
+    /// Applied wallpapers, most recent first
+    pub recently_used: Vec<PathBuf>,
+    /// Quick favorites bound to the number keys 1-9
+    pub favorites: std::collections::BTreeMap<u8, PathBuf>,
+    /// Tile size chosen with the zoom control
+    pub thumbnail_size: Option<u32>,
+    pub quality: ThumbnailQuality,
+    pub tile_mode: TileMode,
+    /// How wallpapers are scaled to the screen
+    pub fit: FitMode,
+}
+
+impl PickerState {
+    /// Load saved state, falling back to defaults if the file is missing or unreadable.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_yaml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+    
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(BackgroundPickerError::SaveState)?;
         }
         
-        // Pre-allocate collections to avoid repeated reallocations
-        let mut temp_images = Vec::new();
-        let mut temp_folders: HashMap<String, Vec<usize>> = HashMap::new();
-        
-        // Collect all image files first
-        for entry in WalkDir::new(&self.args.directory)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| e.file_type().is_file())
-        {
-            if let Some(ext) = entry.path().extension() {
-                let ext_str = ext.to_string_lossy();
-                if IMAGE_EXTENSIONS.iter().any(|&valid_ext| valid_ext.eq_ignore_ascii_case(&ext_str)) {
-                    let relative_path = entry.path()
-                        .strip_prefix(base_path)
-                        .map(|p| p.to_string_lossy().into_owned())
-                        .unwrap_or_else(|_| entry.path().to_string_lossy().into_owned());
-                    
-                    let folder = entry.path()
-                        .parent()
-                        .and_then(|p| p.strip_prefix(base_path).ok())
-                        .map(|p| p.to_string_lossy().into_owned())
-                        .unwrap_or_else(|| ".".to_owned());
-                    
-                    let image_index = temp_images.len();
-                    temp_images.push(ImageInfo {
-                        path: entry.path().to_path_buf(),
-                        thumbnail: None,
-                        relative_path,
-                        loading: false,
-                    });
-                    
-                    temp_folders
-                        .entry(folder)
-                        .or_default()
-                        .push(image_index);
-                }
-            }
+        let contents = serde_yaml::to_string(self)
+            .map_err(|e| BackgroundPickerError::SaveState(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        fs::write(path, contents).map_err(BackgroundPickerError::SaveState)
+    }
+    
+    pub fn record_used(&mut self, path: &Path) {
+        self.recently_used.retain(|used| used != path);
+        self.recently_used.insert(0, path.to_path_buf());
+        self.recently_used.truncate(RECENTLY_USED_LIMIT);
+    }
+    
+    pub fn sorter(&self) -> ImageSorter {
+        ImageSorter::new(self.sort_mode, &self.recently_used, self.shuffle_seed)
+    }
+}
+
+/// Every applied wallpaper, oldest first, stored as YAML in `--history-file`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct History {
+    pub entries: Vec<HistoryEntry>,
+    /// How many of the newest entries have been stepped back over with undo
+    pub undone: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub path: PathBuf,
+    pub backend: String,
+    /// Monitor it was set on, `None` for all of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monitor: Option<String>,
+    /// Sliced across all monitors
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub span: bool,
+}
+
+impl HistoryEntry {
+    pub fn age(&self) -> std::time::Duration {
+        (SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(self.timestamp))
+            .elapsed()
+            .unwrap_or_default()
+    }
+    
+    /// Where it was set, for listings
+    pub fn target_label(&self) -> String {
+        match (&self.monitor, self.span) {
+            (_, true) => "spanned".to_owned(),
+            (Some(monitor), false) => monitor.clone(),
+            (None, false) => "all monitors".to_owned(),
         }
-        
-        // Update the main data structures
-        {
-            let mut images = self.images.write()
-                .map_err(|_| BackgroundPickerError::LockAcquisition)?;
-            *images = temp_images;
+    }
+}
+
+impl History {
+    /// Load the history, starting empty if the file is missing. A file that
+    /// can't be read or parsed is an error, so it isn't overwritten.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(BackgroundPickerError::LoadHistory(e)),
+        };
+        serde_yaml::from_str(&contents)
+            .map_err(|e| BackgroundPickerError::LoadHistory(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+    
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(BackgroundPickerError::SaveHistory)?;
         }
-        self.folder_tree = temp_folders;
         
-        if self.args.debug {
-            println!("Found {} images in {} folders", 
-                self.images.read().map(|i| i.len()).unwrap_or(0), 
-                self.folder_tree.len());
+        let contents = serde_yaml::to_string(self)
+            .map_err(|e| BackgroundPickerError::SaveHistory(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        write_atomically(path, contents.as_bytes()).map_err(BackgroundPickerError::SaveHistory)
+    }
+    
+    /// Add an applied wallpaper. Entries that were undone are dropped, as in an
+    /// editor, and the oldest go once there are more than `HISTORY_LIMIT`.
+    pub fn record(&mut self, entry: HistoryEntry) {
+        self.entries.truncate(self.position());
+        self.entries.push(entry);
+        self.undone = 0;
+        let excess = self.entries.len().saturating_sub(HISTORY_LIMIT);
+        self.entries.drain(..excess);
+    }
+    
+    /// Number of entries currently in effect
+    pub fn position(&self) -> usize {
+        self.entries.len().saturating_sub(self.undone)
+    }
+    
+    /// The first `position` entries from the last one that covered every
+    /// monitor, which is all that's needed to rebuild the desktop at that point
+    pub fn in_effect(&self, position: usize) -> &[HistoryEntry] {
+        let entries = &self.entries[..position.min(self.entries.len())];
+        let start = entries.iter().rposition(|entry| entry.monitor.is_none()).unwrap_or(0);
+        &entries[start..]
+    }
+}
+
+/// The Recent window, listing the history.
+#[derive(Debug, Default)]
+pub struct RecentView {
+    pub open: bool,
+    pub history: History,
+    /// Why the history file couldn't be read; it's left alone until fixed
+    pub error: Option<String>,
+}
+
+/// Inclusive range of accepted width/height ratios.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AspectRatioRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl AspectRatioRange {
+    /// A narrow range around a single ratio, allowing for images a few pixels off.
+    pub fn around(ratio: f32) -> Self {
+        Self {
+            min: ratio * (1.0 - ASPECT_RATIO_TOLERANCE),
+            max: ratio * (1.0 + ASPECT_RATIO_TOLERANCE),
         }
-        
-        self.loading = false;
-        Ok(())
+    }
+    
+    pub fn contains(&self, ratio: f32) -> bool {
+        ratio >= self.min && ratio <= self.max
+    }
+    
+    /// Common monitor shapes offered in the GUI
+    pub fn presets() -> Vec<(&'static str, AspectRatioRange)> {
+        vec![
+            ("Portrait", AspectRatioRange { min: 0.0, max: 1.0 }),
+            ("4:3", Self::around(4.0 / 3.0)),
+            ("16:10", Self::around(16.0 / 10.0)),
+            ("16:9", Self::around(16.0 / 9.0)),
+            ("21:9", Self::around(21.0 / 9.0)),
+            ("32:9", Self::around(32.0 / 9.0)),
+        ]
+    }
+    
+    fn parse_ratio(s: &str) -> Option<f32> {
+        let s = s.trim();
+        let ratio = match s.split_once(':') {
+            Some((width, height)) => width.trim().parse::<f32>().ok()? / height.trim().parse::<f32>().ok()?,
+            None => s.parse::<f32>().ok()?,
+        };
+        (ratio.is_finite() && ratio > 0.0).then_some(ratio)
+    }
+}
+
+impl FromStr for AspectRatioRange {
+    type Err = String;
+    
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid aspect ratio '{}': expected W:H, a decimal ratio or a MIN-MAX range", s);
+        match s.split_once('-') {
+            Some((low, high)) => {
+                let low = Self::parse_ratio(low).ok_or_else(invalid)?;
+                let high = Self::parse_ratio(high).ok_or_else(invalid)?;
+                Ok(Self { min: low.min(high), max: low.max(high) })
+            }
+            None => Self::parse_ratio(s).map(Self::around).ok_or_else(invalid),
+        }
+    }
+}
+
+/// Resolution and shape constraints applied to the thumbnail grid.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageFilter {
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub aspect_ratio: Option<AspectRatioRange>,
+    pub fit_screen: bool,
+}
+
+impl ImageFilter {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            min_width: args.min_width,
+            min_height: args.min_height,
+            aspect_ratio: args.aspect_ratio,
+            fit_screen: args.fit_screen,
+        }
+    }
+    
+    pub fn is_active(&self) -> bool {
+        *self != Self::default()
+    }
+    
+    /// Images whose dimensions couldn't be read never match an active filter.
+    pub fn matches(&self, dimensions: Option<(u32, u32)>, screen_size: Option<(u32, u32)>) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        
+        let Some((width, height)) = dimensions.filter(|&(w, h)| w > 0 && h > 0) else {
+            return false;
+        };
+        let ratio = width as f32 / height as f32;
+        
+        if self.min_width.is_some_and(|min| width < min) || self.min_height.is_some_and(|min| height < min) {
+            return false;
+        }
+        
+        if self.aspect_ratio.is_some_and(|range| !range.contains(ratio)) {
+            return false;
+        }
+        
+        if self.fit_screen {
+            if let Some((screen_width, screen_height)) = screen_size.filter(|&(w, h)| w > 0 && h > 0) {
+                let screen_ratio = screen_width as f32 / screen_height as f32;
+                if width < screen_width || height < screen_height || !AspectRatioRange::around(screen_ratio).contains(ratio) {
+                    return false;
+                }
+            }
+        }
+        
+        true
+    }
+}
+
+
+#[derive(Clone)]
+pub struct ImageInfo {
+    pub path: PathBuf,
+    pub thumbnail: Option<egui::TextureHandle>,
+    pub relative_path: String,
+    pub loading: bool,
+    /// The thumbnail could not be decoded; it is not retried until the next scan
+    pub thumbnail_failed: bool,
+    pub dimensions: Option<(u32, u32)>,
+    pub modified: Option<SystemTime>,
+    pub file_size: u64,
+}
+
+/// Where and how well a search query matched an image's relative path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    pub score: i64,
+    /// Character positions in the path that matched, for highlighting
+    pub positions: Vec<usize>,
+}
+
+/// Fuzzy-match every whitespace-separated term of `query` against `text`.
+/// All terms must match; scores are summed and positions merged.
+pub fn fuzzy_match(query: &str, text: &str) -> Option<SearchMatch> {
+    fuzzy_match_with(&SkimMatcherV2::default().ignore_case(), query, text)
+}
+
+fn fuzzy_match_with(matcher: &SkimMatcherV2, query: &str, text: &str) -> Option<SearchMatch> {
+    let mut result = SearchMatch { score: 0, positions: Vec::new() };
+    for term in query.split_whitespace() {
+        let (score, positions) = matcher.fuzzy_indices(text, term)?;
+        result.score += score;
+        result.positions.extend(positions);
+    }
+    result.positions.sort_unstable();
+    result.positions.dedup();
+    Some(result)
+}
+
+/// The search box contents and the cached matches for the current query.
+#[derive(Debug, Clone, Default)]
+pub struct SearchState {
+    pub query: String,
+    /// Show matches inside their folders instead of as one flat list
+    pub group_by_folder: bool,
+    matched_query: Option<String>,
+    matches: Vec<Option<SearchMatch>>,
+}
+
+impl SearchState {
+    pub fn is_active(&self) -> bool {
+        !self.query.trim().is_empty()
+    }
+    
+    /// Recompute matches if the query or the scanned images changed
+    pub fn update(&mut self, images: &[ImageInfo]) {
+        if self.matched_query.as_deref() == Some(self.query.as_str()) && self.matches.len() == images.len() {
+            return;
+        }
+        
+        let matcher = SkimMatcherV2::default().ignore_case();
+        self.matches = images.iter()
+            .map(|image| fuzzy_match_with(&matcher, &self.query, &image.relative_path))
+            .collect();
+        self.matched_query = Some(self.query.clone());
+    }
+    
+    /// Forget cached matches, e.g. after a rescan
+    pub fn invalidate(&mut self) {
+        self.matched_query = None;
+    }
+    
+    pub fn get(&self, index: usize) -> Option<&SearchMatch> {
+        self.matches.get(index).and_then(Option::as_ref)
+    }
+    
+    /// Whether the image passes the search; everything passes an empty query
+    pub fn is_match(&self, index: usize) -> bool {
+        !self.is_active() || self.get(index).is_some()
+    }
+}
+
+/// One folder's tiles as laid out in the last frame, used for keyboard navigation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GridSection {
+    pub folder: String,
+    pub indices: Vec<usize>,
+    pub columns: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// Move a selection around the grid. Left/right step through tiles in reading
+/// order; up/down move by rows, crossing into the neighbouring folder's grid at
+/// the top or bottom edge. Without a current selection the first tile is chosen.
+pub fn navigate_grid(sections: &[GridSection], current: Option<usize>, direction: Direction) -> Option<usize> {
+    let sections: Vec<&GridSection> = sections.iter().filter(|s| !s.indices.is_empty()).collect();
+    let first = sections.first().map(|s| s.indices[0]);
+    
+    let Some(current) = current else {
+        return first;
+    };
+    let Some((section_pos, pos)) = sections.iter().enumerate().find_map(|(section_pos, section)| {
+        section.indices.iter().position(|&index| index == current).map(|pos| (section_pos, pos))
+    }) else {
+        return first;
+    };
+    
+    let section = sections[section_pos];
+    let len = section.indices.len();
+    let columns = section.columns.max(1);
+    let (row, column) = (pos / columns, pos % columns);
+    let previous = section_pos.checked_sub(1).map(|i| sections[i]);
+    let next = sections.get(section_pos + 1).copied();
+    
+    let target = match direction {
+        Direction::Right if pos + 1 < len => Some(section.indices[pos + 1]),
+        Direction::Right => next.map(|s| s.indices[0]),
+        Direction::Left if pos > 0 => Some(section.indices[pos - 1]),
+        Direction::Left => previous.and_then(|s| s.indices.last().copied()),
+        Direction::Down if row < (len - 1) / columns => Some(section.indices[(pos + columns).min(len - 1)]),
+        Direction::Down => next.map(|s| s.indices[column.min(s.indices.len() - 1)]),
+        Direction::Up if row > 0 => Some(section.indices[pos - columns]),
+        Direction::Up => previous.map(|s| {
+            let s_columns = s.columns.max(1);
+            let last_row_start = (s.indices.len() - 1) / s_columns * s_columns;
+            s.indices[(last_row_start + column).min(s.indices.len() - 1)]
+        }),
+    };
+    
+    Some(target.unwrap_or(current))
+}
+
+/// Keyboard selection state for the grid.
+#[derive(Debug, Clone, Default)]
+pub struct NavigationState {
+    pub selected: Option<usize>,
+    /// Tile under the mouse pointer in the last frame
+    pub hovered: Option<usize>,
+    /// Grid layout from the last frame
+    pub sections: Vec<GridSection>,
+    /// Scroll the selected tile into view on the next frame
+    pub scroll_to_selected: bool,
+}
+
+/// A wallpaper set on approval, and the one to restore if it is rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TryOn {
+    pub current: PathBuf,
+    /// Wallpaper from `selected_image_file` before trying started
+    pub previous: Option<PathBuf>,
+    /// Monitor being tried on, or `None` for all of them
+    pub monitor: Option<String>,
+}
+
+/// Wallpapers on the desktop: one for every monitor without its own, and per-monitor ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    pub all: Option<PathBuf>,
+    pub monitors: BTreeMap<String, PathBuf>,
+}
+
+impl Selection {
+    pub fn single(path: &Path) -> Self {
+        Self { all: Some(path.to_path_buf()), monitors: BTreeMap::new() }
+    }
+    
+    /// Read the plain-text format older versions wrote: one line per monitor
+    /// as `NAME<TAB>PATH`, and a line that is just a path for every monitor
+    pub fn parse_legacy(contents: &[u8]) -> Self {
+        use std::os::unix::ffi::OsStrExt;
+        
+        let mut selection = Self::default();
+        for line in contents.split(|&byte| byte == b'\n').map(<[u8]>::trim_ascii).filter(|line| !line.is_empty()) {
+            let path = |bytes: &[u8]| PathBuf::from(std::ffi::OsStr::from_bytes(bytes));
+            match line.iter().position(|&byte| byte == b'\t') {
+                Some(tab) => {
+                    let monitor = String::from_utf8_lossy(&line[..tab]).into_owned();
+                    selection.monitors.insert(monitor, path(&line[tab + 1..]));
+                }
+                None => selection.all = Some(path(line)),
+            }
+        }
+        selection
+    }
+    
+    /// Image shown on a monitor
+    pub fn for_monitor(&self, monitor: &str) -> Option<&Path> {
+        self.monitors.get(monitor).or(self.all.as_ref()).map(PathBuf::as_path)
+    }
+    
+    /// Put `path` on `monitor`, or on every monitor if `None`
+    pub fn assign(&mut self, monitor: Option<&str>, path: &Path) {
+        match monitor {
+            Some(monitor) => {
+                self.monitors.insert(monitor.to_owned(), path.to_path_buf());
+            }
+            None => *self = Self::single(path),
+        }
+    }
+    
+    pub fn contains(&self, path: &Path) -> bool {
+        self.all.as_deref() == Some(path) || self.monitors.values().any(|assigned| assigned == path)
+    }
+}
+
+/// Version of the `selected_image_file` format written by `SelectionFile`
+pub const SELECTION_FILE_VERSION: u32 = 1;
+
+/// Contents of `selected_image_file`: YAML with a format version, the
+/// wallpapers, the fit mode and when it was written. Files in the legacy
+/// plain-text format are still read, without fit mode or timestamp.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SelectionFile {
+    pub selection: Selection,
+    pub fit: Option<FitMode>,
+    /// Seconds since the Unix epoch
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSelection {
+    version: u32,
+    timestamp: u64,
+    fit: FitMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    all: Option<StoredPath>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    monitors: BTreeMap<String, StoredPath>,
+}
+
+/// A path in the selection file. Text is kept as is, except that `%` and any
+/// bytes that aren't UTF-8 are written as `%XX`, so every path survives exactly.
+#[derive(Debug, Clone, PartialEq)]
+struct StoredPath(PathBuf);
+
+impl Serialize for StoredPath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        use std::os::unix::ffi::OsStrExt;
+        
+        let mut encoded = String::new();
+        for chunk in self.0.as_os_str().as_bytes().utf8_chunks() {
+            encoded.push_str(&chunk.valid().replace('%', "%25"));
+            for byte in chunk.invalid() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        serializer.serialize_str(&encoded)
+    }
+}
+
+impl<'de> Deserialize<'de> for StoredPath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        use std::os::unix::ffi::OsStringExt;
+        
+        let text = String::deserialize(deserializer)?;
+        let digit = |byte: &u8| (*byte as char).to_digit(16);
+        let mut bytes = Vec::with_capacity(text.len());
+        let mut rest = text.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == b'%' {
+                let (Some(high), Some(low)) = (tail.first().and_then(digit), tail.get(1).and_then(digit)) else {
+                    return Err(serde::de::Error::custom(format!("bad escape in path {:?}", text)));
+                };
+                bytes.push((high * 16 + low) as u8);
+                rest = &tail[2..];
+            } else {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+        Ok(StoredPath(PathBuf::from(std::ffi::OsString::from_vec(bytes))))
+    }
+}
+
+impl SelectionFile {
+    /// Read either format. A file that starts with `version:` is only read as
+    /// the structured format; if that fails, or the version is newer than this
+    /// build knows, the selection is empty.
+    pub fn parse(contents: &[u8]) -> Self {
+        if !contents.starts_with(b"version:") {
+            return Self { selection: Selection::parse_legacy(contents), ..Default::default() };
+        }
+        
+        let stored = std::str::from_utf8(contents)
+            .ok()
+            .and_then(|text| serde_yaml::from_str::<StoredSelection>(text).ok())
+            .filter(|stored| stored.version <= SELECTION_FILE_VERSION);
+        match stored {
+            Some(stored) => Self {
+                selection: Selection {
+                    all: stored.all.map(|path| path.0),
+                    monitors: stored.monitors.into_iter().map(|(monitor, path)| (monitor, path.0)).collect(),
+                },
+                fit: Some(stored.fit),
+                timestamp: Some(stored.timestamp),
+            },
+            None => Self::default(),
+        }
+    }
+    
+    pub fn to_file_contents(&self) -> Result<String> {
+        let stored = StoredSelection {
+            version: SELECTION_FILE_VERSION,
+            timestamp: self.timestamp.unwrap_or_default(),
+            fit: self.fit.unwrap_or_default(),
+            all: self.selection.all.clone().map(StoredPath),
+            monitors: self.selection.monitors.iter()
+                .map(|(monitor, path)| (monitor.clone(), StoredPath(path.clone())))
+                .collect(),
+        };
+        serde_yaml::to_string(&stored)
+            .map_err(|e| BackgroundPickerError::SaveSelectedImage(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
+
+/// Monitors found at startup, and which of them the next wallpaper goes to.
+#[derive(Debug, Clone, Default)]
+pub struct MonitorState {
+    pub available: Vec<Monitor>,
+    /// Monitor chosen in the status bar, or `None` for all of them
+    pub target: Option<String>,
+    /// Cut each wallpaper into slices that continue across the monitors
+    pub span: bool,
+    /// Per-monitor wallpapers currently set
+    pub assigned: BTreeMap<String, PathBuf>,
+}
+
+/// What to do once a wallpaper command started from the UI has finished.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PendingAction {
+    Apply { path: PathBuf, selection: Selection, close: bool },
+    Try(TryOn),
+    Revert,
+}
+
+/// A wallpaper command running on a worker thread, shown with a spinner.
+pub struct PendingCommand {
+    pub action: PendingAction,
+    receiver: std::sync::mpsc::Receiver<CommandOutcome>,
+}
+
+/// How the wallpaper command went, and then the hooks if any ran
+struct CommandOutcome {
+    set: Result<()>,
+    hooks: Result<()>,
+}
+
+/// Full-size preview of a single image, decoded off the UI thread.
+#[derive(Default)]
+pub struct PreviewState {
+    /// Image being previewed
+    pub index: Option<usize>,
+    pub texture: Option<egui::TextureHandle>,
+    pub error: Option<String>,
+    /// Bumped on every `open`, so results for earlier requests can be told apart
+    generation: u64,
+    loader: Option<PreviewLoader>,
+}
+
+/// A single decoding thread. Requests that pile up while it is busy are
+/// skipped, so only the most recent one is decoded next.
+struct PreviewLoader {
+    requests: std::sync::mpsc::Sender<PreviewRequest>,
+    results: std::sync::mpsc::Receiver<(u64, Result<egui::ColorImage>)>,
+}
+
+struct PreviewRequest {
+    generation: u64,
+    path: PathBuf,
+    max_size: (u32, u32),
+}
+
+impl PreviewLoader {
+    fn spawn(ctx: egui::Context) -> Self {
+        let (requests, request_receiver) = std::sync::mpsc::channel::<PreviewRequest>();
+        let (result_sender, results) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            // Exits once the preview state, and with it the sender, is dropped
+            while let Ok(request) = request_receiver.recv() {
+                let request = request_receiver.try_iter().last().unwrap_or(request);
+                let result = BackgroundPickerApp::load_preview_image(&request.path, request.max_size);
+                if result_sender.send((request.generation, result)).is_err() {
+                    break;
+                }
+                ctx.request_repaint();
+            }
+        });
+        Self { requests, results }
+    }
+}
+
+impl PreviewState {
+    pub fn is_open(&self) -> bool {
+        self.index.is_some()
+    }
+    
+    /// Start loading `path`, downscaled to fit within `max_size`. The context is
+    /// woken when the image is ready.
+    pub fn open(&mut self, ctx: &egui::Context, index: usize, path: PathBuf, max_size: (u32, u32)) {
+        if self.index == Some(index) {
+            return;
+        }
+        
+        self.generation += 1;
+        let request = PreviewRequest { generation: self.generation, path, max_size };
+        let loader = self.loader.get_or_insert_with(|| PreviewLoader::spawn(ctx.clone()));
+        if let Err(std::sync::mpsc::SendError(request)) = loader.requests.send(request) {
+            // The worker died (e.g. a decoder panicked); start a fresh one
+            let loader = PreviewLoader::spawn(ctx.clone());
+            let _ = loader.requests.send(request);
+            self.loader = Some(loader);
+        }
+        
+        self.index = Some(index);
+        self.texture = None;
+        self.error = None;
+    }
+    
+    /// Hide the preview; the loader thread is kept for the next one
+    pub fn close(&mut self) {
+        self.index = None;
+        self.texture = None;
+        self.error = None;
+    }
+    
+    /// Pick up a finished load; results for images no longer shown are dropped
+    pub fn poll(&mut self, ctx: &egui::Context) {
+        let Some(loader) = &self.loader else {
+            return;
+        };
+        for (generation, result) in loader.results.try_iter() {
+            if generation != self.generation || self.index.is_none() {
+                continue;
+            }
+            match result {
+                Ok(color_image) => {
+                    self.texture = Some(ctx.load_texture("preview", color_image, egui::TextureOptions::LINEAR));
+                }
+                Err(e) => self.error = Some(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Rows of a uniform list that overlap the view from `view_top` to `view_bottom`,
+/// widened by `prefetch` rows on each side. The first row starts at `top`, and
+/// rows are `pitch` apart.
+pub fn visible_rows(top: f32, pitch: f32, row_count: usize, view_top: f32, view_bottom: f32, prefetch: usize) -> std::ops::Range<usize> {
+    if row_count == 0 || pitch <= 0.0 {
+        return 0..0;
+    }
+    
+    let prefetch = prefetch as f32;
+    let first = ((view_top - top) / pitch).floor() - prefetch;
+    let end = ((view_bottom - top) / pitch).ceil() + prefetch;
+    let start = first.clamp(0.0, row_count as f32) as usize;
+    let end = end.clamp(0.0, row_count as f32) as usize;
+    start..end.max(start)
+}
+
+/// A thumbnail waiting to be loaded or generated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThumbnailJob {
+    pub index: usize,
+    pub path: PathBuf,
+    pub size: u32,
+    pub cache_dir: PathBuf,
+    pub quality: ThumbnailQuality,
+}
+
+/// Pending thumbnail loads, shared with the worker threads. Tiles request their
+/// thumbnail every frame they are laid out; the most recently requested jobs run
+/// first, and jobs nobody asked for during a frame are cancelled at its end.
+#[derive(Clone, Default)]
+pub struct ThumbnailQueue {
+    inner: Arc<Mutex<ThumbnailQueueInner>>,
+}
+
+#[derive(Default)]
+struct ThumbnailQueueInner {
+    frame: u64,
+    next_order: u64,
+    jobs: HashMap<usize, QueuedThumbnail>,
+    /// Images a worker is loading right now
+    in_flight: HashSet<usize>,
+}
+
+struct QueuedThumbnail {
+    job: ThumbnailJob,
+    last_requested: u64,
+    order: u64,
+}
+
+impl ThumbnailQueue {
+    /// Start a new round of requests; see `cancel_stale`
+    pub fn begin_frame(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.frame += 1;
+        }
+    }
+    
+    /// Queue a job, or move an already queued one to the front.
+    /// Returns true if the job is new and needs a worker.
+    pub fn request(&self, job: ThumbnailJob) -> bool {
+        let Ok(mut inner) = self.inner.lock() else {
+            return false;
+        };
+        if inner.in_flight.contains(&job.index) {
+            return false;
+        }
+        let (frame, order) = (inner.frame, inner.next_order);
+        inner.next_order += 1;
+        
+        match inner.jobs.get_mut(&job.index) {
+            Some(queued) => {
+                // Keep the first request's order within a frame, so layout order wins
+                if queued.last_requested != frame {
+                    queued.order = order;
+                }
+                queued.last_requested = frame;
+                queued.job = job;
+                false
+            }
+            None => {
+                inner.jobs.insert(job.index, QueuedThumbnail { job, last_requested: frame, order });
+                true
+            }
+        }
+    }
+    
+    /// Take the most urgent job: the latest requested, then the earliest laid out
+    pub fn pop(&self) -> Option<ThumbnailJob> {
+        let mut inner = self.inner.lock().ok()?;
+        let index = inner.jobs.iter()
+            .max_by_key(|(_, queued)| (queued.last_requested, std::cmp::Reverse(queued.order)))
+            .map(|(&index, _)| index)?;
+        inner.in_flight.insert(index);
+        inner.jobs.remove(&index).map(|queued| queued.job)
+    }
+    
+    /// The result for `index` has arrived, so it may be requested again
+    pub fn finish(&self, index: usize) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.in_flight.remove(&index);
+        }
+    }
+    
+    /// Drop jobs not requested since `begin_frame`, returning their image indices
+    pub fn cancel_stale(&self) -> Vec<usize> {
+        let Ok(mut inner) = self.inner.lock() else {
+            return Vec::new();
+        };
+        let frame = inner.frame;
+        let stale: Vec<usize> = inner.jobs.iter()
+            .filter(|(_, queued)| queued.last_requested < frame)
+            .map(|(&index, _)| index)
+            .collect();
+        for index in &stale {
+            inner.jobs.remove(index);
+        }
+        stale
+    }
+    
+    pub fn len(&self) -> usize {
+        self.inner.lock().map(|inner| inner.jobs.len()).unwrap_or(0)
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A directory in the scanned hierarchy. Subfolders and images are kept in
+/// natural sort order so the tree renders the same way on every run.
+#[derive(Clone, Debug, Default)]
+pub struct FolderNode {
+    pub name: String,
+    /// Path relative to the scanned directory, empty for the root
+    pub path: String,
+    pub images: Vec<usize>,
+    pub children: Vec<FolderNode>,
+}
+
+impl FolderNode {
+    /// Add an image to the folder at `folder` (relative to this node), creating
+    /// intermediate folders as needed.
+    pub fn insert(&mut self, folder: &Path, index: usize) {
+        let mut node = self;
+        for component in folder.components() {
+            let name = component.as_os_str().to_string_lossy().into_owned();
+            let position = match node.children.iter().position(|child| child.name == name) {
+                Some(position) => position,
+                None => {
+                    let path = if node.path.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}/{}", node.path, name)
+                    };
+                    node.children.push(FolderNode { name, path, ..Default::default() });
+                    node.children.len() - 1
+                }
+            };
+            node = &mut node.children[position];
+        }
+        node.images.push(index);
+    }
+    
+    /// Look up a folder by its relative path; `.` and the empty string name the root.
+    pub fn find(&self, path: &str) -> Option<&FolderNode> {
+        if path.is_empty() || path == "." {
+            return Some(self);
+        }
+        path.split('/').try_fold(self, |node, name| {
+            node.children.iter().find(|child| child.name == name)
+        })
+    }
+    
+    /// This folder and all of its descendants, depth first
+    pub fn nodes(&self) -> Vec<&FolderNode> {
+        let mut nodes = vec![self];
+        for child in &self.children {
+            nodes.extend(child.nodes());
+        }
+        nodes
+    }
+    
+    /// Number of images in this folder and all subfolders
+    pub fn total_images(&self) -> usize {
+        self.images.len() + self.children.iter().map(FolderNode::total_images).sum::<usize>()
+    }
+    
+    /// Like `total_images`, but only counting images marked visible
+    pub fn count_matching(&self, visible: &[bool]) -> usize {
+        self.images.iter().filter(|&&index| visible.get(index).copied().unwrap_or(false)).count()
+            + self.children.iter().map(|child| child.count_matching(visible)).sum::<usize>()
+    }
+    
+    /// Sort subfolders naturally by name and images with `sorter`
+    pub fn sort(&mut self, images: &[ImageInfo], sorter: &ImageSorter) {
+        self.children.sort_by(|a, b| natural_cmp(&a.name, &b.name));
+        self.images.sort_by(|&a, &b| match (images.get(a), images.get(b)) {
+            (Some(a), Some(b)) => sorter.compare(a, b),
+            _ => a.cmp(&b),
+        });
+        for child in &mut self.children {
+            child.sort(images, sorter);
+        }
+    }
+}
+
+pub struct BackgroundPickerApp {
+    pub args: Args,
+    /// Current tile size, from `-t`, the remembered zoom or the default
+    pub thumbnail_size: u32,
+    /// When Ctrl+scroll last changed the zoom; it is saved once that settles
+    pub zoom_changed: Option<Instant>,
+    pub images: Arc<RwLock<Vec<ImageInfo>>>,
+    pub folder_tree: FolderNode,
+    pub loading: bool,
+    /// Finished thumbnail loads, with the job that produced them
+    pub thumbnail_sender: std::sync::mpsc::Sender<(ThumbnailJob, Option<egui::ColorImage>)>,
+    pub thumbnail_receiver: std::sync::mpsc::Receiver<(ThumbnailJob, Option<egui::ColorImage>)>,
+    pub thread_pool: rayon::ThreadPool,
+    pub thumbnail_queue: ThumbnailQueue,
+    pub cache_dir: PathBuf,
+    pub filter: ImageFilter,
+    pub state: PickerState,
+    pub search: SearchState,
+    pub navigation: NavigationState,
+    pub preview: PreviewState,
+    pub try_on: Option<TryOn>,
+    /// Last committed wallpaper, highlighted in the grid
+    pub current_wallpaper: Option<PathBuf>,
+    /// Message shown in the status bar
+    pub status: Option<String>,
+    /// Last failed wallpaper command, shown until dismissed
+    pub command_error: Option<CommandFailure>,
+    /// Resolution of the monitor the window is on, for `{width}` and `{height}`
+    pub screen_size: Option<(u32, u32)>,
+    pub monitors: MonitorState,
+    /// Wallpaper command still running, if any
+    pub pending: Option<PendingCommand>,
+    /// Close once `pending` finishes; asked for while it was still running
+    pub closing: bool,
+    pub recent: RecentView,
+}
+
+impl BackgroundPickerApp {
+    pub fn new(_cc: &eframe::CreationContext<'_>, args: Args) -> Result<Self> {
+        Self::from_args(args)
+    }
+    
+    /// Set up the picker without a window; `--restore` uses this directly
+    pub fn from_args(args: Args) -> Result<Self> {
+        let (thumbnail_sender, thumbnail_receiver) = std::sync::mpsc::channel();
+        
+        // Create thread pool with optimal number of threads
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_cpus::get().max(MIN_THREAD_COUNT))
+            .build()?;
+        
+        // Set up thumbnail cache directory (freedesktop.org spec)
+        let cache_dir = Self::get_thumbnail_cache_dir()?;
+        if args.debug {
+            println!("Using thumbnail cache directory: {:?}", cache_dir);
+        }
+        
+        let filter = ImageFilter::from_args(&args);
+        
+        let mut state = PickerState::load(&args.state_file);
+        if let Some(sort_mode) = args.sort {
+            state.sort_mode = sort_mode;
+        }
+        if let Some(quality) = args.quality {
+            state.quality = quality;
+        }
+        if let Some(fit) = args.fit {
+            state.fit = fit;
+        }
+        
+        // A size given on the command line wins over the remembered zoom
+        let thumbnail_size = args.thumbnail_size
+            .or(state.thumbnail_size.map(|size| size.clamp(MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE)))
+            .unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+        
+        let mut app = Self {
+            args,
+            thumbnail_size,
+            zoom_changed: None,
+            images: Arc::new(RwLock::new(Vec::new())),
+            folder_tree: FolderNode::default(),
+            loading: true,
+            thumbnail_sender,
+            thumbnail_receiver,
+            thread_pool,
+            thumbnail_queue: ThumbnailQueue::default(),
+            cache_dir,
+            filter,
+            state,
+            search: SearchState::default(),
+            navigation: NavigationState::default(),
+            preview: PreviewState::default(),
+            try_on: None,
+            current_wallpaper: None,
+            status: None,
+            command_error: None,
+            screen_size: None,
+            monitors: MonitorState::default(),
+            pending: None,
+            closing: false,
+            recent: RecentView::default(),
+        };
+        
+        app.monitors.span = app.args.span;
+        // A bad history file is reported by whatever goes on to use it
+        let _ = app.reload_history();
+        let selection = app.read_selection();
+        app.current_wallpaper = selection.all;
+        app.monitors.assigned = selection.monitors;
+        let setter = app.wallpaper_setter();
+        if setter.per_monitor() && !app.args.pregenerate {
+            app.monitors.available = wallpaper::detect_monitors(setter.as_ref(), &wallpaper::SessionInfo::from_env(), &app.runner());
+            if app.args.debug {
+                println!("Monitors: {:?}", app.monitors.available);
+            }
+        }
+        // Restoring only needs the images if nothing was recorded
+        if !app.args.runs_without_gui() || app.args.pregenerate {
+            app.scan_images()?;
+        }
+        
+        Ok(app)
+    }
+    
+    pub fn get_thumbnail_cache_dir() -> Result<PathBuf> {
+        // Use freedesktop.org thumbnail specification
+        let cache_home = dirs::cache_dir()
+            .or_else(|| dirs::home_dir().map(|h| h.join(".cache")))
+            .unwrap_or_else(|| PathBuf::from(".cache"));
+            
+        let normal_dir = cache_home.join("thumbnails").join("normal");
+        
+        // Create the directory structure if it doesn't exist
+        fs::create_dir_all(&normal_dir)
+            .map_err(BackgroundPickerError::CacheDirectoryCreation)?;
+        
+        Ok(normal_dir)
+    }
+    
+    pub fn find_existing_thumbnail(file_path: &Path) -> Option<PathBuf> {
+        Self::find_existing_thumbnail_for_size(file_path, 0)
+    }
+    
+    /// Look for a thumbnail made by another application that is at least `size` pixels
+    pub fn find_existing_thumbnail_for_size(file_path: &Path, size: u32) -> Option<PathBuf> {
+        // Look for existing thumbnails in multiple sizes
+        let cache_home = dirs::cache_dir()?;
+        let thumbnails_dir = cache_home.join("thumbnails");
+        
+        let hash = Self::get_thumbnail_hash(file_path)?;
+        let thumbnail_name = format!("{}.png", hash);
+        
+        // Check the smallest sufficient size first
+        let smallest = ThumbnailBucket::for_size(size);
+        for bucket in ThumbnailBucket::ALL.into_iter().filter(|&bucket| bucket >= smallest) {
+            let thumbnail_path = thumbnails_dir.join(bucket.dir_name()).join(&thumbnail_name);
+            if thumbnail_path.exists() && Self::is_thumbnail_cache_valid_static(file_path, &thumbnail_path) {
+                return Some(thumbnail_path);
+            }
+        }
+        
+        None
+    }
+    
+    pub fn get_thumbnail_hash(file_path: &Path) -> Option<String> {
+        // Generate SHA1 hash of file URI as per freedesktop.org thumbnail spec
+        // This matches exactly what pcmanfm and other file managers use
+        let canonicalized = fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
+        let file_uri = format!("file://{}", canonicalized.to_string_lossy());
+        
+        use sha1::{Digest, Sha1};
+        let mut hasher = Sha1::new();
+        hasher.update(file_uri.as_bytes());
+        let result = hasher.finalize();
+        Some(format!("{:x}", result))
+    }
+    
+    
+    
+    /// Cache bucket for the current tile size; thumbnails are made at the bucket's
+    /// size and scaled down for display, so zooming within a bucket needs no reload.
+    pub fn thumbnail_bucket(&self) -> ThumbnailBucket {
+        ThumbnailBucket::for_size(self.thumbnail_size)
+    }
+    
+    /// Cache directory for the current bucket. `cache_dir` is the spec's `normal`
+    /// directory, with the other sizes as siblings; any other directory is used as-is.
+    pub fn bucket_cache_dir(&self) -> PathBuf {
+        match self.cache_dir.parent() {
+            Some(root) if self.cache_dir.ends_with("normal") => root.join(self.thumbnail_bucket().dir_name()),
+            _ => self.cache_dir.clone(),
+        }
+    }
+    
+    /// Change the tile size, dropping thumbnails made for a different bucket
+    pub fn set_thumbnail_size(&mut self, size: u32) {
+        let size = size.clamp(MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE);
+        let old_bucket = self.thumbnail_bucket();
+        self.thumbnail_size = size;
+        self.state.thumbnail_size = Some(size);
+        
+        if self.thumbnail_bucket() != old_bucket {
+            self.clear_thumbnails();
+        }
+    }
+    
+    /// Change the resampling quality, reloading every thumbnail
+    pub fn set_quality(&mut self, quality: ThumbnailQuality) {
+        if quality == self.state.quality {
+            return;
+        }
+        self.state.quality = quality;
+        self.clear_thumbnails();
+        self.save_state();
+    }
+    
+    fn clear_thumbnails(&mut self) {
+        if let Ok(mut images) = self.images.write() {
+            for image in images.iter_mut() {
+                image.thumbnail = None;
+                image.thumbnail_failed = false;
+                image.loading = false;
+            }
+        }
+    }
+    
+    fn show_zoom_control(&mut self, ui: &mut egui::Ui) {
+        let mut size = self.thumbnail_size;
+        let response = ui.add(
+            egui::Slider::new(&mut size, MIN_THUMBNAIL_SIZE..=MAX_THUMBNAIL_SIZE)
+                .logarithmic(true)
+                .suffix(" px")
+                .text("Zoom"),
+        );
+        if size != self.thumbnail_size {
+            self.set_thumbnail_size(size);
+        }
+        // Save once the drag ends rather than on every step
+        if response.drag_stopped() || (response.changed() && !response.dragged()) {
+            self.save_state();
+        }
+    }
+    
+    fn handle_zoom_input(&mut self, ctx: &egui::Context) {
+        let zoom = ctx.input(|i| i.zoom_delta());
+        if zoom != 1.0 {
+            self.set_thumbnail_size((self.thumbnail_size as f32 * zoom).round() as u32);
+            self.zoom_changed = Some(Instant::now());
+        }
+        
+        // Scrolling sends many small steps; save once they stop
+        if let Some(changed) = self.zoom_changed {
+            let elapsed = changed.elapsed();
+            if elapsed >= ZOOM_SAVE_DELAY {
+                self.zoom_changed = None;
+                self.save_state();
+            } else {
+                ctx.request_repaint_after(ZOOM_SAVE_DELAY - elapsed);
+            }
+        }
+    }
+    
+    pub fn scan_images(&mut self) -> Result<()> {
+        // Absolute paths, so commands and the selection file don't depend on our working directory
+        let base_path = &fs::canonicalize(&self.args.directory).unwrap_or_else(|_| self.args.directory.clone());
+        
+        // Clear existing data
+        {
+            let mut images = self.images.write()
+                .map_err(|_| BackgroundPickerError::LockAcquisition)?;
+            images.clear();
+        }
+        self.folder_tree = FolderNode::default();
+        self.search.invalidate();
+        
+        if self.args.debug {
+            println!("Scanning directory: {:?}", base_path);
+        }
+        
+        // Pre-allocate collections to avoid repeated reallocations
+        let mut temp_images = Vec::new();
+        let mut temp_folders = FolderNode::default();
+        
+        // Collect all image files first
+        for entry in WalkDir::new(base_path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            if let Some(ext) = entry.path().extension() {
+                let ext_str = ext.to_string_lossy();
+                if IMAGE_EXTENSIONS.iter().any(|&valid_ext| valid_ext.eq_ignore_ascii_case(&ext_str)) {
+                    let relative_path = entry.path()
+                        .strip_prefix(base_path)
+                        .map(|p| p.to_string_lossy().into_owned())
+                        .unwrap_or_else(|_| entry.path().to_string_lossy().into_owned());
+                    
+                    let folder = entry.path()
+                        .parent()
+                        .and_then(|p| p.strip_prefix(base_path).ok())
+                        .map(Path::to_path_buf)
+                        .unwrap_or_default();
+                    
+                    let metadata = entry.metadata().ok();
+                    
+                    let image_index = temp_images.len();
+                    temp_images.push(ImageInfo {
+                        path: entry.path().to_path_buf(),
+                        thumbnail: None,
+                        relative_path,
+                        loading: false,
+                        thumbnail_failed: false,
+                        dimensions: None,
+                        modified: metadata.as_ref().and_then(|m| m.modified().ok()),
+                        file_size: metadata.map(|m| m.len()).unwrap_or(0),
+                    });
+                    
+                    temp_folders.insert(&folder, image_index);
+                }
+            }
+        }
+        
+        // Read dimensions from the image headers so filters don't need a full decode
+        self.thread_pool.install(|| {
+            temp_images.par_iter_mut().for_each(|image| {
+                image.dimensions = Self::read_image_dimensions(&image.path);
+            });
+        });
+        
+        temp_folders.sort(&temp_images, &self.state.sorter());
+        
+        // Update the main data structures
+        {
+            let mut images = self.images.write()
+                .map_err(|_| BackgroundPickerError::LockAcquisition)?;
+            *images = temp_images;
+        }
+        self.folder_tree = temp_folders;
+        
+        if self.args.debug {
+            println!("Found {} images in {} folders", 
+                self.images.read().map(|i| i.len()).unwrap_or(0), 
+                self.folder_tree.nodes().len());
+        }
+        
+        self.loading = false;
+        Ok(())
+    }
+    
+    pub fn read_image_dimensions(path: &Path) -> Option<(u32, u32)> {
+        image::io::Reader::open(path).ok()?
+            .with_guessed_format().ok()?
+            .into_dimensions().ok()
+    }
+    
+    /// Indices from `indices` whose images pass the current filter
+    pub fn filtered_indices(&self, indices: &[usize], screen_size: Option<(u32, u32)>) -> Vec<usize> {
+        let Ok(images) = self.images.read() else {
+            return Vec::new();
+        };
+        indices.iter()
+            .copied()
+            .filter(|&index| index < images.len() && self.filter.matches(images[index].dimensions, screen_size))
+            .collect()
+    }
+    
+    pub fn screen_size(ctx: &egui::Context) -> Option<(u32, u32)> {
+        let monitor_size = ctx.input(|i| i.viewport().monitor_size)?;
+        let pixels_per_point = ctx.pixels_per_point();
+        Some((
+            (monitor_size.x * pixels_per_point).round() as u32,
+            (monitor_size.y * pixels_per_point).round() as u32,
+        ))
+    }
+    
+    /// Re-apply the current sort mode to every folder
+    pub fn sort_images(&mut self) {
+        let sorter = self.state.sorter();
+        if let Ok(images) = self.images.read() {
+            self.folder_tree.sort(&images, &sorter);
+        }
+    }
+    
+    pub fn set_sort_mode(&mut self, sort_mode: SortMode) {
+        self.state.sort_mode = sort_mode;
+        self.sort_images();
+        self.save_state();
+    }
+    
+    pub fn reshuffle(&mut self) {
+        self.state.shuffle_seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        self.set_sort_mode(SortMode::Random);
+    }
+    
+    fn save_state(&self) {
+        if let Err(e) = self.state.save(&self.args.state_file) {
+            eprintln!("{}", e);
+        }
+    }
+    
+    fn show_sort_selector(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Sort:");
+            let mut sort_mode = self.state.sort_mode;
+            egui::ComboBox::from_id_source("sort_mode")
+                .selected_text(sort_mode.label())
+                .show_ui(ui, |ui| {
+                    for mode in SortMode::ALL {
+                        ui.selectable_value(&mut sort_mode, mode, mode.label());
+                    }
+                });
+            if sort_mode != self.state.sort_mode {
+                self.set_sort_mode(sort_mode);
+            }
+            
+            if self.state.sort_mode == SortMode::Random && ui.button("Reshuffle").clicked() {
+                self.reshuffle();
+            }
+            
+            ui.separator();
+            self.show_zoom_control(ui);
+            
+            ui.separator();
+            ui.label("Quality:");
+            let mut quality = self.state.quality;
+            egui::ComboBox::from_id_source("thumbnail_quality")
+                .selected_text(quality.label())
+                .show_ui(ui, |ui| {
+                    for option in ThumbnailQuality::ALL {
+                        ui.selectable_value(&mut quality, option, option.label());
+                    }
+                });
+            self.set_quality(quality);
+            
+            ui.separator();
+            let mut tile_mode = self.state.tile_mode;
+            for mode in [TileMode::Letterbox, TileMode::Crop] {
+                ui.selectable_value(&mut tile_mode, mode, mode.label());
+            }
+            if tile_mode != self.state.tile_mode {
+                self.state.tile_mode = tile_mode;
+                self.save_state();
+            }
+        });
+    }
+    
+    fn show_filter_bar(&mut self, ui: &mut egui::Ui, screen_size: Option<(u32, u32)>) {
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Min size:");
+            let mut min_width = self.filter.min_width.unwrap_or(0);
+            if ui.add(egui::DragValue::new(&mut min_width).speed(10).suffix(" px")).changed() {
+                self.filter.min_width = (min_width > 0).then_some(min_width);
+            }
+            ui.label("×");
+            let mut min_height = self.filter.min_height.unwrap_or(0);
+            if ui.add(egui::DragValue::new(&mut min_height).speed(10).suffix(" px")).changed() {
+                self.filter.min_height = (min_height > 0).then_some(min_height);
+            }
+            
+            ui.separator();
+            ui.label("Aspect:");
+            let presets = AspectRatioRange::presets();
+            let selected_text = match self.filter.aspect_ratio {
+                None => "Any".to_owned(),
+                Some(range) => presets.iter()
+                    .find(|(_, preset)| *preset == range)
+                    .map(|(label, _)| (*label).to_owned())
+                    .unwrap_or_else(|| format!("{:.2}-{:.2}", range.min, range.max)),
+            };
+            egui::ComboBox::from_id_source("aspect_ratio_filter")
+                .selected_text(selected_text)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.filter.aspect_ratio, None, "Any");
+                    for (label, range) in presets {
+                        ui.selectable_value(&mut self.filter.aspect_ratio, Some(range), label);
+                    }
+                });
+            
+            ui.separator();
+            let fit_label = match screen_size {
+                Some((width, height)) => format!("Fits my screen ({}×{})", width, height),
+                None => "Fits my screen".to_owned(),
+            };
+            ui.checkbox(&mut self.filter.fit_screen, fit_label);
+            
+            if self.filter.is_active() && ui.button("Clear filters").clicked() {
+                self.filter = ImageFilter::default();
+            }
+        });
     }
     
     pub fn pregenerate_all_thumbnails(&mut self) -> Result<()> {
@@ -254,468 +1861,1889 @@ impl BackgroundPickerApp {
             .map_err(|_| BackgroundPickerError::LockAcquisition)?
             .len();
         
-        if total_images == 0 {
-            if self.args.debug {
-                println!("No images found to pregenerate thumbnails for");
+        if total_images == 0 {
+            if self.args.debug {
+                println!("No images found to pregenerate thumbnails for");
+            }
+            return Ok(());
+        }
+        
+        if self.args.debug {
+            println!("Pre-generating thumbnails for {} images...", total_images);
+        } else {
+            println!("Generating thumbnails for {} images...", total_images);
+        }
+        
+        let start_time = std::time::Instant::now();
+        let mut generated_count = 0;
+        let mut cached_count = 0;
+        
+        // Use rayon to process all images in parallel
+        let cache_dir = &self.bucket_cache_dir();
+        let size = self.thumbnail_bucket().pixels();
+        let quality = self.state.quality;
+        let debug = self.args.debug;
+        let images = Arc::clone(&self.images);
+        
+        let results: Vec<(bool, bool)> = (0..total_images)
+            .collect::<Vec<_>>()
+            .par_chunks(CHUNK_SIZE) // Process in chunks for progress reporting
+            .enumerate()
+            .flat_map(|(chunk_idx, chunk)| {
+                let chunk_results: Vec<(bool, bool)> = chunk.par_iter().map(|&index| {
+                    let path = {
+                        match images.read() {
+                            Ok(images_guard) => {
+                                if index >= images_guard.len() {
+                                    return (false, false); // (was_cached, was_generated)
+                                }
+                                images_guard[index].path.clone()
+                            }
+                            Err(_) => return (false, false),
+                        }
+                    };
+                    
+                    let abs_path = std::fs::canonicalize(&path).unwrap_or_else(|_| path.to_path_buf());
+                    
+                    // Check if thumbnail already exists
+                    let existing_thumbnail = (quality == ThumbnailQuality::default())
+                        .then(|| Self::find_existing_thumbnail_for_size(&abs_path, size))
+                        .flatten();
+                    if let Some(existing_thumbnail) = existing_thumbnail {
+                        if Self::load_cached_thumbnail(&existing_thumbnail, size, quality).is_some() {
+                            if debug {
+                                println!("  [{}] Found existing thumbnail: {:?}", 
+                                    index + 1, path.file_name().unwrap_or_default());
+                            }
+                            return (true, false); // was cached
+                        }
+                    }
+                    
+                    if let Some(cache_path) = Self::get_cached_thumbnail_path_for(&abs_path, cache_dir, quality) {
+                        if Self::is_thumbnail_cache_valid_static(&abs_path, &cache_path) && Self::load_cached_thumbnail(&cache_path, size, quality).is_some() {
+                            if debug {
+                                println!("  [{}] Found cached thumbnail: {:?}", 
+                                    index + 1, path.file_name().unwrap_or_default());
+                            }
+                            return (true, false); // was cached
+                        }
+                    }
+                    
+                    // Generate new thumbnail
+                    if let Some(color_image) = Self::generate_thumbnail(&abs_path, size, quality) {
+                        // Save to cache
+                        if let Some(cache_path) = Self::get_cached_thumbnail_path_for(&abs_path, cache_dir, quality) {
+                            Self::save_thumbnail_to_cache(&color_image, &cache_path, &abs_path);
+                        }
+                        
+                        if debug {
+                            println!("  [{}] Generated thumbnail: {:?}", 
+                                index + 1, path.file_name().unwrap_or_default());
+                        }
+                        (false, true) // was generated
+                    } else {
+                        if debug {
+                            println!("  [{}] Failed to generate thumbnail: {:?}", 
+                                index + 1, path.file_name().unwrap_or_default());
+                        }
+                        (false, false)
+                    }
+                }).collect();
+                
+                // Show progress for large collections
+                if !debug && total_images > PROGRESS_THRESHOLD {
+                    let completed = (chunk_idx + 1) * CHUNK_SIZE.min(total_images);
+                    print!("\rProgress: {}/{} images processed", completed, total_images);
+                    io::stdout().flush().ok();
+                }
+                
+                chunk_results
+            }).collect();
+        
+        // Count results
+        for (was_cached, was_generated) in results {
+            if was_cached {
+                cached_count += 1;
+            } else if was_generated {
+                generated_count += 1;
+            }
+        }
+        
+        let elapsed = start_time.elapsed();
+        
+        if !self.args.debug && total_images > PROGRESS_THRESHOLD {
+            println!(); // New line after progress indicator
+        }
+        
+        if self.args.debug {
+            println!("Thumbnail pregeneration complete:");
+            println!("  - {} thumbnails found in cache", cached_count);
+            println!("  - {} thumbnails generated", generated_count);
+            println!("  - {} thumbnails failed", total_images - cached_count - generated_count);
+            println!("  - Time elapsed: {:.2}s", elapsed.as_secs_f64());
+        } else {
+            println!("Thumbnail generation complete: {} cached, {} generated ({:.1}s)", 
+                cached_count, generated_count, elapsed.as_secs_f64());
+        }
+        
+        Ok(())
+    }
+    
+    pub fn load_thumbnail(&mut self, ctx: &egui::Context, index: usize) {
+        let path = {
+            let Ok(mut images) = self.images.write() else {
+                return;
+            };
+            let Some(image) = images.get_mut(index) else {
+                return;
+            };
+            if image.thumbnail.is_some() || image.thumbnail_failed {
+                return;
+            }
+            image.loading = true;
+            image.path.clone()
+        };
+        
+        let job = ThumbnailJob {
+            index,
+            path,
+            size: self.thumbnail_bucket().pixels(),
+            cache_dir: self.bucket_cache_dir(),
+            quality: self.state.quality,
+        };
+        if !self.thumbnail_queue.request(job) {
+            return;
+        }
+        
+        // Each worker takes whichever job is most urgent by the time it runs
+        let queue = self.thumbnail_queue.clone();
+        let sender = self.thumbnail_sender.clone();
+        let ctx = ctx.clone();
+        let debug = self.args.debug;
+        self.thread_pool.spawn(move || {
+            if let Some(job) = queue.pop() {
+                // Failures are reported too, so the tile stops showing "Loading..."
+                let color_image = Self::load_or_generate_thumbnail(&job.path, job.size, &job.cache_dir, job.quality, debug);
+                let _ = sender.send((job, color_image));
+                // Wake the UI to upload the texture; nothing else repaints while idle
+                ctx.request_repaint();
+            }
+        });
+    }
+    
+    /// Cancel loads for tiles that were not laid out this frame
+    pub fn cancel_stale_thumbnails(&mut self) {
+        let cancelled = self.thumbnail_queue.cancel_stale();
+        if cancelled.is_empty() {
+            return;
+        }
+        
+        if let Ok(mut images) = self.images.write() {
+            for index in cancelled {
+                if let Some(image) = images.get_mut(index) {
+                    image.loading = false;
+                }
+            }
+        }
+    }
+    
+    pub fn load_or_generate_thumbnail(path: &Path, size: u32, cache_dir: &Path, quality: ThumbnailQuality, debug: bool) -> Option<egui::ColorImage> {
+        // Get absolute path for cache key generation
+        let abs_path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        
+        // First, look for existing thumbnails created by other applications (pcmanfm, etc.),
+        // which only stand in for the default quality
+        if quality == ThumbnailQuality::default() {
+            if let Some(existing_thumbnail) = Self::find_existing_thumbnail_for_size(&abs_path, size) {
+                if let Some(cached_image) = Self::load_cached_thumbnail(&existing_thumbnail, size, quality) {
+                    if debug {
+                        println!("Loaded existing system thumbnail for {:?}", path.file_name().unwrap_or_default());
+                    }
+                    return Some(cached_image);
+                }
+            }
+        }
+        
+        // Try to load from our own cache
+        if let Some(cache_path) = Self::get_cached_thumbnail_path_for(&abs_path, cache_dir, quality) {
+            if Self::is_thumbnail_cache_valid_static(&abs_path, &cache_path) {
+                if let Some(cached_image) = Self::load_cached_thumbnail(&cache_path, size, quality) {
+                    if debug {
+                        println!("Loaded our cached thumbnail for {:?}", path.file_name().unwrap_or_default());
+                    }
+                    return Some(cached_image);
+                }
+            }
+        }
+        
+        // Generate new thumbnail and cache it
+        if debug {
+            println!("Generating new thumbnail for {:?}", path.file_name().unwrap_or_default());
+        }
+        let color_image = Self::generate_thumbnail(&abs_path, size, quality)?;
+        
+        // Save to cache for future use
+        if let Some(cache_path) = Self::get_cached_thumbnail_path_for(&abs_path, cache_dir, quality) {
+            Self::save_thumbnail_to_cache(&color_image, &cache_path, &abs_path);
+        }
+        
+        Some(color_image)
+    }
+    
+    pub fn get_cached_thumbnail_path_static(file_path: &Path, cache_dir: &Path) -> Option<PathBuf> {
+        Self::get_cached_thumbnail_path_for(file_path, cache_dir, ThumbnailQuality::default())
+    }
+    
+    pub fn get_cached_thumbnail_path_for(file_path: &Path, cache_dir: &Path, quality: ThumbnailQuality) -> Option<PathBuf> {
+        let hash = Self::get_thumbnail_hash(file_path)?;
+        Some(cache_dir.join(format!("{}{}.png", hash, quality.cache_suffix())))
+    }
+    
+    pub fn is_thumbnail_cache_valid_static(original_path: &Path, cache_path: &Path) -> bool {
+        if !cache_path.exists() {
+            return false;
+        }
+        
+        let original_modified = fs::metadata(original_path)
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+            
+        let cache_modified = fs::metadata(cache_path)
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+            
+        cache_modified >= original_modified
+    }
+    
+    pub fn load_cached_thumbnail(cache_path: &Path, target_size: u32, quality: ThumbnailQuality) -> Option<egui::ColorImage> {
+        match image::io::Reader::open(cache_path) {
+            Ok(reader) => {
+                if let Ok(img) = reader.with_guessed_format().ok()?.decode() {
+                    // Resize cached thumbnail to target size if needed
+                    Self::create_thumbnail(img, target_size, quality)
+                } else {
+                    None
+                }
+            }
+            Err(_) => None,
+        }
+    }
+    
+    pub fn save_thumbnail_to_cache(color_image: &egui::ColorImage, cache_path: &Path, original_path: &Path) {
+        // Convert egui::ColorImage back to image format for caching
+        let [width, height] = color_image.size;
+        
+        // Pre-allocate vector with exact capacity for better performance
+        let mut pixels = Vec::with_capacity(color_image.pixels.len() * 4);
+        for pixel in &color_image.pixels {
+            pixels.extend_from_slice(&[pixel.r(), pixel.g(), pixel.b(), pixel.a()]);
+        }
+        
+        if let Some(img_buffer) = image::RgbaImage::from_raw(
+            width as u32, 
+            height as u32, 
+            pixels
+        ) {
+            let dynamic_img = image::DynamicImage::ImageRgba8(img_buffer);
+            
+            // Create parent directory if it doesn't exist
+            if let Some(parent) = cache_path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            
+            // Save with freedesktop.org thumbnail metadata
+            Self::save_thumbnail_with_metadata(&dynamic_img, cache_path, original_path);
+        }
+    }
+    
+    pub fn save_thumbnail_with_metadata(img: &image::DynamicImage, cache_path: &Path, original_path: &Path) {
+        // Get file metadata for thumbnail spec compliance (currently unused but could be added later)
+        let _file_uri = format!("file://{}", 
+            fs::canonicalize(original_path)
+                .unwrap_or_else(|_| original_path.to_path_buf())
+                .to_string_lossy()
+        );
+        
+        let _file_size = fs::metadata(original_path)
+            .map(|m| m.len())
+            .unwrap_or(0);
+            
+        let _mtime = fs::metadata(original_path)
+            .and_then(|m| m.modified())
+            .map(|t| t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs())
+            .unwrap_or(0);
+        
+        // Create PNG encoder with metadata
+        use std::io::BufWriter;
+        use std::fs::File;
+        
+        if let Ok(file) = File::create(cache_path) {
+            let writer = BufWriter::new(file);
+            let encoder = image::codecs::png::PngEncoder::new(writer);
+            
+            // Convert to RGB for PNG encoding
+            let rgb_img = img.to_rgb8();
+            
+            if let Err(e) = encoder.write_image(
+                rgb_img.as_raw(),
+                img.width(),
+                img.height(),
+                image::ColorType::Rgb8,
+            ) {
+                eprintln!("Failed to save thumbnail for {:?}: {}", original_path, e);
             }
-            return Ok(());
         }
         
-        if self.args.debug {
-            println!("Pre-generating thumbnails for {} images...", total_images);
+        // Add metadata using external PNG tools would be ideal, but for now this basic save works
+        // The important part is using the correct hash and cache location
+    }
+    
+    pub fn fast_thumbnail_generation(path: &Path, size: u32) -> Option<egui::ColorImage> {
+        Self::generate_thumbnail(path, size, ThumbnailQuality::Fast)
+    }
+    
+    pub fn generate_thumbnail(path: &Path, size: u32, quality: ThumbnailQuality) -> Option<egui::ColorImage> {
+        // Use image reader with auto format detection
+        let reader = image::io::Reader::open(path).ok()?
+            .with_guessed_format().ok()?;
+        
+        // Try to get dimensions first to avoid full decode if possible
+        let img = reader.decode().ok()?;
+        let (width, height) = (img.width(), img.height());
+        
+        // Early return for already small images
+        if width <= size && height <= size {
+            return Self::create_thumbnail(img, size, quality);
+        }
+        
+        // Calculate optimal resize strategy based on image size
+        let scale_factor = (width.max(height) as f32 / size as f32).max(1.0);
+        
+        match quality {
+            ThumbnailQuality::Fast | ThumbnailQuality::Balanced if scale_factor > 2.0 => {
+                // Area averaging is cheap and alias-free, leaving the final step to the filter
+                let intermediate = img.thumbnail(size * 2, size * 2);
+                Self::create_thumbnail(intermediate, size, quality)
+            }
+            _ => Self::create_thumbnail(img, size, quality),
+        }
+    }
+    
+    pub fn create_thumbnail_fast(img: image::DynamicImage, size: u32) -> Option<egui::ColorImage> {
+        Self::create_thumbnail(img, size, ThumbnailQuality::Fast)
+    }
+    
+    /// Fit `img` within `size` pixels with the quality's filter and convert it for egui
+    pub fn create_thumbnail(img: image::DynamicImage, size: u32, quality: ThumbnailQuality) -> Option<egui::ColorImage> {
+        let thumbnail = if img.width().max(img.height()) == size {
+            img
         } else {
-            println!("Generating thumbnails for {} images...", total_images);
+            img.resize(size, size, quality.filter())
+        };
+        let rgba = thumbnail.to_rgba8();
+        let (width, height) = (thumbnail.width() as usize, thumbnail.height() as usize);
+        
+        // Pre-allocate the pixel buffer for better performance
+        let pixel_count = width * height;
+        let raw_pixels = rgba.as_raw();
+        
+        if raw_pixels.len() != pixel_count * 4 {
+            return None; // Safety check
         }
         
-        let start_time = std::time::Instant::now();
-        let mut generated_count = 0;
-        let mut cached_count = 0;
+        Some(egui::ColorImage::from_rgba_unmultiplied(
+            [width, height],
+            raw_pixels,
+        ))
+    }
+    
+    /// Decode an image for the preview, scaled down to fit within `max_size`
+    pub fn load_preview_image(path: &Path, max_size: (u32, u32)) -> Result<egui::ColorImage> {
+        let img = image::open(path).map_err(|e| BackgroundPickerError::ThumbnailGeneration {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        })?;
         
-        // Use rayon to process all images in parallel
-        let cache_dir = &self.cache_dir;
-        let size = self.args.thumbnail_size;
-        let debug = self.args.debug;
-        let images = Arc::clone(&self.images);
+        let (max_width, max_height) = max_size;
+        let img = if img.width() > max_width || img.height() > max_height {
+            img.resize(max_width, max_height, FilterType::Triangle)
+        } else {
+            img
+        };
         
-        let results: Vec<(bool, bool)> = (0..total_images)
-            .collect::<Vec<_>>()
-            .par_chunks(CHUNK_SIZE) // Process in chunks for progress reporting
+        let rgba = img.to_rgba8();
+        Ok(egui::ColorImage::from_rgba_unmultiplied(
+            [img.width() as usize, img.height() as usize],
+            rgba.as_raw(),
+        ))
+    }
+    
+    pub fn process_thumbnail_results(&mut self, ctx: &egui::Context) {
+        let (size, quality) = (self.thumbnail_bucket().pixels(), self.state.quality);
+        while let Ok((job, color_image)) = self.thumbnail_receiver.try_recv() {
+            let index = job.index;
+            self.thumbnail_queue.finish(index);
+            let Ok(mut images) = self.images.write() else {
+                continue;
+            };
+            let Some(image) = images.get_mut(index) else {
+                continue;
+            };
+            
+            // Made for another zoom bucket, quality or scan; the tile asks again
+            if job.size != size || job.quality != quality || job.path != image.path {
+                image.loading = false;
+                continue;
+            }
+            
+            let texture = color_image.map(|color_image| ctx.load_texture(
+                format!("thumbnail_{}", index),
+                color_image,
+                egui::TextureOptions::default(),
+            ));
+            image.thumbnail_failed = texture.is_none();
+            image.thumbnail = texture;
+            image.loading = false;
+        }
+    }
+    
+    /// Visibility of every scanned image under the current filter and search, indexed like `images`
+    pub fn visibility_mask(&mut self, screen_size: Option<(u32, u32)>) -> Vec<bool> {
+        let Ok(images) = self.images.read() else {
+            return Vec::new();
+        };
+        self.search.update(&images);
+        images.iter()
             .enumerate()
-            .flat_map(|(chunk_idx, chunk)| {
-                let chunk_results: Vec<(bool, bool)> = chunk.par_iter().map(|&index| {
-                    let path = {
-                        match images.read() {
-                            Ok(images_guard) => {
-                                if index >= images_guard.len() {
-                                    return (false, false); // (was_cached, was_generated)
-                                }
-                                images_guard[index].path.clone()
-                            }
-                            Err(_) => return (false, false),
-                        }
-                    };
-                    
-                    let abs_path = std::fs::canonicalize(&path).unwrap_or_else(|_| path.to_path_buf());
-                    
-                    // Check if thumbnail already exists
-                    if let Some(existing_thumbnail) = Self::find_existing_thumbnail(&abs_path) {
-                        if Self::load_cached_thumbnail(&existing_thumbnail, size).is_some() {
-                            if debug {
-                                println!("  [{}] Found existing thumbnail: {:?}", 
-                                    index + 1, path.file_name().unwrap_or_default());
-                            }
-                            return (true, false); // was cached
-                        }
-                    }
-                    
-                    if let Some(cache_path) = Self::get_cached_thumbnail_path_static(&abs_path, cache_dir) {
-                        if Self::is_thumbnail_cache_valid_static(&abs_path, &cache_path) && Self::load_cached_thumbnail(&cache_path, size).is_some() {
-                            if debug {
-                                println!("  [{}] Found cached thumbnail: {:?}", 
-                                    index + 1, path.file_name().unwrap_or_default());
-                            }
-                            return (true, false); // was cached
-                        }
-                    }
-                    
-                    // Generate new thumbnail
-                    if let Some(color_image) = Self::fast_thumbnail_generation(&abs_path, size) {
-                        // Save to cache
-                        if let Some(cache_path) = Self::get_cached_thumbnail_path_static(&abs_path, cache_dir) {
-                            Self::save_thumbnail_to_cache(&color_image, &cache_path, &abs_path);
-                        }
-                        
-                        if debug {
-                            println!("  [{}] Generated thumbnail: {:?}", 
-                                index + 1, path.file_name().unwrap_or_default());
-                        }
-                        (false, true) // was generated
-                    } else {
-                        if debug {
-                            println!("  [{}] Failed to generate thumbnail: {:?}", 
-                                index + 1, path.file_name().unwrap_or_default());
-                        }
-                        (false, false)
-                    }
-                }).collect();
-                
-                // Show progress for large collections
-                if !debug && total_images > PROGRESS_THRESHOLD {
-                    let completed = (chunk_idx + 1) * CHUNK_SIZE.min(total_images);
-                    print!("\rProgress: {}/{} images processed", completed, total_images);
-                    io::stdout().flush().ok();
-                }
-                
-                chunk_results
-            }).collect();
+            .map(|(index, image)| self.filter.matches(image.dimensions, screen_size) && self.search.is_match(index))
+            .collect()
+    }
+    
+    /// Whether the filter or search is hiding any images
+    pub fn is_narrowed(&self) -> bool {
+        self.filter.is_active() || self.search.is_active()
+    }
+    
+    /// Visible search matches, best first
+    pub fn search_results(&self, visible: &[bool]) -> Vec<usize> {
+        let Ok(images) = self.images.read() else {
+            return Vec::new();
+        };
+        let mut results: Vec<usize> = (0..images.len())
+            .filter(|&index| visible.get(index).copied().unwrap_or(false) && self.search.get(index).is_some())
+            .collect();
+        results.sort_by(|&a, &b| {
+            let score = |index| self.search.get(index).map(|m| m.score).unwrap_or(0);
+            score(b).cmp(&score(a))
+                .then_with(|| natural_cmp(&images[a].relative_path, &images[b].relative_path))
+        });
+        results
+    }
+    
+    fn search_field_id() -> egui::Id {
+        egui::Id::new("search_field")
+    }
+    
+    /// Focus the search box on `/` or Ctrl+F unless something else is taking keyboard input
+    fn handle_search_shortcuts(&self, ctx: &egui::Context) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
         
-        // Count results
-        for (was_cached, was_generated) in results {
-            if was_cached {
-                cached_count += 1;
-            } else if was_generated {
-                generated_count += 1;
+        let pressed = ctx.input_mut(|i| {
+            let is_slash = |event: &egui::Event| matches!(event, egui::Event::Text(text) if text == "/");
+            let slash = i.events.iter().any(is_slash);
+            if slash {
+                // Don't let the slash end up in the search box once it has focus
+                i.events.retain(|event| !is_slash(event));
+            }
+            let find = i.consume_key(egui::Modifiers::COMMAND, egui::Key::F);
+            slash || find
+        });
+        
+        if pressed {
+            ctx.memory_mut(|m| m.request_focus(Self::search_field_id()));
+        }
+    }
+    
+    fn show_search_bar(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Search:");
+            ui.checkbox(&mut self.search.group_by_folder, "Group by folder");
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.search.query)
+                    .id(Self::search_field_id())
+                    .hint_text("Press / or Ctrl+F to search file names")
+                    .desired_width(f32::INFINITY),
+            );
+            if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                self.search.query.clear();
+            }
+        });
+    }
+    
+    /// Lay out `text` with the characters at `positions` highlighted
+    fn highlighted_text(ui: &egui::Ui, text: &str, positions: &[usize]) -> egui::text::LayoutJob {
+        let normal = egui::TextFormat {
+            color: ui.visuals().text_color(),
+            ..Default::default()
+        };
+        let highlight = egui::TextFormat {
+            color: ui.visuals().strong_text_color(),
+            background: ui.visuals().selection.bg_fill,
+            ..Default::default()
+        };
+        
+        let mut job = egui::text::LayoutJob::default();
+        let mut run = String::new();
+        let mut run_highlighted = false;
+        for (position, c) in text.chars().enumerate() {
+            let highlighted = positions.binary_search(&position).is_ok();
+            if highlighted != run_highlighted && !run.is_empty() {
+                job.append(&run, 0.0, if run_highlighted { highlight.clone() } else { normal.clone() });
+                run.clear();
+            }
+            run_highlighted = highlighted;
+            run.push(c);
+        }
+        if !run.is_empty() {
+            job.append(&run, 0.0, if run_highlighted { highlight } else { normal });
+        }
+        job
+    }
+    
+    fn show_search_results(&mut self, ui: &mut egui::Ui, ctx: &egui::Context, visible: &[bool]) {
+        let results = self.search_results(visible);
+        ui.label(format!("{} matching images", results.len()));
+        
+        self.navigation.sections.push(GridSection {
+            folder: String::new(),
+            indices: results.clone(),
+            columns: 1,
+        });
+        
+        self.show_rows(ui, results.len(), |index| results.iter().position(|&i| i == index), |app, ui, row| {
+            let index = results[row];
+            let relative_path = match app.images.read() {
+                Ok(images) => images[index].relative_path.clone(),
+                Err(_) => return,
+            };
+            let positions = app.search.get(index).map(|m| m.positions.clone()).unwrap_or_default();
+            
+            app.show_tile(ui, ctx, index);
+            ui.label(Self::highlighted_text(ui, &relative_path, &positions));
+        });
+    }
+    
+    /// Lay out `row_count` rows of tiles, building only those near the viewport.
+    /// Rows out of view are replaced by empty space of the same height.
+    /// `row_of` finds the row holding an image, so keyboard selection can scroll to it.
+    fn show_rows(
+        &mut self,
+        ui: &mut egui::Ui,
+        row_count: usize,
+        row_of: impl Fn(usize) -> Option<usize>,
+        mut show_row: impl FnMut(&mut Self, &mut egui::Ui, usize),
+    ) {
+        let row_height = self.thumbnail_size as f32 + 2.0 * ui.spacing().button_padding.y;
+        let spacing = ui.spacing().item_spacing.y;
+        let pitch = row_height + spacing;
+        let width = ui.available_width();
+        let clip = ui.clip_rect();
+        
+        let top = ui.cursor().top();
+        let rows = visible_rows(top, pitch, row_count, clip.top(), clip.bottom(), PREFETCH_ROWS);
+        
+        // The selected tile isn't built when out of view, so scroll to where it will be
+        if self.navigation.scroll_to_selected {
+            if let Some(row) = self.navigation.selected.and_then(&row_of).filter(|row| !rows.contains(row)) {
+                let row_top = top + row as f32 * pitch;
+                let rect = egui::Rect::from_min_size(egui::pos2(ui.cursor().left(), row_top), egui::vec2(width, row_height));
+                ui.scroll_to_rect(rect, Some(egui::Align::Center));
+            }
+        }
+        
+        if rows.start > 0 {
+            ui.allocate_space(egui::vec2(width, rows.start as f32 * pitch - spacing));
+        }
+        for row in rows.clone() {
+            ui.horizontal(|ui| {
+                ui.set_min_height(row_height);
+                show_row(self, ui, row);
+            });
+        }
+        if rows.end < row_count {
+            ui.allocate_space(egui::vec2(width, (row_count - rows.end) as f32 * pitch - spacing));
+        }
+    }
+    
+    fn folder_state_id(path: &str) -> egui::Id {
+        egui::Id::new(("folder", path))
+    }
+    
+    pub fn set_all_folders_open(&self, ctx: &egui::Context, open: bool) {
+        let mut paths: Vec<&str> = self.folder_tree.nodes().iter().map(|node| node.path.as_str()).collect();
+        paths.push(".");
+        for path in paths {
+            let mut state = egui::collapsing_header::CollapsingState::load_with_default_open(
+                ctx,
+                Self::folder_state_id(path),
+                false,
+            );
+            state.set_open(open);
+            state.store(ctx);
+        }
+    }
+    
+    fn show_folder(&mut self, ui: &mut egui::Ui, ctx: &egui::Context, node: &FolderNode, visible: &[bool]) {
+        let total = node.total_images();
+        let matching = node.count_matching(visible);
+        if matching == 0 && self.is_narrowed() {
+            return;
+        }
+        
+        let label = if self.is_narrowed() {
+            format!("{} ({} of {} images)", node.name, matching, total)
+        } else {
+            format!("{} ({} images)", node.name, total)
+        };
+        
+        let state = egui::collapsing_header::CollapsingState::load_with_default_open(
+            ctx,
+            Self::folder_state_id(&node.path),
+            false,
+        );
+        let mut label_clicked = false;
+        let mut header = state.show_header(ui, |ui| {
+            label_clicked = ui.add(egui::Label::new(label).sense(egui::Sense::click())).clicked();
+        });
+        if label_clicked {
+            header.toggle();
+        }
+        header.body(|ui| {
+            for child in &node.children {
+                self.show_folder(ui, ctx, child, visible);
+            }
+            
+            let image_indices: Vec<usize> = node.images.iter()
+                .copied()
+                .filter(|&index| visible.get(index).copied().unwrap_or(false))
+                .collect();
+            if !image_indices.is_empty() {
+                self.show_image_grid(ui, ctx, &node.path, &image_indices);
+            }
+        });
+    }
+    
+    fn show_image_grid(&mut self, ui: &mut egui::Ui, ctx: &egui::Context, folder: &str, image_indices: &[usize]) {
+        // Lay out fixed rows so keyboard navigation knows where each tile is
+        let spacing = ui.spacing().item_spacing.x;
+        let cell_width = self.thumbnail_size as f32 + 2.0 * ui.spacing().button_padding.x + spacing;
+        let columns = ((ui.available_width() + spacing) / cell_width).floor().max(1.0) as usize;
+        
+        // Only tiles that get laid out request their thumbnails
+        let row_count = image_indices.len().div_ceil(columns);
+        let row_of = |index| image_indices.iter().position(|&i| i == index).map(|position| position / columns);
+        self.show_rows(ui, row_count, row_of, |app, ui, row| {
+            let start = row * columns;
+            for &index in &image_indices[start..(start + columns).min(image_indices.len())] {
+                app.show_tile(ui, ctx, index);
+            }
+        });
+        
+        self.navigation.sections.push(GridSection {
+            folder: folder.to_owned(),
+            indices: image_indices.to_vec(),
+            columns,
+        });
+    }
+    
+    /// Folders with visible images, paired with those images, in display order
+    pub fn folder_grid_order(&self, visible: &[bool]) -> Vec<(String, Vec<usize>)> {
+        fn collect(node: &FolderNode, path: &str, visible: &[bool], order: &mut Vec<(String, Vec<usize>)>) {
+            for child in &node.children {
+                collect(child, &child.path, visible, order);
+            }
+            let indices: Vec<usize> = node.images.iter()
+                .copied()
+                .filter(|&index| visible.get(index).copied().unwrap_or(false))
+                .collect();
+            if !indices.is_empty() {
+                order.push((path.to_owned(), indices));
+            }
+        }
+        
+        let mut order = Vec::new();
+        let root_images = FolderNode { images: self.folder_tree.images.clone(), ..Default::default() };
+        collect(&root_images, ".", visible, &mut order);
+        for child in &self.folder_tree.children {
+            collect(child, &child.path, visible, &mut order);
+        }
+        order
+    }
+    
+    /// Open a folder and all of its ancestors
+    fn reveal_folder(ctx: &egui::Context, folder: &str) {
+        let mut path = String::new();
+        for name in folder.split('/') {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(name);
+            let mut state = egui::collapsing_header::CollapsingState::load_with_default_open(
+                ctx,
+                Self::folder_state_id(&path),
+                false,
+            );
+            state.set_open(true);
+            state.store(ctx);
+        }
+    }
+    
+    fn select(&mut self, index: Option<usize>) {
+        if index.is_some() && index != self.navigation.selected {
+            self.navigation.selected = index;
+            self.navigation.scroll_to_selected = true;
+        }
+    }
+    
+    /// Jump to the first image of the next (or previous) folder, opening it
+    fn select_adjacent_folder(&mut self, ctx: &egui::Context, visible: &[bool], forward: bool) {
+        let order = self.folder_grid_order(visible);
+        if order.is_empty() {
+            return;
+        }
+        
+        let current = self.navigation.selected.and_then(|selected| {
+            order.iter().position(|(_, indices)| indices.contains(&selected))
+        });
+        let target = match (current, forward) {
+            (None, true) => 0,
+            (None, false) => order.len() - 1,
+            (Some(position), true) => (position + 1) % order.len(),
+            (Some(position), false) => (position + order.len() - 1) % order.len(),
+        };
+        
+        let (folder, indices) = &order[target];
+        Self::reveal_folder(ctx, folder);
+        self.select(indices.first().copied());
+    }
+    
+    fn image_path(&self, index: usize) -> Option<PathBuf> {
+        self.images.read().ok()?.get(index).map(|image| image.path.clone())
+    }
+    
+    /// Set the wallpaper and remember it as the current selection, then run
+    /// the hooks. A hook failure is returned after the wallpaper is committed.
+    pub fn apply_wallpaper(&mut self, path: &Path) -> Result<()> {
+        let selection = self.selection_with(self.selection(), path)?;
+        let hook_target = self.hook_target(path);
+        self.set_selection(&selection)?;
+        self.commit_selection(path, selection);
+        run_hooks(&self.args.hooks, &hook_target, &self.runner(), self.args.debug)
+    }
+    
+    /// Put the recorded wallpapers back, e.g. at login, with the fit mode they
+    /// were set with. Images that no longer exist are dropped; if none are left,
+    /// a random image from the directory (within the filters) is applied instead.
+    /// Hooks only run for the random image, since a restored wallpaper was
+    /// already through them.
+    pub fn restore_wallpaper(&mut self) -> Result<PathBuf> {
+        let SelectionFile { mut selection, fit, .. } = self.read_selection_file();
+        if let Some(fit) = fit {
+            self.state.fit = fit;
+        }
+        selection.all = selection.all.filter(|path| path.is_file());
+        selection.monitors.retain(|_, path| path.is_file());
+        
+        if let Some(path) = selection.all.clone().or_else(|| selection.monitors.values().next().cloned()) {
+            if self.args.debug {
+                println!("Restoring {:?}", selection);
             }
+            self.set_selection(&selection)?;
+            return Ok(path);
         }
         
-        let elapsed = start_time.elapsed();
+        self.scan_images()?;
+        let len = self.images.read().map_err(|_| BackgroundPickerError::LockAcquisition)?.len();
+        let candidates = self.filtered_indices(&(0..len).collect::<Vec<_>>(), None);
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as usize)
+            .unwrap_or_default();
+        let path = candidates.get(seed % candidates.len().max(1))
+            .and_then(|&index| self.image_path(index))
+            .ok_or_else(|| BackgroundPickerError::NothingToRestore(self.args.directory.clone()))?;
         
-        if !self.args.debug && total_images > PROGRESS_THRESHOLD {
-            println!(); // New line after progress indicator
+        if self.args.debug {
+            println!("No wallpaper to restore, picked {}", path.display());
+        }
+        self.apply_wallpaper(&path)?;
+        Ok(path)
+    }
+    
+    /// What the hooks are told about applying `path`: the monitor it went to,
+    /// if just one, and the wallpaper it replaced there
+    fn hook_target(&self, path: &Path) -> CommandTarget {
+        let monitor = self.monitors.target.as_ref().filter(|_| !self.monitors.span);
+        let previous = match monitor {
+            Some(monitor) => self.selection().for_monitor(monitor).map(Path::to_path_buf),
+            None => self.current_wallpaper.clone(),
+        };
+        let size = monitor
+            .and_then(|name| self.monitors.available.iter().find(|m| &m.name == name))
+            .map(|m| m.size)
+            .or(self.screen_size);
+        CommandTarget {
+            path: path.to_path_buf(),
+            monitor: monitor.cloned(),
+            size,
+            fit: self.state.fit,
+            previous,
+        }
+    }
+    
+    /// Remember wallpapers that have been set
+    fn commit_selection(&mut self, path: &Path, selection: Selection) {
+        self.state.record_used(path);
+        self.save_state();
+        self.record_history(path);
+        self.remember_selection(selection);
+    }
+    
+    /// Note `selection` as what is on the desktop now
+    fn remember_selection(&mut self, selection: Selection) {
+        let _ = self.save_selection(&selection);
+        self.try_on = None;
+        self.current_wallpaper = selection.all;
+        self.monitors.assigned = selection.monitors;
+    }
+    
+    /// Log an apply. The file is reread first, so entries logged by other
+    /// instances, such as `--restore` at login, are kept.
+    fn record_history(&mut self, path: &Path) {
+        let span = self.monitors.span && self.monitors.available.len() > 1;
+        let entry = HistoryEntry {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            path: path.to_path_buf(),
+            backend: self.wallpaper_setter().name().to_owned(),
+            monitor: self.monitors.target.clone().filter(|_| !span),
+            span,
+        };
+        let result = self.reload_history().and_then(|()| {
+            self.recent.history.record(entry);
+            self.recent.history.save(&self.args.history_file)
+        });
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+    }
+    
+    /// Reread the history file, keeping the error for the Recent window
+    fn reload_history(&mut self) -> Result<()> {
+        match History::load(&self.args.history_file) {
+            Ok(history) => {
+                self.recent.history = history;
+                self.recent.error = None;
+                Ok(())
+            }
+            Err(e) => {
+                self.recent.history = History::default();
+                self.recent.error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+    
+    /// Step back to the wallpapers in effect before the latest history entry.
+    /// Returns the entry now current, or `None` if there is nothing to go back to.
+    pub fn undo(&mut self) -> Result<Option<HistoryEntry>> {
+        self.reload_history()?;
+        let position = self.recent.history.position();
+        if position < 2 {
+            return Ok(None);
+        }
+        self.show_history_position(position - 1)?;
+        self.recent.history.undone += 1;
+        self.recent.history.save(&self.args.history_file)?;
+        Ok(self.recent.history.entries.get(position - 2).cloned())
+    }
+    
+    /// Reapply the entry the last undo stepped back over
+    pub fn redo(&mut self) -> Result<Option<HistoryEntry>> {
+        self.reload_history()?;
+        if self.recent.history.undone == 0 {
+            return Ok(None);
         }
+        let position = self.recent.history.position() + 1;
+        self.show_history_position(position)?;
+        self.recent.history.undone -= 1;
+        self.recent.history.save(&self.args.history_file)?;
+        Ok(self.recent.history.entries.get(position - 1).cloned())
+    }
+    
+    /// Reapply the `number`th newest history entry (1 is the latest) as a new apply
+    pub fn reapply_history(&mut self, number: usize) -> Result<HistoryEntry> {
+        self.reload_history()?;
+        let entries = &self.recent.history.entries;
+        let entry = number.checked_sub(1)
+            .and_then(|offset| entries.len().checked_sub(offset + 1))
+            .map(|index| entries[index].clone())
+            .ok_or_else(|| BackgroundPickerError::CommandExecution(format!("There is no history entry {}", number)))?;
+        self.target_history_entry(&entry);
+        self.apply_wallpaper(&entry.path)?;
+        Ok(entry)
+    }
+    
+    /// Point the monitor selection at where `entry` was set, if that monitor is still there
+    fn target_history_entry(&mut self, entry: &HistoryEntry) {
+        let available = &self.monitors.available;
+        self.monitors.target = entry.monitor.clone()
+            .filter(|monitor| available.is_empty() || available.iter().any(|m| &m.name == monitor));
+        self.monitors.span = entry.span;
+    }
+    
+    /// Put back the desktop as it was after the first `position` history entries
+    fn show_history_position(&mut self, position: usize) -> Result<()> {
+        let entries = self.recent.history.in_effect(position).to_vec();
+        let Some(latest) = entries.last() else {
+            return Ok(());
+        };
         
-        if self.args.debug {
-            println!("Thumbnail pregeneration complete:");
-            println!("  - {} thumbnails found in cache", cached_count);
-            println!("  - {} thumbnails generated", generated_count);
-            println!("  - {} thumbnails failed", total_images - cached_count - generated_count);
-            println!("  - Time elapsed: {:.2}s", elapsed.as_secs_f64());
+        let mut selection = Selection::default();
+        for entry in &entries {
+            if entry.span && self.monitors.available.len() > 1 {
+                selection = self.span_selection(&entry.path)?;
+            } else {
+                selection.assign(entry.monitor.as_deref(), &entry.path);
+            }
+        }
+        let hook_target = self.hook_target(&latest.path);
+        self.set_selection(&selection)?;
+        self.remember_selection(selection);
+        run_hooks(&self.args.hooks, &hook_target, &self.runner(), self.args.debug)
+    }
+    
+    /// Do the work of a mode that runs without the picker window
+    pub fn run_without_gui(&mut self) -> Result<()> {
+        if self.args.pregenerate {
+            self.pregenerate_all_thumbnails()?;
+        } else if self.args.restore {
+            self.restore_wallpaper()?;
+        } else if self.args.undo || self.args.redo {
+            let (entry, nothing) = if self.args.undo {
+                (self.undo()?, "Nothing to undo")
+            } else {
+                (self.redo()?, "Nothing to redo")
+            };
+            match entry {
+                Some(entry) => println!("{} ({})", entry.path.display(), entry.target_label()),
+                None => println!("{}", nothing),
+            }
+        } else if let Some(number) = self.args.history {
+            match number {
+                Some(number) => {
+                    let entry = self.reapply_history(number)?;
+                    println!("{} ({})", entry.path.display(), entry.target_label());
+                }
+                None => {
+                    self.reload_history()?;
+                    print!("{}", format_history(&self.recent.history));
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// `selection` with `path` put where the status bar says: sliced across the
+    /// monitors when spanning, else on the chosen monitor or all of them
+    fn selection_with(&self, selection: Selection, path: &Path) -> Result<Selection> {
+        let (selection, span) = self.planned_selection(selection, path)?;
+        if let Some(span) = span {
+            span.render()?;
+        }
+        Ok(selection)
+    }
+    
+    /// Like `selection_with`, but leaves cutting the slices to the caller
+    fn planned_selection(&self, mut selection: Selection, path: &Path) -> Result<(Selection, Option<SpanPlan>)> {
+        if self.monitors.span && self.monitors.available.len() > 1 {
+            let (selection, span) = self.span_plan(path)?;
+            return Ok((selection, Some(span)));
+        }
+        selection.assign(self.monitors.target.as_deref(), path);
+        Ok((selection, None))
+    }
+    
+    /// `path` sliced across all monitors
+    fn span_selection(&self, path: &Path) -> Result<Selection> {
+        let (selection, span) = self.span_plan(path)?;
+        span.render()?;
+        Ok(selection)
+    }
+    
+    /// The selection for `path` sliced across all monitors, before the slices exist
+    fn span_plan(&self, path: &Path) -> Result<(Selection, SpanPlan)> {
+        let span = SpanPlan::new(path, &self.monitors.available, self.args.bezel, &self.span_cache_dir())?;
+        let selection = Selection {
+            all: Some(path.to_path_buf()),
+            monitors: span.files().into_iter().collect(),
+        };
+        Ok((selection, span))
+    }
+    
+    /// Where spanned slices are kept: next to, not inside, the shared thumbnail cache
+    pub fn span_cache_dir(&self) -> PathBuf {
+        match self.cache_dir.parent().and_then(Path::parent) {
+            Some(cache_home) if self.cache_dir.ends_with("thumbnails/normal") => cache_home.join("background-picker").join("span"),
+            _ => self.cache_dir.join("span"),
+        }
+    }
+    
+    /// The wallpapers currently set
+    pub fn selection(&self) -> Selection {
+        Selection {
+            all: self.current_wallpaper.clone(),
+            monitors: self.monitors.assigned.clone(),
+        }
+    }
+    
+    /// The wallpapers last committed, as recorded in `selected_image_file`
+    pub fn read_selection(&self) -> Selection {
+        self.read_selection_file().selection
+    }
+    
+    /// Everything recorded in `selected_image_file`
+    pub fn read_selection_file(&self) -> SelectionFile {
+        fs::read(&self.args.selected_image_file)
+            .map(|contents| SelectionFile::parse(&contents))
+            .unwrap_or_default()
+    }
+    
+    /// The wallpaper last committed for every monitor
+    pub fn read_selected_image(&self) -> Option<PathBuf> {
+        self.read_selection().all
+    }
+    
+    /// Set a wallpaper without committing to it; see `revert_try_on`
+    pub fn try_wallpaper(&mut self, path: &Path) -> Result<()> {
+        let (selection, try_on, span) = self.prepare_try(path)?;
+        if let Some(span) = span {
+            span.render()?;
+        }
+        self.set_selection(&selection)?;
+        self.try_on = Some(try_on);
+        Ok(())
+    }
+    
+    fn prepare_try(&self, path: &Path) -> Result<(Selection, TryOn, Option<SpanPlan>)> {
+        let previous = match &self.try_on {
+            Some(try_on) => try_on.previous.clone(),
+            None => self.read_selected_image(),
+        };
+        let (selection, span) = self.planned_selection(self.read_selection(), path)?;
+        let try_on = TryOn { current: path.to_path_buf(), previous, monitor: self.monitors.target.clone() };
+        Ok((selection, try_on, span))
+    }
+    
+    /// Put back the wallpaper that was set before trying started. The try-on is
+    /// kept if that fails, so it can be retried.
+    pub fn revert_try_on(&mut self) -> Result<()> {
+        if let Some(try_on) = &self.try_on {
+            self.set_selection(&self.reverted_selection(try_on))?;
+            self.try_on = None;
+        }
+        Ok(())
+    }
+    
+    /// What was committed before `try_on`
+    fn reverted_selection(&self, try_on: &TryOn) -> Selection {
+        let committed = self.read_selection();
+        if committed.monitors.is_empty() {
+            Selection { all: try_on.previous.clone(), monitors: BTreeMap::new() }
         } else {
-            println!("Thumbnail generation complete: {} cached, {} generated ({:.1}s)", 
-                cached_count, generated_count, elapsed.as_secs_f64());
+            committed
+        }
+    }
+    
+    fn report_error(&mut self, context: &str, error: BackgroundPickerError) {
+        eprintln!("{}: {}", context, error);
+        self.status = Some(format!("{}: {}", context, error));
+        if let BackgroundPickerError::CommandFailed(failure) = error {
+            self.command_error = Some(failure);
         }
+    }
+    
+    fn show_command_error(&mut self, ctx: &egui::Context) {
+        let Some(failure) = &self.command_error else {
+            return;
+        };
         
-        Ok(())
+        let mut open = true;
+        let mut dismissed = false;
+        egui::Window::new("Setting the background failed")
+            .id(egui::Id::new("command_error_window"))
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(true)
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-12.0, -40.0))
+            .show(ctx, |ui| {
+                egui::Grid::new("command_error_grid").num_columns(2).show(ui, |ui| {
+                    ui.strong("Command");
+                    ui.monospace(&failure.command_line);
+                    ui.end_row();
+                    ui.strong("Status");
+                    ui.label(&failure.status);
+                    ui.end_row();
+                });
+                
+                if !failure.stderr.trim().is_empty() {
+                    ui.strong("Error output");
+                    egui::ScrollArea::vertical().max_height(160.0).show(ui, |ui| {
+                        ui.monospace(failure.stderr.trim_end());
+                    });
+                }
+                
+                ui.horizontal(|ui| {
+                    if ui.button("Copy details").clicked() {
+                        ctx.copy_text(failure.details());
+                    }
+                    if ui.button("Dismiss").clicked() {
+                        dismissed = true;
+                    }
+                });
+            });
+        
+        if !open || dismissed {
+            self.command_error = None;
+        }
     }
     
-    pub fn load_thumbnail(&mut self, _ctx: &egui::Context, index: usize) {
-        let images_len = self.images.read().map(|images| images.len()).unwrap_or(0);
-        if index >= images_len {
+    /// Applied wallpapers, newest first; clicking one applies it again where it was set
+    fn show_recent(&mut self, ctx: &egui::Context) {
+        if !self.recent.open {
             return;
         }
         
-        let (should_load, path) = {
-            if let Ok(mut images) = self.images.write() {
-                if images[index].thumbnail.is_some() || images[index].loading {
+        let mut open = true;
+        let mut chosen = None;
+        egui::Window::new("Recent wallpapers")
+            .id(egui::Id::new("recent_window"))
+            .open(&mut open)
+            .resizable(true)
+            .default_width(420.0)
+            .show(ctx, |ui| {
+                if let Some(error) = &self.recent.error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
                     return;
                 }
-                images[index].loading = true;
-                (true, images[index].path.clone())
-            } else {
-                return;
-            }
-        };
-        
-        if should_load {
-            let sender = self.thumbnail_sender.clone();
-            let size = self.args.thumbnail_size;
-            let cache_dir = self.cache_dir.clone();
-            let debug = self.args.debug;
-            
-            self.thread_pool.spawn(move || {
-                if let Some(color_image) = Self::load_or_generate_thumbnail(&path, size, &cache_dir, debug) {
-                    let _ = sender.send((index, color_image));
+                
+                let history = &self.recent.history;
+                if history.entries.is_empty() {
+                    ui.label("No wallpapers applied yet");
+                    return;
                 }
+                
+                let position = history.position();
+                egui::ScrollArea::vertical().max_height(320.0).show(ui, |ui| {
+                    egui::Grid::new("recent_grid").num_columns(3).striped(true).show(ui, |ui| {
+                        for (index, entry) in history.entries.iter().enumerate().rev() {
+                            ui.label(format_age(entry.age())).on_hover_text(format!("Set with {}", entry.backend));
+                            ui.label(entry.target_label());
+                            let name = entry.path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                            let text = if index >= position {
+                                egui::RichText::new(format!("{} (undone)", name)).weak()
+                            } else {
+                                egui::RichText::new(name)
+                            };
+                            if ui.link(text).on_hover_text(entry.path.display().to_string()).clicked() {
+                                chosen = Some(entry.clone());
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
             });
+        
+        self.recent.open = open;
+        if let Some(entry) = chosen.filter(|_| !self.is_busy()) {
+            self.target_history_entry(&entry);
+            self.apply_from_ui(ctx, &entry.path, !self.args.stay_open);
         }
     }
     
-    pub fn load_or_generate_thumbnail(path: &Path, size: u32, cache_dir: &Path, debug: bool) -> Option<egui::ColorImage> {
-        // Get absolute path for cache key generation
-        let abs_path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    /// Set the wallpaper on a worker thread, then act on the result in `poll_pending_command`.
+    /// Spanned slices in `span` are cut there first. With a `hook_target`, the
+    /// hooks run on the same thread once the wallpaper is set.
+    fn start_command(&mut self, ctx: &egui::Context, selection: &Selection, span: Option<SpanPlan>, action: PendingAction, hook_target: Option<CommandTarget>) {
+        let setter = self.wallpaper_setter();
+        let targets = self.targets_for(selection, setter.as_ref());
+        let hooks = self.args.hooks.clone();
+        let runner = self.runner();
+        let debug = self.args.debug;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let set = span.as_ref().map_or(Ok(()), SpanPlan::render)
+                .and(targets)
+                .and_then(|targets| set_targets(setter.as_ref(), &targets, debug));
+            let hooks = match (&set, hook_target) {
+                (Ok(()), Some(target)) => run_hooks(&hooks, &target, &runner, debug),
+                _ => Ok(()),
+            };
+            let _ = sender.send(CommandOutcome { set, hooks });
+            ctx.request_repaint();
+        });
+        self.pending = Some(PendingCommand { action, receiver });
+    }
+    
+    /// Only one wallpaper command runs at a time
+    fn is_busy(&mut self) -> bool {
+        if self.pending.is_some() {
+            self.status = Some("Still waiting for the wallpaper command to finish".to_owned());
+        }
+        self.pending.is_some()
+    }
+    
+    /// Act on the result of the running command, if it has finished
+    pub fn poll_pending_command(&mut self, ctx: &egui::Context) {
+        let Some(pending) = &self.pending else {
+            return;
+        };
+        let outcome = match pending.receiver.try_recv() {
+            Ok(outcome) => outcome,
+            Err(std::sync::mpsc::TryRecvError::Empty) => return,
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => CommandOutcome {
+                set: Err(BackgroundPickerError::CommandExecution(
+                    "The wallpaper command stopped without a result".to_owned(),
+                )),
+                hooks: Ok(()),
+            },
+        };
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
         
-        // First, look for existing thumbnails created by other applications (pcmanfm, etc.)
-        if let Some(existing_thumbnail) = Self::find_existing_thumbnail(&abs_path) {
-            if let Some(cached_image) = Self::load_cached_thumbnail(&existing_thumbnail, size) {
-                if debug {
-                    println!("Loaded existing system thumbnail for {:?}", path.file_name().unwrap_or_default());
+        match (pending.action, outcome.set) {
+            (PendingAction::Apply { path, selection, close }, Ok(())) => {
+                self.commit_selection(&path, selection);
+                if let Err(e) = outcome.hooks {
+                    // Stay open so the failure can be read
+                    self.report_error("Post-apply hook failed", e);
+                } else if close {
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                } else {
+                    self.status = Some(format!("Wallpaper set to {}", path.display()));
                 }
-                return Some(cached_image);
             }
+            (PendingAction::Apply { .. }, Err(e)) => self.report_error("Failed to set background", e),
+            (PendingAction::Try(try_on), Ok(())) => {
+                self.status = Some(format!("Trying {}", try_on.current.display()));
+                self.try_on = Some(try_on);
+            }
+            (PendingAction::Try(_), Err(e)) => self.report_error("Failed to try background", e),
+            (PendingAction::Revert, Ok(())) => {
+                self.try_on = None;
+                self.status = Some("Restored the previous wallpaper".to_owned());
+            }
+            (PendingAction::Revert, Err(e)) => self.report_error("Failed to restore previous background", e),
         }
         
-        // Try to load from our own cache
-        if let Some(cache_path) = Self::get_cached_thumbnail_path_static(&abs_path, cache_dir) {
-            if Self::is_thumbnail_cache_valid_static(&abs_path, &cache_path) {
-                if let Some(cached_image) = Self::load_cached_thumbnail(&cache_path, size) {
-                    if debug {
-                        println!("Loaded our cached thumbnail for {:?}", path.file_name().unwrap_or_default());
-                    }
-                    return Some(cached_image);
-                }
+        if self.closing {
+            self.closing = false;
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+    }
+    
+    /// Try the wallpaper on a worker thread, like `try_wallpaper`
+    pub fn try_from_ui(&mut self, ctx: &egui::Context, path: &Path) {
+        if self.is_busy() {
+            return;
+        }
+        match self.prepare_try(path) {
+            Ok((selection, try_on, span)) => {
+                self.status = Some(format!("Trying {}…", path.display()));
+                self.start_command(ctx, &selection, span, PendingAction::Try(try_on), None);
             }
+            Err(e) => self.report_error("Failed to try background", e),
         }
-        
-        // Generate new thumbnail and cache it
-        if debug {
-            println!("Generating new thumbnail for {:?}", path.file_name().unwrap_or_default());
+    }
+    
+    fn revert_from_ui(&mut self, ctx: &egui::Context) {
+        if self.is_busy() {
+            return;
         }
-        let color_image = Self::fast_thumbnail_generation(&abs_path, size)?;
-        
-        // Save to cache for future use
-        if let Some(cache_path) = Self::get_cached_thumbnail_path_static(&abs_path, cache_dir) {
-            Self::save_thumbnail_to_cache(&color_image, &cache_path, &abs_path);
+        // The try-on is only dropped once the revert has worked
+        if let Some(try_on) = &self.try_on {
+            let selection = self.reverted_selection(try_on);
+            self.status = Some("Restoring the previous wallpaper…".to_owned());
+            self.start_command(ctx, &selection, None, PendingAction::Revert, None);
         }
-        
-        Some(color_image)
     }
     
-    pub fn get_cached_thumbnail_path_static(file_path: &Path, cache_dir: &Path) -> Option<PathBuf> {
-        let hash = Self::get_thumbnail_hash(file_path)?;
-        Some(cache_dir.join(format!("{}.png", hash)))
+    /// Put back the previous wallpaper before the window closes, waiting for it
+    fn revert_or_report(&mut self) {
+        if let Err(e) = self.revert_try_on() {
+            self.report_error("Failed to restore previous background", e);
+        }
     }
     
-    pub fn is_thumbnail_cache_valid_static(original_path: &Path, cache_path: &Path) -> bool {
-        if !cache_path.exists() {
-            return false;
+    /// The window is closing. A running command is finished first, so that a
+    /// try-on can still be reverted and an apply recorded.
+    pub fn handle_close_request(&mut self, ctx: &egui::Context) {
+        if self.pending.is_some() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            self.close_when_idle();
+        } else {
+            // Closing the window while trying a wallpaper means it was rejected
+            self.revert_or_report();
         }
-        
-        let original_modified = fs::metadata(original_path)
-            .and_then(|m| m.modified())
-            .unwrap_or(SystemTime::UNIX_EPOCH);
-            
-        let cache_modified = fs::metadata(cache_path)
-            .and_then(|m| m.modified())
-            .unwrap_or(SystemTime::UNIX_EPOCH);
-            
-        cache_modified >= original_modified
     }
     
-    pub fn load_cached_thumbnail(cache_path: &Path, target_size: u32) -> Option<egui::ColorImage> {
-        match image::io::Reader::open(cache_path) {
-            Ok(reader) => {
-                if let Ok(img) = reader.with_guessed_format().ok()?.decode() {
-                    // Resize cached thumbnail to target size if needed
-                    let resized = if img.width() != target_size || img.height() != target_size {
-                        img.resize(target_size, target_size, FilterType::Nearest)
-                    } else {
-                        img
-                    };
-                    Self::create_thumbnail_fast(resized, target_size)
-                } else {
-                    None
-                }
+    fn close_when_idle(&mut self) {
+        self.closing = true;
+        self.status = Some("Closing once the wallpaper command finishes…".to_owned());
+    }
+    
+    /// Set the wallpaper, then close the picker unless asked to stay open
+    fn apply_from_ui(&mut self, ctx: &egui::Context, path: &Path, close: bool) {
+        if self.is_busy() {
+            return;
+        }
+        match self.planned_selection(self.selection(), path) {
+            Ok((selection, span)) => {
+                self.status = Some(format!("Setting wallpaper to {}…", path.display()));
+                let action = PendingAction::Apply { path: path.to_path_buf(), selection: selection.clone(), close };
+                let hook_target = self.hook_target(path);
+                self.start_command(ctx, &selection, span, action, Some(hook_target));
             }
-            Err(_) => None,
+            Err(e) => self.report_error("Failed to set background", e),
         }
     }
     
-    pub fn save_thumbnail_to_cache(color_image: &egui::ColorImage, cache_path: &Path, original_path: &Path) {
-        // Convert egui::ColorImage back to image format for caching
-        let [width, height] = color_image.size;
+    fn show_status_bar(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::bottom("status_bar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.args.stay_open, "Stay open after applying");
+                ui.separator();
+                ui.label("Fit:");
+                let mut fit = self.state.fit;
+                egui::ComboBox::from_id_source("fit_mode")
+                    .selected_text(fit.label())
+                    .show_ui(ui, |ui| {
+                        for option in FitMode::ALL {
+                            ui.selectable_value(&mut fit, option, option.label());
+                        }
+                    });
+                if fit != self.state.fit {
+                    self.state.fit = fit;
+                    self.save_state();
+                }
+                self.show_monitor_selector(ui);
+                ui.separator();
+                if self.pending.is_some() {
+                    ui.spinner();
+                }
+                let current = match &self.monitors.target {
+                    Some(monitor) => self.selection().for_monitor(monitor).map(Path::to_path_buf),
+                    None => self.current_wallpaper.clone(),
+                };
+                match &self.status {
+                    Some(status) => ui.label(status),
+                    None => match current {
+                        Some(current) => ui.label(format!("Current wallpaper: {}", current.display())),
+                        None => ui.label("No wallpaper selected yet"),
+                    },
+                };
+            });
+        });
+    }
+    
+    /// Choose which monitor the next wallpaper goes to, when there is more than one
+    fn show_monitor_selector(&mut self, ui: &mut egui::Ui) {
+        if self.monitors.available.len() < 2 {
+            return;
+        }
         
-        // Pre-allocate vector with exact capacity for better performance
-        let mut pixels = Vec::with_capacity(color_image.pixels.len() * 4);
-        for pixel in &color_image.pixels {
-            pixels.extend_from_slice(&[pixel.r(), pixel.g(), pixel.b(), pixel.a()]);
+        ui.separator();
+        ui.label("Monitor:");
+        let selected_text = match (&self.monitors.target, self.monitors.span) {
+            (_, true) => "Span across monitors".to_owned(),
+            (Some(monitor), false) => monitor.clone(),
+            (None, false) => "All monitors".to_owned(),
+        };
+        egui::ComboBox::from_id_source("target_monitor")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                let monitors = &mut self.monitors;
+                if ui.selectable_label(monitors.target.is_none() && !monitors.span, "All monitors").clicked() {
+                    monitors.target = None;
+                    monitors.span = false;
+                }
+                if ui.selectable_label(monitors.span, "Span across monitors").clicked() {
+                    monitors.target = None;
+                    monitors.span = true;
+                }
+                for monitor in &monitors.available {
+                    let label = format!("{} ({}x{})", monitor.name, monitor.size.0, monitor.size.1);
+                    let selected = !monitors.span && monitors.target.as_ref() == Some(&monitor.name);
+                    if ui.selectable_label(selected, label).clicked() {
+                        monitors.target = Some(monitor.name.clone());
+                        monitors.span = false;
+                    }
+                }
+            });
+    }
+    
+    fn open_preview(&mut self, ctx: &egui::Context, index: usize) {
+        let Some(path) = self.image_path(index) else {
+            return;
+        };
+        let max_size = Self::screen_size(ctx).unwrap_or((2560, 1440));
+        self.preview.open(ctx, index, path, max_size);
+    }
+    
+    fn handle_keyboard(&mut self, ctx: &egui::Context, visible: &[bool]) {
+        if ctx.wants_keyboard_input() {
+            return;
         }
         
-        if let Some(img_buffer) = image::RgbaImage::from_raw(
-            width as u32, 
-            height as u32, 
-            pixels
-        ) {
-            let dynamic_img = image::DynamicImage::ImageRgba8(img_buffer);
-            
-            // Create parent directory if it doesn't exist
-            if let Some(parent) = cache_path.parent() {
-                let _ = fs::create_dir_all(parent);
+        let direction_keys = [
+            (egui::Key::ArrowLeft, Direction::Left),
+            (egui::Key::H, Direction::Left),
+            (egui::Key::ArrowRight, Direction::Right),
+            (egui::Key::L, Direction::Right),
+            (egui::Key::ArrowUp, Direction::Up),
+            (egui::Key::K, Direction::Up),
+            (egui::Key::ArrowDown, Direction::Down),
+            (egui::Key::J, Direction::Down),
+        ];
+        let digit_keys = [
+            egui::Key::Num1, egui::Key::Num2, egui::Key::Num3,
+            egui::Key::Num4, egui::Key::Num5, egui::Key::Num6,
+            egui::Key::Num7, egui::Key::Num8, egui::Key::Num9,
+        ];
+        
+        let (directions, previous_folder, next_folder, enter, space, escape, assign_favorite, use_favorite, try_on) = ctx.input_mut(|i| {
+            let directions: Vec<Direction> = direction_keys.iter()
+                .filter(|(key, _)| i.consume_key(egui::Modifiers::NONE, *key))
+                .map(|(_, direction)| *direction)
+                .collect();
+            // Consume the modified variants first; plain matching ignores extra modifiers
+            let previous_folder = i.consume_key(egui::Modifiers::SHIFT, egui::Key::Tab);
+            let next_folder = i.consume_key(egui::Modifiers::NONE, egui::Key::Tab);
+            let assign_favorite = (1u8..).zip(digit_keys).find(|(_, key)| i.consume_key(egui::Modifiers::COMMAND, *key)).map(|(slot, _)| slot);
+            let use_favorite = (1u8..).zip(digit_keys).find(|(_, key)| i.consume_key(egui::Modifiers::NONE, *key)).map(|(slot, _)| slot);
+            (
+                directions,
+                previous_folder,
+                next_folder,
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Enter),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Space),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Escape),
+                assign_favorite,
+                use_favorite,
+                i.consume_key(egui::Modifiers::NONE, egui::Key::T),
+            )
+        });
+        
+        for direction in directions {
+            let target = navigate_grid(&self.navigation.sections, self.navigation.selected, direction);
+            self.select(target);
+            if let (true, Some(selected)) = (self.preview.is_open(), self.navigation.selected) {
+                self.open_preview(ctx, selected);
+            }
+        }
+        
+        if previous_folder || next_folder {
+            self.select_adjacent_folder(ctx, visible, next_folder);
+        }
+        
+        if space {
+            if self.preview.is_open() {
+                self.preview.close();
+            } else if let Some(selected) = self.navigation.selected {
+                self.open_preview(ctx, selected);
+            }
+        }
+        
+        if try_on {
+            let target = self.navigation.hovered.or(self.navigation.selected);
+            if let Some(path) = target.and_then(|index| self.image_path(index)) {
+                self.try_from_ui(ctx, &path);
             }
-            
-            // Save with freedesktop.org thumbnail metadata
-            Self::save_thumbnail_with_metadata(&dynamic_img, cache_path, original_path);
         }
-    }
-    
-    pub fn save_thumbnail_with_metadata(img: &image::DynamicImage, cache_path: &Path, original_path: &Path) {
-        // Get file metadata for thumbnail spec compliance (currently unused but could be added later)
-        let _file_uri = format!("file://{}", 
-            fs::canonicalize(original_path)
-                .unwrap_or_else(|_| original_path.to_path_buf())
-                .to_string_lossy()
-        );
         
-        let _file_size = fs::metadata(original_path)
-            .map(|m| m.len())
-            .unwrap_or(0);
-            
-        let _mtime = fs::metadata(original_path)
-            .and_then(|m| m.modified())
-            .map(|t| t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs())
-            .unwrap_or(0);
+        if escape {
+            if self.preview.is_open() {
+                self.preview.close();
+            } else if self.pending.is_some() {
+                // Revert or record what the command did, then leave
+                self.close_when_idle();
+            } else if self.try_on.is_some() {
+                self.revert_from_ui(ctx);
+            } else {
+                // Leave without changing anything
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+        }
         
-        // Create PNG encoder with metadata
-        use std::io::BufWriter;
-        use std::fs::File;
+        if let Some(slot) = assign_favorite {
+            if let Some(path) = self.navigation.selected.and_then(|index| self.image_path(index)) {
+                self.state.favorites.insert(slot, path);
+                self.save_state();
+            }
+        }
         
-        if let Ok(file) = File::create(cache_path) {
-            let writer = BufWriter::new(file);
-            let encoder = image::codecs::png::PngEncoder::new(writer);
-            
-            // Convert to RGB for PNG encoding
-            let rgb_img = img.to_rgb8();
-            
-            if let Err(e) = encoder.write_image(
-                rgb_img.as_raw(),
-                img.width(),
-                img.height(),
-                image::ColorType::Rgb8,
-            ) {
-                eprintln!("Failed to save thumbnail for {:?}: {}", original_path, e);
+        if let Some(slot) = use_favorite {
+            if let Some(path) = self.state.favorites.get(&slot).cloned() {
+                self.apply_from_ui(ctx, &path, !self.args.stay_open);
             }
         }
         
-        // Add metadata using external PNG tools would be ideal, but for now this basic save works
-        // The important part is using the correct hash and cache location
+        if enter {
+            if let Some(path) = self.navigation.selected.and_then(|index| self.image_path(index)) {
+                self.apply_from_ui(ctx, &path, !self.args.stay_open);
+            }
+        }
     }
     
-    pub fn fast_thumbnail_generation(path: &Path, size: u32) -> Option<egui::ColorImage> {
-        // Use image reader with auto format detection
-        let reader = image::io::Reader::open(path).ok()?
-            .with_guessed_format().ok()?;
+    fn show_preview(&mut self, ctx: &egui::Context) {
+        let Some(index) = self.preview.index else {
+            return;
+        };
+        self.preview.poll(ctx);
         
-        // Try to get dimensions first to avoid full decode if possible
-        let img = reader.decode().ok()?;
-        let (width, height) = (img.width(), img.height());
+        let Some(image) = self.images.read().ok().and_then(|images| images.get(index).cloned()) else {
+            self.preview.close();
+            return;
+        };
         
-        // Early return for already small images
-        if width <= size && height <= size {
-            return Self::create_thumbnail_fast(img, size);
+        enum Action {
+            Apply,
+            ApplyAndStay,
+            Try,
+            Cancel,
         }
         
-        // Calculate optimal resize strategy based on image size
-        let scale_factor = (width.max(height) as f32 / size as f32).max(1.0);
+        let mut open = true;
+        let mut action = None;
+        let max_image_size = ctx.screen_rect().size() * 0.8;
         
-        if scale_factor > 8.0 {
-            // For very large images, use three-step resize for better quality/performance balance
-            let first_step = (size as f32 * 4.0) as u32;
-            let second_step = (size as f32 * 2.0) as u32;
-            
-            let step1 = img.resize(first_step, first_step, FilterType::Nearest);
-            let step2 = step1.resize(second_step, second_step, FilterType::Triangle);
-            Self::create_thumbnail_fast(step2, size)
-        } else if scale_factor > 4.0 {
-            // For large images, use two-step resize
-            let intermediate_size = size * 2;
-            let intermediate = img.resize(intermediate_size, intermediate_size, FilterType::Nearest);
-            Self::create_thumbnail_fast(intermediate, size)
-        } else {
-            // For moderately sized images, direct resize with higher quality filter
-            Self::create_thumbnail_fast(img, size)
+        egui::Window::new(image.relative_path.as_str())
+            .id(egui::Id::new("preview_window"))
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                if let Some(texture) = &self.preview.texture {
+                    ui.add(egui::Image::new(texture).max_size(max_image_size).maintain_aspect_ratio(true));
+                } else if let Some(error) = &self.preview.error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                } else {
+                    // Show the thumbnail while the full image decodes
+                    ui.horizontal(|ui| {
+                        if let Some(thumbnail) = &image.thumbnail {
+                            ui.image(thumbnail);
+                        }
+                        ui.spinner();
+                    });
+                }
+                
+                ui.separator();
+                ui.label(image.path.display().to_string());
+                ui.horizontal(|ui| {
+                    if let Some((width, height)) = image.dimensions {
+                        ui.label(format!("{} × {}", width, height));
+                        ui.label(format!("{:.2}:1", width as f32 / height.max(1) as f32));
+                    }
+                    ui.label(format_file_size(image.file_size));
+                    if let Some(age) = image.modified.and_then(|modified| modified.elapsed().ok()) {
+                        ui.label(format!("modified {}", format_age(age)));
+                    }
+                });
+                
+                ui.horizontal(|ui| {
+                    if ui.button("Apply").clicked() {
+                        action = Some(Action::Apply);
+                    }
+                    if ui.button("Apply and stay open").clicked() {
+                        action = Some(Action::ApplyAndStay);
+                    }
+                    if ui.button("Try it").on_hover_text("Set it on the desktop; Cancel restores the previous wallpaper").clicked() {
+                        action = Some(Action::Try);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        action = Some(Action::Cancel);
+                    }
+                });
+            });
+        
+        match action {
+            Some(Action::Apply) => self.apply_from_ui(ctx, &image.path, !self.args.stay_open),
+            Some(Action::ApplyAndStay) => self.apply_from_ui(ctx, &image.path, false),
+            Some(Action::Try) => self.try_from_ui(ctx, &image.path),
+            Some(Action::Cancel) => {
+                self.preview.close();
+                self.revert_from_ui(ctx);
+            }
+            None => {}
+        }
+        
+        if !open {
+            self.preview.close();
+            self.revert_from_ui(ctx);
         }
     }
     
-    pub fn create_thumbnail_fast(img: image::DynamicImage, size: u32) -> Option<egui::ColorImage> {
-        // Use fastest resize algorithm for thumbnails
-        let thumbnail = img.resize(size, size, FilterType::Nearest);
-        let rgba = thumbnail.to_rgba8();
-        let (width, height) = (thumbnail.width() as usize, thumbnail.height() as usize);
-        
-        // Pre-allocate the pixel buffer for better performance
-        let pixel_count = width * height;
-        let raw_pixels = rgba.as_raw();
+    /// Outline the current wallpaper and the keyboard selection
+    fn highlight_tile(&mut self, ui: &egui::Ui, response: &egui::Response, index: usize, path: &Path) {
+        if self.current_wallpaper.as_deref() == Some(path) || self.monitors.assigned.values().any(|assigned| assigned == path) {
+            ui.painter().rect_stroke(
+                response.rect.shrink(1.0),
+                egui::Rounding::same(4.0),
+                egui::Stroke::new(3.0, egui::Color32::from_rgb(60, 180, 75)),
+            );
+        }
         
-        if raw_pixels.len() != pixel_count * 4 {
-            return None; // Safety check
+        if self.navigation.selected != Some(index) {
+            return;
         }
         
-        Some(egui::ColorImage::from_rgba_unmultiplied(
-            [width, height],
-            raw_pixels,
-        ))
+        ui.painter().rect_stroke(
+            response.rect.expand(2.0),
+            egui::Rounding::same(4.0),
+            egui::Stroke::new(3.0, ui.visuals().selection.stroke.color),
+        );
+        if self.navigation.scroll_to_selected {
+            response.scroll_to_me(Some(egui::Align::Center));
+            self.navigation.scroll_to_selected = false;
+        }
     }
     
-    pub fn process_thumbnail_results(&mut self, ctx: &egui::Context) {
-        while let Ok((index, color_image)) = self.thumbnail_receiver.try_recv() {
-            let texture = ctx.load_texture(
-                format!("thumbnail_{}", index),
-                color_image,
-                egui::TextureOptions::default(),
-            );
-            
-            if let Ok(mut images) = self.images.write() {
-                if index < images.len() {
-                    images[index].thumbnail = Some(texture);
-                    images[index].loading = false;
+    /// Draw one thumbnail (or its placeholder) and handle clicks on it
+    fn show_tile(&mut self, ui: &mut egui::Ui, ctx: &egui::Context, index: usize) {
+        self.load_thumbnail(ctx, index);
+        
+        let image_info = {
+            match self.images.read() {
+                Ok(images) => {
+                    if index >= images.len() {
+                        return;
+                    }
+                    // Clone the data we need
+                    (
+                        images[index].thumbnail_failed,
+                        images[index].path.clone(),
+                        images[index].relative_path.clone(),
+                        images[index].thumbnail.clone(),
+                        images[index].dimensions,
+                    )
                 }
+                Err(_) => return,
             }
-        }
-    }
-    
-    pub fn preload_batch(&mut self, indices: &[usize]) {
-        // Preload first few thumbnails when folder opens
-        let images_len = match self.images.read() {
-            Ok(images) => images.len(),
-            Err(_) => return,
         };
         
-        // Collect paths that need loading to minimize lock time
-        let mut paths_to_load = Vec::new();
+        let (failed, path, relative_path, texture_ref, dimensions) = image_info;
+        let hover_text = match self.search.get(index) {
+            Some(search_match) => Self::highlighted_text(ui, &relative_path, &search_match.positions),
+            None => egui::text::LayoutJob::simple_singleline(relative_path, egui::FontId::default(), ui.visuals().text_color()),
+        };
         
-        {
-            let mut images = match self.images.write() {
-                Ok(images) => images,
-                Err(_) => return,
+        // Every tile takes the same cell, loaded or not, so nothing moves when textures arrive
+        let padding = ui.spacing().button_padding;
+        let size = self.thumbnail_size as f32;
+        let (cell, response) = ui.allocate_exact_size(egui::vec2(size, size) + 2.0 * padding, egui::Sense::click());
+        
+        if ui.is_rect_visible(cell) {
+            let visuals = ui.style().interact(&response);
+            ui.painter().rect(cell, visuals.rounding, visuals.weak_bg_fill, visuals.bg_stroke);
+            
+            // The header size is known before the texture, so use it for both
+            let inner = cell.shrink2(padding);
+            let aspect = dimensions
+                .map(|(width, height)| width as f32 / height.max(1) as f32)
+                .or_else(|| texture_ref.as_ref().map(|texture| texture.aspect_ratio()))
+                .unwrap_or(1.0);
+            let (image_rect, uv) = match self.state.tile_mode {
+                TileMode::Letterbox => (letterbox_rect(inner, aspect), egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0))),
+                TileMode::Crop => (inner, crop_uv(aspect)),
             };
             
-            for &index in indices.iter().take(DEFAULT_PRELOAD_COUNT) {
-                if index >= images_len {
-                    continue;
+            match &texture_ref {
+                Some(texture) => {
+                    ui.painter().image(texture.id(), image_rect, uv, egui::Color32::WHITE);
                 }
-                
-                if images[index].thumbnail.is_none() && !images[index].loading {
-                    images[index].loading = true;
-                    paths_to_load.push((index, images[index].path.clone()));
+                None => {
+                    // Show placeholder for loading images
+                    ui.painter().rect_filled(
+                        image_rect,
+                        egui::Rounding::same(5.0),
+                        egui::Color32::LIGHT_GRAY
+                    );
+                    
+                    // Tiles without a thumbnail yet are queued for one
+                    let loading_text = if failed { "No preview" } else { "Loading..." };
+                    ui.painter().text(
+                        image_rect.center(),
+                        egui::Align2::CENTER_CENTER,
+                        loading_text,
+                        egui::FontId::default(),
+                        egui::Color32::DARK_GRAY
+                    );
                 }
             }
         }
         
-        // Spawn loading tasks
-        let sender = self.thumbnail_sender.clone();
-        let size = self.args.thumbnail_size;
-        let cache_dir = self.cache_dir.clone();
-        let debug = self.args.debug;
+        if response.double_clicked() {
+            self.navigation.selected = Some(index);
+            self.apply_from_ui(ctx, &path, !self.args.stay_open);
+        } else if response.clicked() {
+            self.navigation.selected = Some(index);
+            self.open_preview(ctx, index);
+        }
         
-        for (index, path) in paths_to_load {
-            let sender = sender.clone();
-            let cache_dir = cache_dir.clone();
-            
-            self.thread_pool.spawn(move || {
-                if let Some(color_image) = Self::load_or_generate_thumbnail(&path, size, &cache_dir, debug) {
-                    let _ = sender.send((index, color_image));
-                }
-            });
+        self.highlight_tile(ui, &response, index, &path);
+        if response.hovered() {
+            self.navigation.hovered = Some(index);
+        }
+        response.on_hover_text(hover_text);
+    }
+    
+    /// How wallpaper commands are run, from the command line options
+    pub fn runner(&self) -> Runner {
+        Runner {
+            timeout: (self.args.timeout > 0).then(|| std::time::Duration::from_secs(self.args.timeout)),
+            env: self.args.env.clone(),
+            working_dir: self.args.working_dir.clone(),
         }
     }
     
+    /// The custom command if one was given, else the chosen or detected backend
+    pub fn wallpaper_setter(&self) -> Box<dyn WallpaperSetter> {
+        if let Some(template) = &self.args.command {
+            return Box::new(wallpaper::CustomCommand { template: template.clone(), runner: self.runner() });
+        }
+        self.args.backend
+            .unwrap_or_else(|| wallpaper::detect_backend(&wallpaper::SessionInfo::from_env()))
+            .setter(self.runner())
+    }
+    
     pub fn set_background(&self, path: &Path) -> Result<()> {
-        let command_parts: Vec<&str> = self.args.command.split_whitespace().collect();
-        if command_parts.is_empty() {
-            return Err(BackgroundPickerError::CommandExecution("Empty command".to_owned()));
+        self.set_selection(&Selection::single(path))
+    }
+    
+    /// Set every monitor's wallpaper at once
+    pub fn set_selection(&self, selection: &Selection) -> Result<()> {
+        let setter = self.wallpaper_setter();
+        set_targets(setter.as_ref(), &self.targets_for(selection, setter.as_ref())?, self.args.debug)
+    }
+    
+    /// Backend calls that put `selection` on the desktop. A single image for
+    /// every monitor is one call; otherwise monitors without an image of their
+    /// own get the one for all monitors, and if none are known the named ones are used.
+    /// Backends that go by position refuse to leave a monitor out, since the
+    /// images after it would shift onto the wrong screens.
+    fn targets_for(&self, selection: &Selection, setter: &dyn WallpaperSetter) -> Result<Vec<CommandTarget>> {
+        if selection.monitors.is_empty() {
+            return Ok(selection.all.iter()
+                .map(|path| CommandTarget {
+                    path: path.clone(),
+                    monitor: None,
+                    size: self.screen_size,
+                    fit: self.state.fit,
+                    previous: None,
+                })
+                .collect());
         }
         
-        let mut cmd = Command::new(command_parts[0]);
-        cmd.args(&command_parts[1..]);
-        cmd.arg(path);
-        
-        let output = cmd.output()
-            .map_err(|e| BackgroundPickerError::CommandExecution(e.to_string()))?;
-        
-        if !output.status.success() {
-            let error_msg = String::from_utf8_lossy(&output.stderr);
-            return Err(BackgroundPickerError::CommandExecution(error_msg.into_owned()));
+        if self.monitors.available.is_empty() {
+            return Ok(selection.monitors.iter()
+                .map(|(monitor, path)| CommandTarget {
+                    path: path.clone(),
+                    monitor: Some(monitor.clone()),
+                    size: self.screen_size,
+                    fit: self.state.fit,
+                    previous: None,
+                })
+                .collect());
         }
         
-        Ok(())
+        let mut targets = Vec::new();
+        for monitor in &self.monitors.available {
+            match selection.for_monitor(&monitor.name) {
+                Some(path) => targets.push(CommandTarget {
+                    path: path.to_path_buf(),
+                    monitor: Some(monitor.name.clone()),
+                    size: Some(monitor.size),
+                    fit: self.state.fit,
+                    previous: None,
+                }),
+                None if setter.assigns_by_position() => {
+                    return Err(BackgroundPickerError::IncompleteSelection(monitor.name.clone()));
+                }
+                None => {}
+            }
+        }
+        Ok(targets)
     }
     
     pub fn save_selected_image(&self, path: &Path) -> Result<()> {
+        self.save_selection(&Selection::single(path))
+    }
+    
+    pub fn save_selection(&self, selection: &Selection) -> Result<()> {
         if let Some(parent) = self.args.selected_image_file.parent() {
             fs::create_dir_all(parent)
                 .map_err(BackgroundPickerError::SaveSelectedImage)?;
         }
         
-        let path_str = path.to_string_lossy();
-        fs::write(&self.args.selected_image_file, path_str.as_bytes())
+        let file = SelectionFile {
+            selection: selection.clone(),
+            fit: Some(self.state.fit),
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .ok(),
+        };
+        write_atomically(&self.args.selected_image_file, file.to_file_contents()?.as_bytes())
             .map_err(BackgroundPickerError::SaveSelectedImage)?;
+        self.prune_span_cache(selection);
         
         Ok(())
     }
+    
+    /// Delete cut slices that the saved `selection` no longer uses, so spanning
+    /// doesn't fill the cache. Undoing to a spanned wallpaper cuts them again.
+    fn prune_span_cache(&self, selection: &Selection) {
+        let Ok(entries) = fs::read_dir(self.span_cache_dir()) else {
+            return;
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if !selection.monitors.values().any(|kept| kept == &path) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
 }
 
 impl eframe::App for BackgroundPickerApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         self.process_thumbnail_results(ctx);
+        self.poll_pending_command(ctx);
+        self.thumbnail_queue.begin_frame();
+        self.handle_search_shortcuts(ctx);
+        self.handle_zoom_input(ctx);
+        
+        if ctx.input(|i| i.viewport().close_requested()) {
+            self.handle_close_request(ctx);
+        }
+        
+        self.show_status_bar(ctx);
         
         egui::CentralPanel::default().show(ctx, |ui| {
             if self.loading {
@@ -725,102 +3753,77 @@ impl eframe::App for BackgroundPickerApp {
                 return;
             }
             
-            ui.heading("Background Picker");
+            ui.horizontal(|ui| {
+                ui.heading("Background Picker");
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    ui.toggle_value(&mut self.recent.open, "Recent");
+                    if ui.button("Collapse all").clicked() {
+                        self.set_all_folders_open(ctx, false);
+                    }
+                    if ui.button("Expand all").clicked() {
+                        self.set_all_folders_open(ctx, true);
+                    }
+                });
+            });
+            ui.label(
+                egui::RichText::new("Arrows/hjkl: move   Enter: apply   Space: preview   T: try   Tab: next folder   1-9: favorite (Ctrl+1-9 to assign)   Esc: quit")
+                    .small()
+                    .weak(),
+            );
+            if let Some(try_on) = &self.try_on {
+                let name = try_on.current.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                ui.colored_label(
+                    ui.visuals().warn_fg_color,
+                    format!("Trying {} - Apply to keep it, Esc to restore the previous wallpaper", name),
+                );
+            }
+            let screen_size = Self::screen_size(ctx);
+            self.screen_size = screen_size;
+            let mut visible = self.visibility_mask(screen_size);
+            self.handle_keyboard(ctx, &visible);
+            let narrowing = (self.search.query.clone(), self.filter.clone());
+            self.show_search_bar(ui);
+            self.show_filter_bar(ui, screen_size);
+            self.show_sort_selector(ui);
             ui.separator();
+            // Only an edit in the bars above makes the mask stale
+            if narrowing.0 != self.search.query || narrowing.1 != self.filter {
+                visible = self.visibility_mask(screen_size);
+            }
             
             egui::ScrollArea::vertical().show(ui, |ui| {
-                // Clone folder data to avoid borrowing issues
-                let folders: Vec<(String, Vec<usize>)> = self.folder_tree.iter()
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .collect();
+                self.navigation.sections.clear();
+                self.navigation.hovered = None;
                 
-                for (folder, image_indices) in folders {
-                    let folder_label = if folder == "." { 
-                        format!("Root ({} images)", image_indices.len())
-                    } else { 
-                        format!("{} ({} images)", folder, image_indices.len())
+                if self.search.is_active() && !self.search.group_by_folder {
+                    self.show_search_results(ui, ctx, &visible);
+                    return;
+                }
+                
+                // Move the tree out while rendering so folders can borrow it
+                let mut tree = std::mem::take(&mut self.folder_tree);
+                if !tree.images.is_empty() {
+                    let root = FolderNode {
+                        name: "Root".to_owned(),
+                        path: ".".to_owned(),
+                        images: std::mem::take(&mut tree.images),
+                        children: Vec::new(),
                     };
-                    
-                    let header_response = egui::CollapsingHeader::new(folder_label)
-                        .default_open(false)
-                        .show(ui, |ui| {
-                            ui.horizontal_wrapped(|ui| {
-                                for index in &image_indices {
-                                    self.load_thumbnail(ctx, *index);
-                                    
-                                    let image_info = {
-                                        match self.images.read() {
-                                            Ok(images) => {
-                                                if *index >= images.len() {
-                                                    continue;
-                                                }
-                                                // Clone the data we need
-                                                (
-                                                    images[*index].loading,
-                                                    images[*index].path.clone(),
-                                                    images[*index].relative_path.clone(),
-                                                    images[*index].thumbnail.clone()
-                                                )
-                                            }
-                                            Err(_) => continue,
-                                        }
-                                    };
-                                    
-                                    let (is_loading, path, relative_path, texture_ref) = image_info;
-                                    
-                                    if let Some(texture) = texture_ref {
-                                        let image_button = egui::ImageButton::new(&texture)
-                                            .frame(true);
-                                        
-                                        let button_response = ui.add(image_button);
-                                        if button_response.clicked() {
-                                            if let Err(e) = self.set_background(&path) {
-                                                eprintln!("Failed to set background: {}", e);
-                                            } else {
-                                                let _ = self.save_selected_image(&path);
-                                                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
-                                            }
-                                        }
-                                        
-                                        button_response.on_hover_text(&relative_path);
-                                    } else {
-                                        // Show placeholder for loading images
-                                        let size = self.args.thumbnail_size as f32;
-                                        let (rect, response) = ui.allocate_exact_size(
-                                            egui::Vec2::splat(size),
-                                            egui::Sense::hover()
-                                        );
-                                        ui.painter().rect_filled(
-                                            rect,
-                                            egui::Rounding::same(5.0),
-                                            egui::Color32::LIGHT_GRAY
-                                        );
-                                        
-                                        let loading_text = if is_loading { "Loading..." } else { "Click to load" };
-                                        ui.painter().text(
-                                            rect.center(),
-                                            egui::Align2::CENTER_CENTER,
-                                            loading_text,
-                                            egui::FontId::default(),
-                                            egui::Color32::DARK_GRAY
-                                        );
-                                        response.on_hover_text(&relative_path);
-                                    }
-                                }
-                            });
-                        });
-                    
-                    // If folder was just opened, preload some thumbnails
-                    if let Some(body_response) = header_response.body_response {
-                        if body_response.rect.height() > 0.0 {
-                            self.preload_batch(&image_indices);
-                        }
-                    }
+                    self.show_folder(ui, ctx, &root, &visible);
+                    tree.images = root.images;
                 }
+                
+                for child in &tree.children {
+                    self.show_folder(ui, ctx, child, &visible);
+                }
+                self.folder_tree = tree;
             });
         });
         
-        ctx.request_repaint(); // Keep updating to process thumbnail results
+        self.show_preview(ctx);
+        self.show_recent(ctx);
+        self.show_command_error(ctx);
+        self.cancel_stale_thumbnails();
     }
     
 }
@@ -832,10 +3835,317 @@ pub fn is_image_file(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
+fn set_targets(setter: &dyn WallpaperSetter, targets: &[CommandTarget], debug: bool) -> Result<()> {
+    if targets.is_empty() {
+        return Ok(());
+    }
+    if debug {
+        for target in targets {
+            match &target.monitor {
+                Some(monitor) => println!("Setting background with {} on {}: {}", setter.name(), monitor, target.path.display()),
+                None => println!("Setting background with {}: {}", setter.name(), target.path.display()),
+            }
+        }
+    }
+    setter.set_each(targets)
+}
+
+/// Write through a temporary file next to `path` and rename it into place, so
+/// a crash or a concurrent reader never sees a half-written file. A symlink is
+/// replaced at its target, and an existing file keeps its permissions.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file name", path.display())))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            if let Ok(metadata) = fs::metadata(&path) {
+                file.set_permissions(metadata.permissions())?;
+            }
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp_path, &path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// `KEY=VALUE` from `--env`
+pub fn parse_env_var(text: &str) -> std::result::Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", text)),
+    }
+}
+
+/// `[POLICY:]COMMAND` from `--hook`; without a known policy prefix the hook warns
+pub fn parse_hook(text: &str) -> std::result::Result<Hook, String> {
+    let (policy, command) = match text.split_once(':') {
+        Some(("ignore", command)) => (HookPolicy::Ignore, command),
+        Some(("warn", command)) => (HookPolicy::Warn, command),
+        Some(("stop", command)) => (HookPolicy::Stop, command),
+        _ => (HookPolicy::Warn, text),
+    };
+    validate_command(command).map_err(|e| e.to_string())?;
+    Ok(Hook { command: command.trim().to_owned(), policy })
+}
+
+/// Run the post-apply hooks in order. A failing `stop` hook skips the rest;
+/// otherwise the first `warn` failure is returned once all hooks have run.
+pub fn run_hooks(hooks: &[Hook], target: &CommandTarget, runner: &Runner, debug: bool) -> Result<()> {
+    let mut reported = Ok(());
+    for hook in hooks {
+        let result = expand_command(&hook.command, target).and_then(|args| {
+            if debug {
+                println!("Running hook: {}", wallpaper::command_line(&args));
+            }
+            runner.run_with_stderr(&args)
+        });
+        match result {
+            Ok((stdout, stderr)) => {
+                if debug {
+                    for line in stdout.lines() {
+                        println!("  stdout: {}", line);
+                    }
+                    for line in stderr.lines() {
+                        println!("  stderr: {}", line);
+                    }
+                }
+            }
+            Err(e) => {
+                if debug {
+                    match &e {
+                        BackgroundPickerError::CommandFailed(failure) => println!("Hook failed:\n{}", failure.details()),
+                        e => println!("Hook failed: {}", e),
+                    }
+                }
+                match hook.policy {
+                    HookPolicy::Ignore => {}
+                    HookPolicy::Warn => {
+                        if reported.is_ok() {
+                            reported = Err(e);
+                        }
+                    }
+                    HookPolicy::Stop => return Err(e),
+                }
+            }
+        }
+    }
+    reported
+}
+
 pub fn validate_command(command: &str) -> Result<()> {
     // Check if command has any non-whitespace characters without allocating
     if command.trim().is_empty() {
         return Err(BackgroundPickerError::CommandExecution("Empty command".to_owned()));
     }
+    shell_words::split(command)
+        .map_err(|e| BackgroundPickerError::CommandExecution(format!("Cannot parse command: {}", e)))?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Placeholders understood in `--command` and `--hook`; `{previous}` is empty
+/// unless a wallpaper was set before
+pub const COMMAND_PLACEHOLDERS: &[&str] = &["{path}", "{uri}", "{dir}", "{monitor}", "{width}", "{height}", "{fit}", "{previous}"];
+
+/// What a wallpaper command is being run for; fills in the command's placeholders.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandTarget {
+    pub path: PathBuf,
+    pub monitor: Option<String>,
+    /// Resolution of the monitor
+    pub size: Option<(u32, u32)>,
+    pub fit: FitMode,
+    /// Wallpaper that was replaced, for hooks
+    pub previous: Option<PathBuf>,
+}
+
+/// Turn a command template into program and arguments. The template is split
+/// like a shell would (quotes and escapes, no expansion), then placeholders are
+/// filled in per word, so substituted values never split into more arguments.
+/// If the template never mentions the image (`{path}`, `{uri}` or `{dir}`), the
+/// path is appended, as older versions did.
+pub fn build_command(template: &str, target: &CommandTarget) -> Result<Vec<std::ffi::OsString>> {
+    let mut args = expand_command(template, target)?;
+    if !["{path}", "{uri}", "{dir}"].iter().any(|p| template.contains(p)) {
+        args.push(target.path.clone().into_os_string());
+    }
+    Ok(args)
+}
+
+/// Like `build_command`, but never appends the path; used for hooks
+pub fn expand_command(template: &str, target: &CommandTarget) -> Result<Vec<std::ffi::OsString>> {
+    let words = shell_words::split(template)
+        .map_err(|e| BackgroundPickerError::CommandExecution(format!("Cannot parse command: {}", e)))?;
+    if words.is_empty() {
+        return Err(BackgroundPickerError::CommandExecution("Empty command".to_owned()));
+    }
+    
+    let mut args = Vec::with_capacity(words.len() + 1);
+    for word in &words {
+        // A bare {path} or {previous} is passed through untouched, even if it isn't valid UTF-8
+        match word.as_str() {
+            "{path}" => args.push(target.path.clone().into_os_string()),
+            "{previous}" => args.push(target.previous.clone().unwrap_or_default().into_os_string()),
+            _ => args.push(expand_placeholders(word, target)?.into()),
+        }
+    }
+    Ok(args)
+}
+
+/// Substitute each `{name}` in `word` in one pass over it, so placeholder text
+/// inside a substituted value (a file named `{monitor}.png`) is left alone
+fn expand_placeholders(word: &str, target: &CommandTarget) -> Result<String> {
+    let missing = |placeholder: &str| {
+        BackgroundPickerError::CommandExecution(format!("{} is not known for this command", placeholder))
+    };
+    
+    let mut expanded = String::with_capacity(word.len());
+    let mut rest = word;
+    while let Some(open) = rest.find('{') {
+        expanded.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let Some(close) = rest.find('}') else {
+            break;
+        };
+        let placeholder = &rest[..=close];
+        match placeholder {
+            "{path}" => expanded.push_str(&target.path.to_string_lossy()),
+            "{uri}" => expanded.push_str(&file_uri(&target.path)),
+            "{dir}" => expanded.push_str(&target.path.parent().unwrap_or(Path::new("/")).to_string_lossy()),
+            "{monitor}" => expanded.push_str(target.monitor.as_deref().ok_or_else(|| missing("{monitor}"))?),
+            "{width}" | "{height}" => {
+                let (width, height) = target.size.ok_or_else(|| missing("{width}/{height}"))?;
+                let value = if placeholder == "{width}" { width } else { height };
+                expanded.push_str(&value.to_string());
+            }
+            "{fit}" => expanded.push_str(target.fit.name()),
+            "{previous}" => {
+                expanded.push_str(&target.previous.as_deref().map(Path::to_string_lossy).unwrap_or_default());
+            }
+            _ => {
+                // Not a placeholder; keep the brace and look again just after it
+                expanded.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// `file://` URI for a path, percent-encoding everything but unreserved characters
+pub fn file_uri(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    
+    let mut uri = String::from("file://");
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+/// Human-readable file size, e.g. "1.5 MB"
+pub fn format_file_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// `--history` listing, newest first and numbered for `--history N`; undone entries are marked
+pub fn format_history(history: &History) -> String {
+    if history.entries.is_empty() {
+        return "No wallpapers applied yet\n".to_owned();
+    }
+    
+    let position = history.position();
+    let mut listing = String::new();
+    for (number, (index, entry)) in history.entries.iter().enumerate().rev().enumerate() {
+        listing.push_str(&format!(
+            "{:>4}  {:<16}  {:<14}  {}{}\n",
+            number + 1,
+            format_age(entry.age()),
+            entry.target_label(),
+            entry.path.display(),
+            if index >= position { "  (undone)" } else { "" },
+        ));
+    }
+    listing
+}
+
+/// Rough age of a file, e.g. "3 days ago"
+pub fn format_age(age: std::time::Duration) -> String {
+    let seconds = age.as_secs();
+    let (amount, unit) = match seconds {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        86400..=2_591_999 => (seconds / 86400, "day"),
+        2_592_000..=31_535_999 => (seconds / 2_592_000, "month"),
+        _ => (seconds / 31_536_000, "year"),
+    };
+    format!("{} {}{} ago", amount, unit, if amount == 1 { "" } else { "s" })
+}
+
+/// Compare strings the way people expect file names to sort: case-insensitively,
+/// with runs of digits compared by numeric value (`img2` before `img10`).
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            // Fall back to a plain comparison so the order is total
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(c);
+                    }
+                    digits
+                };
+                let x_digits = take_number(&mut a_chars);
+                let y_digits = take_number(&mut b_chars);
+                let x_value = x_digits.trim_start_matches('0');
+                let y_value = y_digits.trim_start_matches('0');
+                let ordering = x_value.len().cmp(&y_value.len()).then_with(|| x_value.cmp(y_value));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
@@ -7,6 +7,11 @@ const DEFAULT_WINDOW_HEIGHT: f32 = 600.0;
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     
+    if args.runs_without_gui() {
+        BackgroundPickerApp::from_args(args)?.run_without_gui()?;
+        return Ok(());
+    }
+    
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([DEFAULT_WINDOW_WIDTH, DEFAULT_WINDOW_HEIGHT])
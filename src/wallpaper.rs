@@ -0,0 +1,988 @@
+//! Built-in ways of setting the wallpaper on common desktops and compositors.
+
+use crate::{build_command, file_uri, BackgroundPickerError, CommandFailure, CommandTarget, Result};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Something that can put an image on the desktop.
+pub trait WallpaperSetter: Send {
+    /// Short name shown in the UI and debug output
+    fn name(&self) -> &str;
+    
+    fn set(&self, target: &CommandTarget) -> Result<()>;
+    
+    /// Give each monitor its own image. Backends that need all outputs in a
+    /// single call override this; the rest set one monitor at a time.
+    fn set_each(&self, targets: &[CommandTarget]) -> Result<()> {
+        targets.iter().try_for_each(|target| self.set(target))
+    }
+    
+    /// Whether `CommandTarget::monitor` is honoured
+    fn per_monitor(&self) -> bool {
+        true
+    }
+    
+    /// Whether `set_each` hands images to the monitors in order instead of by
+    /// name, so every monitor needs a target
+    fn assigns_by_position(&self) -> bool {
+        false
+    }
+    
+    /// The backend's own list of outputs, when it has one
+    fn monitors(&self) -> Option<Vec<Monitor>> {
+        None
+    }
+}
+
+/// A connected, enabled output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monitor {
+    /// Output name such as `HDMI-1`, as the wallpaper tools expect it
+    pub name: String,
+    /// Resolution in pixels
+    pub size: (u32, u32),
+    /// Top-left corner in the combined desktop
+    pub position: (i32, i32),
+}
+
+/// List the monitors, asking the backend first, then `wlr-randr` on Wayland,
+/// then `xrandr`. Sorted left to right, except for backends that assign images
+/// by position: those get them in Xinerama screen order, as `parse_xrandr`
+/// lists them, so each image lands on the monitor it was meant for.
+pub fn detect_monitors(setter: &dyn WallpaperSetter, session: &SessionInfo, runner: &Runner) -> Vec<Monitor> {
+    let query = |command: &[&str], parse: fn(&str) -> Vec<Monitor>| {
+        runner.run(&args(command)).map(|output| parse(&output)).unwrap_or_default()
+    };
+    
+    let mut monitors = setter.monitors().unwrap_or_default();
+    if monitors.is_empty() && session.wayland {
+        monitors = query(&["wlr-randr"], parse_wlr_randr);
+    }
+    if monitors.is_empty() {
+        monitors = query(&["xrandr", "--query"], parse_xrandr);
+    }
+    if !setter.assigns_by_position() {
+        monitors.sort_by_key(|monitor| monitor.position);
+    }
+    monitors
+}
+
+/// The part of a spanned image shown on one monitor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanSlice {
+    pub monitor: String,
+    /// Region of the source image as x, y, width, height
+    pub source: (u32, u32, u32, u32),
+    /// Size the region is scaled to: the monitor's resolution
+    pub size: (u32, u32),
+}
+
+/// Split an image across the monitor layout. The monitors are placed as
+/// arranged, with `bezel` pixels of hidden image between neighbouring columns
+/// and rows, and the image is scaled to cover the whole area, cropping the
+/// overflow evenly, so each monitor shows its part at the same scale.
+pub fn span_slices(image_size: (u32, u32), monitors: &[Monitor], bezel: u32) -> Vec<SpanSlice> {
+    let (image_width, image_height) = image_size;
+    if monitors.is_empty() || image_width == 0 || image_height == 0 {
+        return Vec::new();
+    }
+    
+    let mut columns: Vec<i32> = monitors.iter().map(|monitor| monitor.position.0).collect();
+    let mut rows: Vec<i32> = monitors.iter().map(|monitor| monitor.position.1).collect();
+    columns.sort_unstable();
+    columns.dedup();
+    rows.sort_unstable();
+    rows.dedup();
+    
+    // Top-left corner of each monitor in the layout, bezels included
+    let placed: Vec<(f64, f64)> = monitors.iter()
+        .map(|monitor| {
+            let column = columns.binary_search(&monitor.position.0).unwrap_or(0);
+            let row = rows.binary_search(&monitor.position.1).unwrap_or(0);
+            (
+                (monitor.position.0 - columns[0]) as f64 + (column as u32 * bezel) as f64,
+                (monitor.position.1 - rows[0]) as f64 + (row as u32 * bezel) as f64,
+            )
+        })
+        .collect();
+    let layout_width = monitors.iter().zip(&placed).map(|(monitor, (x, _))| x + monitor.size.0 as f64).fold(0.0, f64::max);
+    let layout_height = monitors.iter().zip(&placed).map(|(monitor, (_, y))| y + monitor.size.1 as f64).fold(0.0, f64::max);
+    
+    let scale = (layout_width / image_width as f64).max(layout_height / image_height as f64);
+    let crop_x = (image_width as f64 * scale - layout_width) / 2.0;
+    let crop_y = (image_height as f64 * scale - layout_height) / 2.0;
+    
+    monitors.iter()
+        .zip(&placed)
+        .map(|(monitor, &(x, y))| {
+            let left = (((x + crop_x) / scale).round() as u32).min(image_width - 1);
+            let top = (((y + crop_y) / scale).round() as u32).min(image_height - 1);
+            let width = ((monitor.size.0 as f64 / scale).round() as u32).clamp(1, image_width - left);
+            let height = ((monitor.size.1 as f64 / scale).round() as u32).clamp(1, image_height - top);
+            SpanSlice {
+                monitor: monitor.name.clone(),
+                source: (left, top, width, height),
+                size: monitor.size,
+            }
+        })
+        .collect()
+}
+
+/// Cut `path` into one image per monitor (see `span_slices`) and return the
+/// file for each monitor. Slices are cached in `cache_dir` under a name derived
+/// from the source, its modification time and the slice geometry, so the same
+/// image on the same layout is only cut once.
+pub fn render_span(path: &Path, monitors: &[Monitor], bezel: u32, cache_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let plan = SpanPlan::new(path, monitors, bezel, cache_dir)?;
+    plan.render()?;
+    Ok(plan.files())
+}
+
+/// The slice files for a spanned image, worked out from its header alone so
+/// the slow cutting in `render` can happen elsewhere.
+#[derive(Debug, Clone)]
+pub struct SpanPlan {
+    path: PathBuf,
+    cache_dir: PathBuf,
+    slices: Vec<(SpanSlice, PathBuf)>,
+}
+
+impl SpanPlan {
+    pub fn new(path: &Path, monitors: &[Monitor], bezel: u32, cache_dir: &Path) -> Result<Self> {
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| span_error(path, e.into()))?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        let image_size = image::image_dimensions(path).map_err(|e| span_error(path, e.into()))?;
+        
+        let slices = span_slices(image_size, monitors, bezel).into_iter()
+            .map(|slice| {
+                let file = cache_dir.join(format!("{}.png", slice_cache_key(path, modified, &slice)));
+                (slice, file)
+            })
+            .collect();
+        Ok(Self { path: path.to_path_buf(), cache_dir: cache_dir.to_path_buf(), slices })
+    }
+    
+    /// The file for each monitor, once rendered
+    pub fn files(&self) -> Vec<(String, PathBuf)> {
+        self.slices.iter().map(|(slice, file)| (slice.monitor.clone(), file.clone())).collect()
+    }
+    
+    /// Cut the slices that aren't cached yet, decoding the source at most once
+    pub fn render(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.cache_dir).map_err(|e| span_error(&self.path, e.into()))?;
+        
+        let mut source_image = None;
+        for (slice, file) in &self.slices {
+            if file.exists() {
+                continue;
+            }
+            if source_image.is_none() {
+                source_image = Some(image::open(&self.path).map_err(|e| span_error(&self.path, e.into()))?);
+            }
+            if let Some(image) = &source_image {
+                let (x, y, width, height) = slice.source;
+                image.crop_imm(x, y, width, height)
+                    .resize_exact(slice.size.0, slice.size.1, image::imageops::FilterType::Lanczos3)
+                    .save(file)
+                    .map_err(|e| span_error(&self.path, e.into()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn span_error(path: &Path, source: Box<dyn std::error::Error + Send + Sync>) -> BackgroundPickerError {
+    BackgroundPickerError::SpanSlicing { path: path.to_path_buf(), source }
+}
+
+fn slice_cache_key(path: &Path, modified: u64, slice: &SpanSlice) -> String {
+    use sha1::{Digest, Sha1};
+    
+    let (x, y, width, height) = slice.source;
+    let mut hasher = Sha1::new();
+    hasher.update(file_uri(path).as_bytes());
+    hasher.update(format!("|{}|{},{},{},{}|{}x{}", modified, x, y, width, height, slice.size.0, slice.size.1).as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// `WIDTHxHEIGHT`
+fn parse_size(text: &str) -> Option<(u32, u32)> {
+    let (width, height) = text.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Outputs from `xrandr --query`, in Xinerama screen order: the primary output
+/// first, then the rest as xrandr lists them. Connected but disabled outputs
+/// have no geometry and are left out.
+pub fn parse_xrandr(output: &str) -> Vec<Monitor> {
+    let mut monitors: Vec<(Monitor, bool)> = output.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let name = words.next()?;
+            if words.next()? != "connected" {
+                return None;
+            }
+            let rest: Vec<&str> = words.collect();
+            // The geometry looks like 1920x1080+1920+0
+            let geometry = rest.iter().find(|word| word.contains('x') && word.contains('+'))?;
+            let mut parts = geometry.split('+');
+            let size = parse_size(parts.next()?)?;
+            let position = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+            Some((Monitor { name: name.to_owned(), size, position }, rest.first() == Some(&"primary")))
+        })
+        .collect();
+    monitors.sort_by_key(|(_, primary)| !primary);
+    monitors.into_iter().map(|(monitor, _)| monitor).collect()
+}
+
+/// Outputs from `wlr-randr`: a header line per output, followed by indented
+/// details including the current mode and the position.
+pub fn parse_wlr_randr(output: &str) -> Vec<Monitor> {
+    let mut monitors = Vec::new();
+    let mut current: Option<(Monitor, bool)> = None;
+    for line in output.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            monitors.extend(current.take().filter(|(_, enabled)| *enabled).map(|(monitor, _)| monitor));
+            if let Some(name) = line.split_whitespace().next() {
+                let monitor = Monitor { name: name.to_owned(), size: (0, 0), position: (0, 0) };
+                current = Some((monitor, true));
+            }
+            continue;
+        }
+        
+        let Some((monitor, enabled)) = current.as_mut() else {
+            continue;
+        };
+        let line = line.trim();
+        if let Some(value) = line.strip_prefix("Enabled:") {
+            *enabled = value.trim() == "yes";
+        } else if let Some(value) = line.strip_prefix("Position:") {
+            if let Some((x, y)) = value.trim().split_once(',') {
+                monitor.position = (x.parse().unwrap_or(0), y.parse().unwrap_or(0));
+            }
+        } else if line.contains("current") {
+            if let Some(size) = line.split_whitespace().next().and_then(parse_size) {
+                monitor.size = size;
+            }
+        }
+    }
+    monitors.extend(current.filter(|(_, enabled)| *enabled).map(|(monitor, _)| monitor));
+    monitors
+}
+
+/// Outputs from `hyprctl monitors`, where each `Monitor NAME (ID n):` header is
+/// followed by a `WIDTHxHEIGHT@RATE at XxY` line.
+pub fn parse_hyprctl_monitors(output: &str) -> Vec<Monitor> {
+    let mut monitors = Vec::new();
+    let mut lines = output.lines();
+    while let Some(line) = lines.next() {
+        let Some(name) = line.strip_prefix("Monitor ").and_then(|rest| rest.split_whitespace().next()) else {
+            continue;
+        };
+        let Some((mode, position)) = lines.next().and_then(|line| line.trim().split_once(" at ")) else {
+            continue;
+        };
+        let size = mode.split('@').next().and_then(parse_size);
+        let position = position.split_once('x').and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)));
+        if let (Some(size), Some(position)) = (size, position) {
+            monitors.push(Monitor { name: name.to_owned(), size, position });
+        }
+    }
+    monitors
+}
+
+/// How the image is scaled to the screen. Each backend maps it to its own option.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FitMode {
+    /// Cover the screen, cropping the overflow
+    Fill,
+    /// Show the whole image, with bars where the shape differs
+    #[default]
+    Fit,
+    /// Original size in the middle of the screen
+    Center,
+    /// Repeat the image at its original size
+    Tile,
+    /// Scale to the screen, ignoring the aspect ratio
+    Stretch,
+}
+
+impl FitMode {
+    pub const ALL: [FitMode; 5] = [FitMode::Fill, FitMode::Fit, FitMode::Center, FitMode::Tile, FitMode::Stretch];
+    
+    pub fn label(self) -> &'static str {
+        match self {
+            FitMode::Fill => "Fill",
+            FitMode::Fit => "Fit",
+            FitMode::Center => "Center",
+            FitMode::Tile => "Tile",
+            FitMode::Stretch => "Stretch",
+        }
+    }
+    
+    /// Value of the `{fit}` placeholder
+    pub fn name(self) -> &'static str {
+        match self {
+            FitMode::Fill => "fill",
+            FitMode::Fit => "fit",
+            FitMode::Center => "center",
+            FitMode::Tile => "tile",
+            FitMode::Stretch => "stretch",
+        }
+    }
+    
+    /// `picture-options` value used by GNOME, MATE and Cinnamon
+    fn picture_options(self) -> &'static str {
+        match self {
+            FitMode::Fill => "zoom",
+            FitMode::Fit => "scaled",
+            FitMode::Center => "centered",
+            FitMode::Tile => "wallpaper",
+            FitMode::Stretch => "stretched",
+        }
+    }
+}
+
+/// Built-in backends, selectable with `--backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    Feh,
+    Nitrogen,
+    Xwallpaper,
+    Swaybg,
+    Swww,
+    Hyprpaper,
+    Gnome,
+    Xfce,
+    Kde,
+    Mate,
+    Cinnamon,
+}
+
+impl Backend {
+    pub fn setter(self, runner: Runner) -> Box<dyn WallpaperSetter> {
+        match self {
+            Backend::Feh => Box::new(Feh { runner }),
+            Backend::Nitrogen => Box::new(Nitrogen { runner }),
+            Backend::Xwallpaper => Box::new(Xwallpaper { runner }),
+            Backend::Swaybg => Box::new(Swaybg { runner }),
+            Backend::Swww => Box::new(Swww { runner }),
+            Backend::Hyprpaper => Box::new(Hyprpaper { runner }),
+            Backend::Gnome => Box::new(Gnome { runner }),
+            Backend::Xfce => Box::new(Xfce { runner }),
+            Backend::Kde => Box::new(Kde { runner }),
+            Backend::Mate => Box::new(Mate { runner }),
+            Backend::Cinnamon => Box::new(Cinnamon { runner }),
+        }
+    }
+}
+
+/// The parts of the session environment that decide which backend to use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionInfo {
+    /// `XDG_CURRENT_DESKTOP`, a colon-separated list such as `ubuntu:GNOME`
+    pub current_desktop: Option<String>,
+    /// Whether `WAYLAND_DISPLAY` is set
+    pub wayland: bool,
+    /// `PATH`, used to see which tools are installed
+    pub path: Option<OsString>,
+}
+
+impl SessionInfo {
+    pub fn from_env() -> Self {
+        Self {
+            current_desktop: std::env::var("XDG_CURRENT_DESKTOP").ok(),
+            wayland: std::env::var_os("WAYLAND_DISPLAY").is_some_and(|display| !display.is_empty()),
+            path: std::env::var_os("PATH"),
+        }
+    }
+    
+    fn has_program(&self, name: &str) -> bool {
+        find_program(name, self.path.as_deref()).is_some()
+    }
+}
+
+/// Pick a backend for the session: the desktop's own mechanism when it has
+/// one, otherwise the first installed generic tool for Wayland or X11.
+pub fn detect_backend(session: &SessionInfo) -> Backend {
+    let desktops = session.current_desktop.as_deref().unwrap_or_default();
+    for desktop in desktops.split(':').map(str::to_ascii_lowercase) {
+        let backend = match desktop.as_str() {
+            "gnome" | "unity" | "pantheon" | "budgie" => Some(Backend::Gnome),
+            "kde" => Some(Backend::Kde),
+            "xfce" => Some(Backend::Xfce),
+            "mate" => Some(Backend::Mate),
+            "x-cinnamon" | "cinnamon" => Some(Backend::Cinnamon),
+            "hyprland" => Some(Backend::Hyprpaper),
+            "sway" => Some(Backend::Swaybg),
+            _ => None,
+        };
+        if let Some(backend) = backend {
+            return backend;
+        }
+    }
+    
+    let (candidates, fallback): (&[Backend], Backend) = if session.wayland {
+        (&[Backend::Swww, Backend::Swaybg], Backend::Swaybg)
+    } else {
+        (&[Backend::Feh, Backend::Xwallpaper, Backend::Nitrogen], Backend::Feh)
+    };
+    candidates.iter()
+        .copied()
+        .find(|backend| session.has_program(backend.program()))
+        .unwrap_or(fallback)
+}
+
+impl Backend {
+    /// Main program the backend runs, used to check it is installed
+    fn program(self) -> &'static str {
+        match self {
+            Backend::Feh => "feh",
+            Backend::Nitrogen => "nitrogen",
+            Backend::Xwallpaper => "xwallpaper",
+            Backend::Swaybg => "swaybg",
+            Backend::Swww => "swww",
+            Backend::Hyprpaper => "hyprctl",
+            Backend::Gnome | Backend::Mate | Backend::Cinnamon => "gsettings",
+            Backend::Xfce => "xfconf-query",
+            Backend::Kde => "qdbus",
+        }
+    }
+}
+
+/// Find an executable in a `PATH`-style list of directories
+pub fn find_program(name: &str, path: Option<&OsStr>) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    std::env::split_paths(path?)
+        .map(|dir| dir.join(name))
+        .find(|candidate| {
+            candidate.metadata()
+                .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+}
+
+/// How long a wallpaper command may run before it is killed, unless configured
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How wallpaper commands are started: time limit, extra environment and working directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Runner {
+    /// `None` waits for as long as the command takes
+    pub timeout: Option<Duration>,
+    pub env: Vec<(String, String)>,
+    pub working_dir: Option<PathBuf>,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_COMMAND_TIMEOUT),
+            env: Vec::new(),
+            working_dir: None,
+        }
+    }
+}
+
+impl Runner {
+    fn command(&self, args: &[OsString]) -> Result<Command> {
+        let Some((program, rest)) = args.split_first() else {
+            return Err(BackgroundPickerError::CommandExecution("Empty command".to_owned()));
+        };
+        
+        let mut command = Command::new(program);
+        command.args(rest)
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null());
+        if let Some(dir) = &self.working_dir {
+            command.current_dir(dir);
+        }
+        Ok(command)
+    }
+    
+    /// Run a command to completion, returning its standard output. A command
+    /// still running when the timeout expires is killed and reported as failed.
+    pub fn run(&self, args: &[OsString]) -> Result<String> {
+        self.run_with_stderr(args).map(|(stdout, _)| stdout)
+    }
+    
+    /// Like `run`, but also keep the standard error of a successful command
+    pub fn run_with_stderr(&self, args: &[OsString]) -> Result<(String, String)> {
+        let failure = |status: String, stderr: String| BackgroundPickerError::CommandFailed(CommandFailure {
+            command_line: command_line(args),
+            status,
+            stderr,
+        });
+        
+        let mut child = self.command(args)?
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| failure(format!("could not be started ({})", e), String::new()))?;
+        
+        // Drain both pipes while waiting, so a chatty command can't block on a full pipe
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        
+        let status = match self.timeout {
+            Some(timeout) => wait_with_timeout(&mut child, timeout),
+            None => child.wait().map(Some),
+        }
+        .map_err(|e| failure(format!("could not be waited for ({})", e), String::new()))?;
+        
+        let stdout = collect_output(stdout);
+        let stderr = collect_output(stderr);
+        match (status, self.timeout) {
+            (Some(status), _) if status.success() => Ok((stdout, stderr)),
+            (Some(status), _) => Err(failure(status.to_string(), stderr)),
+            (None, timeout) => Err(failure(
+                format!("timed out after {}s and was killed", timeout.unwrap_or_default().as_secs_f32()),
+                stderr,
+            )),
+        }
+    }
+    
+    /// Start a long-running program, such as a wallpaper daemon, without waiting
+    /// for it. A thread reaps it once it exits, so a replaced instance doesn't
+    /// linger as a zombie.
+    pub fn spawn_detached(&self, args: &[OsString]) -> Result<()> {
+        self.command(args)?
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map(|mut child| {
+                std::thread::spawn(move || child.wait());
+            })
+            .map_err(|e| BackgroundPickerError::CommandFailed(CommandFailure {
+                command_line: command_line(args),
+                status: format!("could not be started ({})", e),
+                stderr: String::new(),
+            }))
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    if let Some(mut pipe) = pipe {
+        std::thread::spawn(move || {
+            let mut bytes = Vec::new();
+            let _ = pipe.read_to_end(&mut bytes);
+            let _ = sender.send(String::from_utf8_lossy(&bytes).into_owned());
+        });
+    }
+    receiver
+}
+
+/// Output of a finished command. Anything it left running in the background
+/// may hold the pipe open, so only wait briefly for the end of it.
+fn collect_output(receiver: mpsc::Receiver<String>) -> String {
+    receiver.recv_timeout(Duration::from_millis(500)).unwrap_or_default()
+}
+
+/// Exit status, or `None` if the command ran out of time and was killed
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// A command line as it would be typed into a shell
+pub fn command_line(args: &[OsString]) -> String {
+    shell_words::join(args.iter().map(|arg| arg.to_string_lossy()))
+}
+
+fn args<I, S>(parts: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<OsString>,
+{
+    parts.into_iter().map(Into::into).collect()
+}
+
+/// A user-supplied `--command` template.
+pub struct CustomCommand {
+    pub template: String,
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for CustomCommand {
+    fn name(&self) -> &str {
+        "custom command"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        self.runner.run(&build_command(&self.template, target)?).map(|_| ())
+    }
+    
+    fn per_monitor(&self) -> bool {
+        self.template.contains("{monitor}")
+    }
+}
+
+pub struct Feh {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Feh {
+    fn name(&self) -> &str {
+        "feh"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        self.set_each(std::slice::from_ref(target))
+    }
+    
+    /// feh takes one image per screen, so the targets must be sorted by Xinerama
+    /// screen index, as `detect_monitors` lists the monitors for this backend
+    fn set_each(&self, targets: &[CommandTarget]) -> Result<()> {
+        let Some(first) = targets.first() else {
+            return Ok(());
+        };
+        let option = match first.fit {
+            FitMode::Fill => "--bg-fill",
+            FitMode::Fit => "--bg-max",
+            FitMode::Center => "--bg-center",
+            FitMode::Tile => "--bg-tile",
+            FitMode::Stretch => "--bg-scale",
+        };
+        let mut command = args(["feh", option]);
+        command.extend(targets.iter().map(|target| target.path.clone().into_os_string()));
+        self.runner.run(&command).map(|_| ())
+    }
+    
+    fn assigns_by_position(&self) -> bool {
+        true
+    }
+}
+
+pub struct Nitrogen {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Nitrogen {
+    fn name(&self) -> &str {
+        "nitrogen"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        let option = match target.fit {
+            FitMode::Fill => "--set-zoom-fill",
+            FitMode::Fit => "--set-zoom",
+            FitMode::Center => "--set-centered",
+            FitMode::Tile => "--set-tiled",
+            FitMode::Stretch => "--set-scaled",
+        };
+        self.runner.run(&args([OsString::from("nitrogen"), option.into(), "--save".into(), target.path.clone().into()])).map(|_| ())
+    }
+    
+    fn per_monitor(&self) -> bool {
+        false
+    }
+}
+
+pub struct Xwallpaper {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Xwallpaper {
+    fn name(&self) -> &str {
+        "xwallpaper"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        let mut command = args(["xwallpaper"]);
+        if let Some(monitor) = &target.monitor {
+            command.extend(args(["--output", monitor.as_str()]));
+        }
+        let option = match target.fit {
+            FitMode::Fill => "--zoom",
+            FitMode::Fit => "--maximize",
+            FitMode::Center => "--center",
+            FitMode::Tile => "--tile",
+            FitMode::Stretch => "--stretch",
+        };
+        command.extend(args([OsString::from(option), target.path.clone().into()]));
+        self.runner.run(&command).map(|_| ())
+    }
+}
+
+/// swaybg keeps running to draw the wallpaper, so the old instance is replaced
+pub struct Swaybg {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Swaybg {
+    fn name(&self) -> &str {
+        "swaybg"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        self.set_each(std::slice::from_ref(target))
+    }
+    
+    /// One swaybg draws every output, so all of them are passed to a single instance
+    fn set_each(&self, targets: &[CommandTarget]) -> Result<()> {
+        // Not running yet is fine
+        let _ = self.runner.run(&args(["pkill", "-x", "swaybg"]));
+        
+        let mut command = args(["swaybg"]);
+        for target in targets {
+            let output = target.monitor.clone().unwrap_or_else(|| "*".to_owned());
+            command.extend(args([
+                OsString::from("-o"),
+                output.into(),
+                "-i".into(),
+                target.path.clone().into(),
+                "-m".into(),
+                target.fit.name().into(),
+            ]));
+        }
+        self.runner.spawn_detached(&command)
+    }
+}
+
+pub struct Swww {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Swww {
+    fn name(&self) -> &str {
+        "swww"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        // swww has no tiling; centering without scaling is the closest
+        let resize = match target.fit {
+            FitMode::Fill => "crop",
+            FitMode::Fit => "fit",
+            FitMode::Center | FitMode::Tile => "no",
+            FitMode::Stretch => "stretch",
+        };
+        let mut command = args([OsString::from("swww"), "img".into(), "--resize".into(), resize.into(), target.path.clone().into()]);
+        if let Some(monitor) = &target.monitor {
+            command.extend(args(["--outputs", monitor.as_str()]));
+        }
+        self.runner.run(&command).map(|_| ())
+    }
+}
+
+/// hyprpaper is driven over IPC: the image is preloaded, then shown
+pub struct Hyprpaper {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Hyprpaper {
+    fn name(&self) -> &str {
+        "hyprpaper"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        let path = target.path.to_string_lossy();
+        self.runner.run(&args(["hyprctl", "hyprpaper", "preload", path.as_ref()]))?;
+        // hyprpaper covers the screen unless told otherwise, and can't center or stretch
+        let mode = match target.fit {
+            FitMode::Fill | FitMode::Stretch => "",
+            FitMode::Fit | FitMode::Center => "contain:",
+            FitMode::Tile => "tile:",
+        };
+        // An empty monitor name means every monitor
+        let assignment = format!("{},{}{}", target.monitor.as_deref().unwrap_or_default(), mode, path);
+        self.runner.run(&args(["hyprctl", "hyprpaper", "wallpaper", assignment.as_str()])).map(|_| ())
+    }
+    
+    fn monitors(&self) -> Option<Vec<Monitor>> {
+        self.runner.run(&args(["hyprctl", "monitors"])).ok().map(|output| parse_hyprctl_monitors(&output))
+    }
+}
+
+fn gsettings_set(runner: &Runner, schema: &str, key: &str, value: &str) -> Result<()> {
+    runner.run(&args(["gsettings", "set", schema, key, value])).map(|_| ())
+}
+
+/// GNOME keeps separate wallpapers for the light and dark styles; both are set
+pub struct Gnome {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Gnome {
+    fn name(&self) -> &str {
+        "GNOME"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        let uri = file_uri(&target.path);
+        gsettings_set(&self.runner, "org.gnome.desktop.background", "picture-options", target.fit.picture_options())?;
+        gsettings_set(&self.runner, "org.gnome.desktop.background", "picture-uri", &uri)?;
+        gsettings_set(&self.runner, "org.gnome.desktop.background", "picture-uri-dark", &uri)
+    }
+    
+    fn per_monitor(&self) -> bool {
+        false
+    }
+}
+
+pub struct Mate {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Mate {
+    fn name(&self) -> &str {
+        "MATE"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        gsettings_set(&self.runner, "org.mate.background", "picture-options", target.fit.picture_options())?;
+        gsettings_set(&self.runner, "org.mate.background", "picture-filename", &target.path.to_string_lossy())
+    }
+    
+    fn per_monitor(&self) -> bool {
+        false
+    }
+}
+
+pub struct Cinnamon {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Cinnamon {
+    fn name(&self) -> &str {
+        "Cinnamon"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        gsettings_set(&self.runner, "org.cinnamon.desktop.background", "picture-options", target.fit.picture_options())?;
+        gsettings_set(&self.runner, "org.cinnamon.desktop.background", "picture-uri", &file_uri(&target.path))
+    }
+    
+    fn per_monitor(&self) -> bool {
+        false
+    }
+}
+
+/// XFCE stores one image per monitor and workspace; every matching
+/// `last-image` property is updated
+pub struct Xfce {
+    pub runner: Runner,
+}
+
+impl WallpaperSetter for Xfce {
+    fn name(&self) -> &str {
+        "XFCE"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        let properties = self.runner.run(&args(["xfconf-query", "-c", "xfce4-desktop", "-l"]))?;
+        let monitor = target.monitor.as_ref().map(|monitor| format!("/monitor{}/", monitor));
+        let properties: Vec<&str> = properties.lines()
+            .map(str::trim)
+            .filter(|property| property.ends_with("/last-image"))
+            .filter(|property| monitor.as_ref().is_none_or(|monitor| property.contains(monitor.as_str())))
+            .collect();
+        
+        if properties.is_empty() {
+            return Err(BackgroundPickerError::CommandExecution(
+                "xfconf-query lists no desktop image properties to set".to_owned(),
+            ));
+        }
+        
+        // 0 is "none"; the rest follow the same order as the settings dialog
+        let style = match target.fit {
+            FitMode::Center => "1",
+            FitMode::Tile => "2",
+            FitMode::Stretch => "3",
+            FitMode::Fit => "4",
+            FitMode::Fill => "5",
+        };
+        let path = target.path.to_string_lossy();
+        for property in properties {
+            let style_property = format!("{}/image-style", property.trim_end_matches("/last-image"));
+            self.runner.run(&args(["xfconf-query", "-c", "xfce4-desktop", "-p", style_property.as_str(), "--create", "-t", "int", "-s", style]))?;
+            self.runner.run(&args(["xfconf-query", "-c", "xfce4-desktop", "-p", property, "-s", path.as_ref()]))?;
+        }
+        Ok(())
+    }
+}
+
+/// Plasma is scripted through the shell's D-Bus interface
+pub struct Kde {
+    pub runner: Runner,
+}
+
+impl Kde {
+    /// Plasma script that sets the image on every desktop
+    pub fn script(path: &Path, fit: FitMode) -> String {
+        let uri = file_uri(path).replace('\\', "\\\\").replace('"', "\\\"");
+        format!(
+            concat!(
+                "var allDesktops = desktops();",
+                "for (var i = 0; i < allDesktops.length; i++) {{",
+                "var d = allDesktops[i];",
+                "d.wallpaperPlugin = \"org.kde.image\";",
+                "d.currentConfigGroup = Array(\"Wallpaper\", \"org.kde.image\", \"General\");",
+                "d.writeConfig(\"FillMode\", {});",
+                "d.writeConfig(\"Image\", \"{}\");",
+                "}}",
+            ),
+            Self::fill_mode(fit),
+            uri
+        )
+    }
+    
+    /// Plasma's `FillMode`, the numeric value of a Qt `Image.fillMode`
+    fn fill_mode(fit: FitMode) -> u8 {
+        match fit {
+            FitMode::Stretch => 0,
+            FitMode::Fit => 1,
+            FitMode::Fill => 2,
+            FitMode::Tile => 3,
+            FitMode::Center => 6,
+        }
+    }
+}
+
+impl WallpaperSetter for Kde {
+    fn name(&self) -> &str {
+        "KDE Plasma"
+    }
+    
+    fn set(&self, target: &CommandTarget) -> Result<()> {
+        // Plasma 6 ships the tool as qdbus6
+        let session = SessionInfo::from_env();
+        let qdbus = ["qdbus6", "qdbus"].into_iter()
+            .find(|name| session.has_program(name))
+            .unwrap_or("qdbus");
+        
+        let script = Self::script(&target.path, target.fit);
+        self.runner.run(&args([
+            qdbus,
+            "org.kde.plasmashell",
+            "/PlasmaShell",
+            "org.kde.PlasmaShell.evaluateScript",
+            script.as_str(),
+        ])).map(|_| ())
+    }
+    
+    fn per_monitor(&self) -> bool {
+        false
+    }
+}
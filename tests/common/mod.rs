@@ -0,0 +1,36 @@
+use background_picker::{Args, BackgroundPickerApp, FolderNode, DEFAULT_THUMBNAIL_SIZE, ImageFilter, MonitorState, NavigationState, PickerState, PreviewState, RecentView, SearchState, ThumbnailQueue};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// A picker that is still loading, as `from_args` leaves it, with a single
+/// worker thread and thumbnails cached in `cache_dir`
+pub fn test_app(args: Args, cache_dir: PathBuf) -> BackgroundPickerApp {
+    let (thumbnail_sender, thumbnail_receiver) = std::sync::mpsc::channel();
+    BackgroundPickerApp {
+        thumbnail_size: args.thumbnail_size.unwrap_or(DEFAULT_THUMBNAIL_SIZE),
+        zoom_changed: None,
+        args,
+        images: Arc::new(RwLock::new(Vec::new())),
+        folder_tree: FolderNode::default(),
+        loading: true,
+        thumbnail_sender,
+        thumbnail_receiver,
+        thread_pool: rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
+        thumbnail_queue: ThumbnailQueue::default(),
+        cache_dir,
+        filter: ImageFilter::default(),
+        state: PickerState::default(),
+        search: SearchState::default(),
+        navigation: NavigationState::default(),
+        preview: PreviewState::default(),
+        try_on: None,
+        current_wallpaper: None,
+        status: None,
+        command_error: None,
+        screen_size: None,
+        monitors: MonitorState::default(),
+        pending: None,
+        closing: false,
+        recent: RecentView::default(),
+    }
+}
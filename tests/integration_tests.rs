@@ -1,9 +1,12 @@
-use background_picker::{Args, BackgroundPickerApp};
+use background_picker::{Args, BackgroundPickerApp, ThumbnailQuality};
 use clap::Parser;
 use std::fs;
 use tempfile::TempDir;
 use serial_test::serial;
 
+#[path = "common/mod.rs"]
+mod common;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,25 +74,9 @@ mod tests {
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
         args.directory = temp_dir.path().to_path_buf();
         args.debug = true;
-        args.thumbnail_size = 128;
-        
-        // Create a minimal app for testing
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(2)
-            .build()
-            .unwrap();
-        
-        let mut app = BackgroundPickerApp {
-            args: args.clone(),
-            images: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: true,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        args.thumbnail_size = Some(128);
+        
+        let mut app = common::test_app(args.clone(), temp_dir.path().join("cache"));
         
         let _ = app.scan_images();
         
@@ -99,31 +86,30 @@ mod tests {
         assert_eq!(images.len(), 13);
         
         // Verify folder structure
-        assert_eq!(app.folder_tree.len(), 6); // Root + 5 subdirectories
+        let with_images = app.folder_tree.nodes().iter().filter(|node| !node.images.is_empty()).count();
+        assert_eq!(with_images, 6); // Root + 5 subdirectories
         
         // Check root directory
-        if let Some(root_images) = app.folder_tree.get(".") {
-            assert_eq!(root_images.len(), 2); // desktop_bg.jpg, logo.png
-        }
+        assert_eq!(app.folder_tree.images.len(), 2); // desktop_bg.jpg, logo.png
         
         // Check Photos/Vacation
-        let vacation_images = app.folder_tree.get("Photos/Vacation").unwrap();
+        let vacation_images = &app.folder_tree.find("Photos/Vacation").unwrap().images;
         assert_eq!(vacation_images.len(), 3); // beach1.jpg, beach2.jpeg, sunset.png
         
         // Check Photos/Family
-        let family_images = app.folder_tree.get("Photos/Family").unwrap();
+        let family_images = &app.folder_tree.find("Photos/Family").unwrap().images;
         assert_eq!(family_images.len(), 2); // portrait.jpg, group.gif
         
         // Check Wallpapers/Nature
-        let nature_images = app.folder_tree.get("Wallpapers/Nature").unwrap();
+        let nature_images = &app.folder_tree.find("Wallpapers/Nature").unwrap().images;
         assert_eq!(nature_images.len(), 2); // forest.jpg, mountains.png
         
         // Check Wallpapers/Abstract
-        let abstract_images = app.folder_tree.get("Wallpapers/Abstract").unwrap();
+        let abstract_images = &app.folder_tree.find("Wallpapers/Abstract").unwrap().images;
         assert_eq!(abstract_images.len(), 2); // geometric.webp, colors.bmp
         
         // Check Screenshots
-        let screenshot_images = app.folder_tree.get("Screenshots").unwrap();
+        let screenshot_images = &app.folder_tree.find("Screenshots").unwrap().images;
         assert_eq!(screenshot_images.len(), 2); // screen1.png, screen2.jpg
         
         // Verify all images have correct relative paths
@@ -148,22 +134,7 @@ mod tests {
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
         args.selected_image_file = selected_file.clone();
         
-        let (sender, receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
-        
-        let app = BackgroundPickerApp {
-            args,
-            images: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: sender,
-            thumbnail_receiver: receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        let app = common::test_app(args, temp_dir.path().join("cache"));
         
         // Save a selected image
         let first_image = std::path::PathBuf::from("/path/to/vacation/beach.jpg");
@@ -171,16 +142,14 @@ mod tests {
         
         // Verify the file was created and contains the correct path
         assert!(selected_file.exists());
-        let content = fs::read_to_string(&selected_file).unwrap();
-        assert_eq!(content.trim(), first_image.to_string_lossy());
+        assert_eq!(app.read_selected_image(), Some(first_image));
         
         // Update with a new selection
         let second_image = std::path::PathBuf::from("/path/to/nature/forest.jpg");
         app.save_selected_image(&second_image).unwrap();
         
         // Verify the file was updated
-        let updated_content = fs::read_to_string(&selected_file).unwrap();
-        assert_eq!(updated_content.trim(), second_image.to_string_lossy());
+        assert_eq!(app.read_selected_image(), Some(second_image));
     }
 
     #[test]
@@ -198,6 +167,7 @@ mod tests {
             &test_image,
             thumbnail_size,
             &cache_dir,
+            ThumbnailQuality::default(),
             false
         );
         
@@ -223,6 +193,7 @@ mod tests {
             &test_image,
             thumbnail_size,
             &cache_dir,
+            ThumbnailQuality::default(),
             false
         );
         
@@ -240,6 +211,7 @@ mod tests {
             &test_image,
             different_size,
             &cache_dir,
+            ThumbnailQuality::default(),
             false
         );
         
@@ -258,42 +230,18 @@ mod tests {
         
         // Test with a simple command that should succeed
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
-        args.command = "echo test".to_string();
-        
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
-        
-        let app = BackgroundPickerApp {
-            args,
-            images: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        args.command = Some("echo test".to_string());
+        
+        let app = common::test_app(args, temp_dir.path().join("cache"));
         
         let result = app.set_background(&test_image);
         assert!(result.is_ok(), "Command execution should succeed");
         
         // Test with feh-like command structure
         let mut args2 = Args::try_parse_from(["background-picker"]).unwrap();
-        args2.command = "echo --bg-max".to_string(); // Simulate feh --bg-max
-        
-        let app2 = BackgroundPickerApp {
-            args: args2,
-            images: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: app.thumbnail_sender.clone(),
-            thumbnail_receiver: app.thumbnail_receiver,
-            thread_pool: rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        args2.command = Some("echo --bg-max".to_string()); // Simulate feh --bg-max
+        
+        let app2 = common::test_app(args2, temp_dir.path().join("cache"));
         
         let result2 = app2.set_background(&test_image);
         assert!(result2.is_ok(), "Feh-like command should succeed");
@@ -307,25 +255,10 @@ mod tests {
         
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
         args.directory = temp_dir.path().to_path_buf();
-        args.thumbnail_size = 100;
+        args.thumbnail_size = Some(100);
         args.debug = false; // Turn off debug for cleaner output in tests
         
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(2)
-            .build()
-            .unwrap();
-        
-        let mut app = BackgroundPickerApp {
-            args: args.clone(),
-            images: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: true,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("thumbnails"),
-        };
+        let mut app = common::test_app(args.clone(), temp_dir.path().join("thumbnails"));
         
         // First scan for images
         let _ = app.scan_images();
@@ -378,22 +311,7 @@ mod tests {
         args.directory = temp_dir.path().to_path_buf();
         args.debug = false;
         
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
-        
-        let mut app = BackgroundPickerApp {
-            args: args.clone(),
-            images: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: true,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        let mut app = common::test_app(args.clone(), temp_dir.path().join("cache"));
         
         let _ = app.scan_images();
         
@@ -416,4 +334,664 @@ mod tests {
         
         assert!(!app.loading);
     }
-}
\ No newline at end of file
+}
+
+mod wallpaper_backend_tests {
+    use background_picker::wallpaper::{detect_backend, detect_monitors, Backend, FitMode, Kde, Monitor, Runner, SessionInfo};
+    use background_picker::{Args, BackgroundPickerError, CommandTarget, Selection};
+    use clap::Parser;
+    use serial_test::serial;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    /// A directory of fake programs that becomes the whole of PATH while it is alive.
+    /// Every program appends its name and arguments to a shared log.
+    struct FakeBin {
+        dir: TempDir,
+        log: PathBuf,
+        old_path: Option<OsString>,
+    }
+
+    impl FakeBin {
+        fn new(programs: &[&str]) -> Self {
+            let dir = TempDir::new().unwrap();
+            let log = dir.path().join("calls.log");
+            let old_path = std::env::var_os("PATH");
+            std::env::set_var("PATH", dir.path());
+            
+            let fake = Self { dir, log, old_path };
+            for program in programs {
+                fake.script(program, "");
+            }
+            fake
+        }
+        
+        /// Install a fake program that logs its call and then runs `body`
+        fn script(&self, name: &str, body: &str) {
+            let path = self.dir.path().join(name);
+            fs::write(&path, format!(
+                "#!/bin/sh\necho \"${{0##*/}} $*\" >> '{}'\n{}\n",
+                self.log.display(),
+                body
+            )).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        
+        fn calls(&self) -> Vec<String> {
+            fs::read_to_string(&self.log)
+                .map(|log| log.lines().map(str::to_owned).collect())
+                .unwrap_or_default()
+        }
+        
+        /// Wait for programs started in the background to log their call
+        fn wait_for_calls(&self, count: usize) -> Vec<String> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while self.calls().len() < count && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            self.calls()
+        }
+    }
+
+    impl Drop for FakeBin {
+        fn drop(&mut self) {
+            match &self.old_path {
+                Some(path) => std::env::set_var("PATH", path),
+                None => std::env::remove_var("PATH"),
+            }
+        }
+    }
+
+    fn target(monitor: Option<&str>) -> CommandTarget {
+        CommandTarget {
+            path: PathBuf::from("/pics/sunset.png"),
+            monitor: monitor.map(str::to_owned),
+            size: Some((1920, 1080)),
+            fit: FitMode::Fit,
+            previous: None,
+        }
+    }
+
+    fn fitted(fit: FitMode) -> CommandTarget {
+        CommandTarget { fit, ..target(None) }
+    }
+
+    #[test]
+    #[serial]
+    fn test_feh_backend() {
+        let fake = FakeBin::new(&["feh"]);
+        for fit in FitMode::ALL {
+            Backend::Feh.setter(Runner::default()).set(&fitted(fit)).unwrap();
+        }
+        assert_eq!(fake.calls(), vec![
+            "feh --bg-fill /pics/sunset.png",
+            "feh --bg-max /pics/sunset.png",
+            "feh --bg-center /pics/sunset.png",
+            "feh --bg-tile /pics/sunset.png",
+            "feh --bg-scale /pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_feh_backend_one_image_per_screen() {
+        let fake = FakeBin::new(&["feh"]);
+        let left = CommandTarget { path: PathBuf::from("/pics/left.png"), ..target(Some("DP-1")) };
+        let right = CommandTarget { path: PathBuf::from("/pics/right.png"), ..target(Some("HDMI-1")) };
+        Backend::Feh.setter(Runner::default()).set_each(&[left, right]).unwrap();
+        assert_eq!(fake.calls(), vec!["feh --bg-max /pics/left.png /pics/right.png"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_monitor_order_for_feh_follows_xinerama() {
+        let fake = FakeBin::new(&[]);
+        fake.script("xrandr", "printf '%s\\n' 'DP-1 connected 2560x1440+0+0' 'HDMI-1 connected primary 1920x1080+2560+0'");
+        let session = SessionInfo { current_desktop: Some("i3".to_owned()), wayland: false, path: None };
+        let names = |backend: Backend| -> Vec<String> {
+            detect_monitors(backend.setter(Runner::default()).as_ref(), &session, &Runner::default())
+                .into_iter()
+                .map(|monitor| monitor.name)
+                .collect()
+        };
+        
+        // feh's first image goes to the primary screen, even though it's on the right
+        assert_eq!(names(Backend::Feh), vec!["HDMI-1", "DP-1"]);
+        assert_eq!(names(Backend::Xwallpaper), vec!["DP-1", "HDMI-1"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_feh_needs_an_image_for_every_screen() {
+        let fake = FakeBin::new(&["feh"]);
+        let temp_dir = TempDir::new().unwrap();
+        let args = Args::try_parse_from(["background-picker", "--backend", "feh", "--fit", "fit"]).unwrap();
+        let mut app = super::common::test_app(args, temp_dir.path().join("cache"));
+        app.monitors.available = vec![
+            Monitor { name: "DP-1".to_owned(), size: (1920, 1080), position: (0, 0) },
+            Monitor { name: "HDMI-1".to_owned(), size: (1920, 1080), position: (1920, 0) },
+        ];
+        
+        // feh would put the only image on the first screen
+        let mut selection = Selection::default();
+        selection.assign(Some("HDMI-1"), Path::new("/pics/right.png"));
+        assert!(matches!(app.set_selection(&selection), Err(BackgroundPickerError::IncompleteSelection(monitor)) if monitor == "DP-1"));
+        assert!(fake.calls().is_empty());
+        
+        // The first screen keeps the wallpaper it already has
+        selection.all = Some(PathBuf::from("/pics/current.png"));
+        app.set_selection(&selection).unwrap();
+        assert_eq!(fake.calls(), vec!["feh --bg-max /pics/current.png /pics/right.png"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_nitrogen_backend() {
+        let fake = FakeBin::new(&["nitrogen"]);
+        Backend::Nitrogen.setter(Runner::default()).set(&target(None)).unwrap();
+        Backend::Nitrogen.setter(Runner::default()).set(&fitted(FitMode::Fill)).unwrap();
+        assert_eq!(fake.calls(), vec![
+            "nitrogen --set-zoom --save /pics/sunset.png",
+            "nitrogen --set-zoom-fill --save /pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_xwallpaper_backend() {
+        let fake = FakeBin::new(&["xwallpaper"]);
+        Backend::Xwallpaper.setter(Runner::default()).set(&target(None)).unwrap();
+        Backend::Xwallpaper.setter(Runner::default()).set(&target(Some("HDMI-1"))).unwrap();
+        Backend::Xwallpaper.setter(Runner::default()).set(&fitted(FitMode::Tile)).unwrap();
+        assert_eq!(fake.calls(), vec![
+            "xwallpaper --maximize /pics/sunset.png",
+            "xwallpaper --output HDMI-1 --maximize /pics/sunset.png",
+            "xwallpaper --tile /pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_swaybg_backend_replaces_running_instance() {
+        let fake = FakeBin::new(&["swaybg"]);
+        // pkill finding nothing to kill must not stop swaybg from starting
+        fake.script("pkill", "exit 1");
+        
+        Backend::Swaybg.setter(Runner::default()).set(&target(Some("DP-2"))).unwrap();
+        
+        let calls = fake.wait_for_calls(2);
+        assert_eq!(calls, vec![
+            "pkill -x swaybg",
+            "swaybg -o DP-2 -i /pics/sunset.png -m fit",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_swaybg_backend_draws_all_outputs_in_one_instance() {
+        let fake = FakeBin::new(&["swaybg", "pkill"]);
+        let left = CommandTarget { path: PathBuf::from("/pics/left.png"), ..target(Some("DP-1")) };
+        let right = CommandTarget { path: PathBuf::from("/pics/right.png"), ..target(Some("HDMI-1")) };
+        Backend::Swaybg.setter(Runner::default()).set_each(&[left, right]).unwrap();
+        
+        let calls = fake.wait_for_calls(2);
+        assert_eq!(calls, vec![
+            "pkill -x swaybg",
+            "swaybg -o DP-1 -i /pics/left.png -m fit -o HDMI-1 -i /pics/right.png -m fit",
+        ]);
+    }
+
+    #[test]
+    fn test_single_image_desktops_are_not_per_monitor() {
+        for backend in [Backend::Gnome, Backend::Kde, Backend::Mate, Backend::Cinnamon, Backend::Nitrogen] {
+            assert!(!backend.setter(Runner::default()).per_monitor(), "{:?}", backend);
+        }
+        for backend in [Backend::Feh, Backend::Xwallpaper, Backend::Swaybg, Backend::Swww, Backend::Hyprpaper, Backend::Xfce] {
+            assert!(backend.setter(Runner::default()).per_monitor(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_hyprpaper_lists_its_monitors() {
+        let fake = FakeBin::new(&[]);
+        fake.script("hyprctl", "echo 'Monitor DP-1 (ID 0):'\nprintf '\\t2560x1440@60.0 at 0x0\\n'");
+        let monitors = Backend::Hyprpaper.setter(Runner::default()).monitors().unwrap();
+        assert_eq!(monitors.len(), 1);
+        assert_eq!(monitors[0].name, "DP-1");
+        assert_eq!(monitors[0].size, (2560, 1440));
+    }
+
+    #[test]
+    #[serial]
+    fn test_swww_backend() {
+        let fake = FakeBin::new(&["swww"]);
+        Backend::Swww.setter(Runner::default()).set(&target(Some("eDP-1"))).unwrap();
+        Backend::Swww.setter(Runner::default()).set(&fitted(FitMode::Fill)).unwrap();
+        assert_eq!(fake.calls(), vec![
+            "swww img --resize fit /pics/sunset.png --outputs eDP-1",
+            "swww img --resize crop /pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_hyprpaper_backend_preloads_first() {
+        let fake = FakeBin::new(&["hyprctl"]);
+        Backend::Hyprpaper.setter(Runner::default()).set(&target(None)).unwrap();
+        assert_eq!(fake.calls(), vec![
+            "hyprctl hyprpaper preload /pics/sunset.png",
+            "hyprctl hyprpaper wallpaper ,contain:/pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_gnome_backend_sets_light_and_dark() {
+        let fake = FakeBin::new(&["gsettings"]);
+        Backend::Gnome.setter(Runner::default()).set(&target(None)).unwrap();
+        assert_eq!(fake.calls(), vec![
+            "gsettings set org.gnome.desktop.background picture-options scaled",
+            "gsettings set org.gnome.desktop.background picture-uri file:///pics/sunset.png",
+            "gsettings set org.gnome.desktop.background picture-uri-dark file:///pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_mate_and_cinnamon_backends() {
+        let fake = FakeBin::new(&["gsettings"]);
+        Backend::Mate.setter(Runner::default()).set(&target(None)).unwrap();
+        Backend::Cinnamon.setter(Runner::default()).set(&fitted(FitMode::Center)).unwrap();
+        assert_eq!(fake.calls(), vec![
+            "gsettings set org.mate.background picture-options scaled",
+            "gsettings set org.mate.background picture-filename /pics/sunset.png",
+            "gsettings set org.cinnamon.desktop.background picture-options centered",
+            "gsettings set org.cinnamon.desktop.background picture-uri file:///pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_xfce_backend_sets_every_last_image_property() {
+        let fake = FakeBin::new(&[]);
+        fake.script("xfconf-query", concat!(
+            "if [ \"$3\" = \"-l\" ]; then\n",
+            "echo /backdrop/screen0/monitorHDMI-1/workspace0/last-image\n",
+            "echo /backdrop/screen0/monitorHDMI-1/workspace0/image-style\n",
+            "echo /backdrop/screen0/monitoreDP-1/workspace0/last-image\n",
+            "fi",
+        ));
+        
+        Backend::Xfce.setter(Runner::default()).set(&fitted(FitMode::Fill)).unwrap();
+        assert_eq!(fake.calls(), vec![
+            "xfconf-query -c xfce4-desktop -l",
+            "xfconf-query -c xfce4-desktop -p /backdrop/screen0/monitorHDMI-1/workspace0/image-style --create -t int -s 5",
+            "xfconf-query -c xfce4-desktop -p /backdrop/screen0/monitorHDMI-1/workspace0/last-image -s /pics/sunset.png",
+            "xfconf-query -c xfce4-desktop -p /backdrop/screen0/monitoreDP-1/workspace0/image-style --create -t int -s 5",
+            "xfconf-query -c xfce4-desktop -p /backdrop/screen0/monitoreDP-1/workspace0/last-image -s /pics/sunset.png",
+        ]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_xfce_backend_limits_to_monitor() {
+        let fake = FakeBin::new(&[]);
+        fake.script("xfconf-query", concat!(
+            "if [ \"$3\" = \"-l\" ]; then\n",
+            "echo /backdrop/screen0/monitorHDMI-1/workspace0/last-image\n",
+            "echo /backdrop/screen0/monitoreDP-1/workspace0/last-image\n",
+            "fi",
+        ));
+        
+        Backend::Xfce.setter(Runner::default()).set(&target(Some("eDP-1"))).unwrap();
+        let calls = fake.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls[1..].iter().all(|call| call.contains("monitoreDP-1")));
+    }
+
+    #[test]
+    #[serial]
+    fn test_xfce_backend_without_properties_fails() {
+        let _fake = FakeBin::new(&["xfconf-query"]);
+        let result = Backend::Xfce.setter(Runner::default()).set(&target(None));
+        assert!(matches!(result, Err(BackgroundPickerError::CommandExecution(_))));
+    }
+
+    #[test]
+    #[serial]
+    fn test_kde_backend_evaluates_plasma_script() {
+        let fake = FakeBin::new(&["qdbus"]);
+        Backend::Kde.setter(Runner::default()).set(&target(None)).unwrap();
+        
+        let calls = fake.calls();
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].starts_with("qdbus org.kde.plasmashell /PlasmaShell org.kde.PlasmaShell.evaluateScript "));
+        assert!(calls[0].contains("d.writeConfig(\"FillMode\", 1);"));
+        assert!(calls[0].contains("d.writeConfig(\"Image\", \"file:///pics/sunset.png\")"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_kde_backend_prefers_qdbus6() {
+        let fake = FakeBin::new(&["qdbus", "qdbus6"]);
+        Backend::Kde.setter(Runner::default()).set(&target(None)).unwrap();
+        assert!(fake.calls()[0].starts_with("qdbus6 "));
+    }
+
+    #[test]
+    fn test_kde_script_escapes_quotes() {
+        let script = Kde::script(Path::new("/pics/say \"hi\".png"), FitMode::Fill);
+        assert!(script.contains("d.writeConfig(\"FillMode\", 2);"));
+        assert!(script.contains("d.writeConfig(\"Image\", \"file:///pics/say%20%22hi%22.png\");"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_backend_failure_reports_command() {
+        let fake = FakeBin::new(&[]);
+        fake.script("feh", "echo 'feh: cannot open display' >&2\nexit 2");
+        
+        match Backend::Feh.setter(Runner::default()).set(&target(None)) {
+            Err(BackgroundPickerError::CommandFailed(failure)) => {
+                assert_eq!(failure.command_line, "feh --bg-max /pics/sunset.png");
+                assert!(failure.stderr.contains("cannot open display"));
+            }
+            other => panic!("expected a command failure, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_detect_backend_from_desktop() {
+        let session = |desktop: &str| SessionInfo {
+            current_desktop: Some(desktop.to_owned()),
+            wayland: false,
+            path: None,
+        };
+        
+        assert_eq!(detect_backend(&session("ubuntu:GNOME")), Backend::Gnome);
+        assert_eq!(detect_backend(&session("KDE")), Backend::Kde);
+        assert_eq!(detect_backend(&session("XFCE")), Backend::Xfce);
+        assert_eq!(detect_backend(&session("MATE")), Backend::Mate);
+        assert_eq!(detect_backend(&session("X-Cinnamon")), Backend::Cinnamon);
+        assert_eq!(detect_backend(&session("Hyprland")), Backend::Hyprpaper);
+        assert_eq!(detect_backend(&session("sway")), Backend::Swaybg);
+    }
+
+    #[test]
+    fn test_detect_backend_from_installed_tools() {
+        let dir = TempDir::new().unwrap();
+        let install = |name: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        let session = |wayland: bool| SessionInfo {
+            current_desktop: Some("i3".to_owned()),
+            wayland,
+            path: Some(dir.path().as_os_str().to_owned()),
+        };
+        
+        // Nothing installed: the usual tool for the display server
+        assert_eq!(detect_backend(&session(false)), Backend::Feh);
+        assert_eq!(detect_backend(&session(true)), Backend::Swaybg);
+        
+        install("xwallpaper");
+        install("swww");
+        assert_eq!(detect_backend(&session(false)), Backend::Xwallpaper);
+        assert_eq!(detect_backend(&session(true)), Backend::Swww);
+    }
+
+    #[test]
+    #[serial]
+    fn test_runner_kills_command_after_timeout() {
+        let fake = FakeBin::new(&[]);
+        fake.script("feh", "echo 'about to hang' >&2\nexec /bin/sleep 10");
+        let runner = Runner { timeout: Some(Duration::from_millis(200)), ..Runner::default() };
+        
+        let started = Instant::now();
+        let result = Backend::Feh.setter(runner).set(&target(None));
+        assert!(started.elapsed() < Duration::from_secs(5), "the command should not be waited for");
+        match result {
+            Err(BackgroundPickerError::CommandFailed(failure)) => {
+                assert!(failure.status.contains("timed out"), "{}", failure.status);
+                assert!(failure.stderr.contains("about to hang"));
+            }
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_replaced_swaybg_is_reaped() {
+        let fake = FakeBin::new(&["pkill"]);
+        let pid_file = fake.dir.path().join("swaybg.pid");
+        fake.script("swaybg", &format!("echo $$ > '{}'", pid_file.display()));
+        
+        Backend::Swaybg.setter(Runner::default()).set(&target(None)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let pid = loop {
+            if let Some(pid) = fs::read_to_string(&pid_file).ok().filter(|pid| pid.ends_with('\n')) {
+                break pid.trim().to_owned();
+            }
+            assert!(Instant::now() < deadline, "swaybg never ran");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        
+        // Once it has exited, nothing is left in the process table
+        let stat = PathBuf::from(format!("/proc/{}/stat", pid));
+        while stat.exists() {
+            assert!(Instant::now() < deadline, "the exited swaybg was never waited for");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_runner_environment_and_working_directory() {
+        let fake = FakeBin::new(&[]);
+        fake.script("gsettings", &format!("echo \"$WALLPAPER_TEST $(pwd)\" >> '{}'", fake.log.display()));
+        let working_dir = TempDir::new().unwrap();
+        let runner = Runner {
+            env: vec![("WALLPAPER_TEST".to_string(), "from env".to_string())],
+            working_dir: Some(working_dir.path().to_path_buf()),
+            ..Runner::default()
+        };
+        
+        Backend::Mate.setter(runner).set(&target(None)).unwrap();
+        let expected = format!("from env {}", working_dir.path().canonicalize().unwrap().display());
+        assert_eq!(fake.calls()[1], expected);
+    }
+}
+
+mod restore_tests {
+    use background_picker::wallpaper::Monitor;
+    use background_picker::{Args, BackgroundPickerApp, BackgroundPickerError};
+    use clap::Parser;
+    use serial_test::serial;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+
+    /// Args for `--restore` with a command that logs the paths it is given
+    fn restore_args(temp_dir: &TempDir, pictures: &Path) -> Args {
+        let script = temp_dir.path().join("set-wallpaper.sh");
+        fs::write(&script, format!("#!/bin/sh\necho \"$1\" >> '{}'\n", temp_dir.path().join("log.txt").display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker", "--restore"]).unwrap();
+        args.directory = pictures.to_path_buf();
+        args.command = Some(script.display().to_string());
+        args.selected_image_file = temp_dir.path().join("selected.txt");
+        args.state_file = temp_dir.path().join("state.yaml");
+        args.history_file = temp_dir.path().join("history.yaml");
+        args
+    }
+
+    fn logged(temp_dir: &TempDir) -> String {
+        fs::read_to_string(temp_dir.path().join("log.txt")).unwrap_or_default()
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_recorded_wallpaper() {
+        let temp_dir = TempDir::new().unwrap();
+        let pictures = temp_dir.path().join("pictures");
+        fs::create_dir(&pictures).unwrap();
+        fs::write(pictures.join("kept.png"), b"png").unwrap();
+        fs::write(pictures.join("other.png"), b"png").unwrap();
+        
+        let args = restore_args(&temp_dir, &pictures);
+        fs::write(&args.selected_image_file, pictures.join("kept.png").display().to_string()).unwrap();
+        
+        let mut app = BackgroundPickerApp::from_args(args).unwrap();
+        // Nothing is scanned when there is a wallpaper to restore
+        assert!(app.images.read().unwrap().is_empty());
+        assert_eq!(app.restore_wallpaper().unwrap(), pictures.join("kept.png"));
+        assert_eq!(logged(&temp_dir), format!("{}\n", pictures.join("kept.png").display()));
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_falls_back_to_random_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let pictures = temp_dir.path().join("pictures");
+        fs::create_dir(&pictures).unwrap();
+        fs::write(pictures.join("only.png"), b"png").unwrap();
+        
+        // No selection file at all
+        let args = restore_args(&temp_dir, &pictures);
+        let selected_image_file = args.selected_image_file.clone();
+        let mut app = BackgroundPickerApp::from_args(args).unwrap();
+        assert_eq!(app.restore_wallpaper().unwrap(), pictures.join("only.png"));
+        assert_eq!(app.read_selected_image(), Some(pictures.join("only.png")));
+        
+        // A recorded image that has since been deleted
+        fs::write(&selected_image_file, "/gone/away.png").unwrap();
+        let mut app = BackgroundPickerApp::from_args(restore_args(&temp_dir, &pictures)).unwrap();
+        assert_eq!(app.restore_wallpaper().unwrap(), pictures.join("only.png"));
+        assert_eq!(logged(&temp_dir).lines().count(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_relative_directory_gives_absolute_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let pictures = temp_dir.path().join("pictures");
+        fs::create_dir(&pictures).unwrap();
+        fs::write(pictures.join("only.png"), b"png").unwrap();
+        
+        // `-d pictures`, run from the directory holding it
+        let args = restore_args(&temp_dir, Path::new("pictures"));
+        let cwd = std::env::current_dir().unwrap();
+        std::env::set_current_dir(temp_dir.path()).unwrap();
+        let result = BackgroundPickerApp::from_args(args).and_then(|mut app| app.restore_wallpaper());
+        std::env::set_current_dir(cwd).unwrap();
+        
+        let expected = pictures.canonicalize().unwrap().join("only.png");
+        assert_eq!(result.unwrap(), expected);
+        assert_eq!(logged(&temp_dir), format!("{}\n", expected.display()));
+        
+        let app = BackgroundPickerApp::from_args(restore_args(&temp_dir, &pictures)).unwrap();
+        assert_eq!(app.read_selected_image(), Some(expected));
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_without_any_images() {
+        let temp_dir = TempDir::new().unwrap();
+        let pictures = PathBuf::from(temp_dir.path());
+        
+        let mut app = BackgroundPickerApp::from_args(restore_args(&temp_dir, &pictures)).unwrap();
+        assert!(matches!(app.restore_wallpaper(), Err(BackgroundPickerError::NothingToRestore(dir)) if dir == pictures));
+        assert!(logged(&temp_dir).is_empty());
+        
+        assert!(Args::try_parse_from(["background-picker", "--restore", "--pregenerate"]).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_undo_redo_and_reapply_history() {
+        let temp_dir = TempDir::new().unwrap();
+        let pictures = PathBuf::from(temp_dir.path());
+        let mut app = BackgroundPickerApp::from_args(restore_args(&temp_dir, &pictures)).unwrap();
+        let selected = |app: &BackgroundPickerApp| app.read_selection().all.unwrap();
+        
+        for name in ["a.png", "b.png", "c.png"] {
+            app.apply_wallpaper(Path::new(name)).unwrap();
+        }
+        assert_eq!(app.recent.history.entries.len(), 3);
+        assert_eq!(app.recent.history.entries[0].backend, "custom command");
+        
+        assert_eq!(app.undo().unwrap().unwrap().path, PathBuf::from("b.png"));
+        assert_eq!(selected(&app), PathBuf::from("b.png"));
+        assert_eq!(app.undo().unwrap().unwrap().path, PathBuf::from("a.png"));
+        assert_eq!(app.undo().unwrap(), None);
+        assert_eq!(app.redo().unwrap().unwrap().path, PathBuf::from("b.png"));
+        assert_eq!(logged(&temp_dir), "a.png\nb.png\nc.png\nb.png\na.png\nb.png\n");
+        
+        // The undo position is kept in the file, for the next --undo or --redo
+        let mut again = BackgroundPickerApp::from_args(restore_args(&temp_dir, &pictures)).unwrap();
+        assert_eq!(again.recent.history.undone, 1);
+        assert_eq!(again.redo().unwrap().unwrap().path, PathBuf::from("c.png"));
+        assert_eq!(again.redo().unwrap(), None);
+        
+        // A new apply after undoing drops what was undone
+        app.recent.history = again.recent.history.clone();
+        app.undo().unwrap();
+        app.apply_wallpaper(Path::new("d.png")).unwrap();
+        let paths: Vec<PathBuf> = app.recent.history.entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("a.png"), PathBuf::from("b.png"), PathBuf::from("d.png")]);
+        
+        // Entries count from the newest, and reapplying one logs it again
+        assert_eq!(app.reapply_history(3).unwrap().path, PathBuf::from("a.png"));
+        assert_eq!(selected(&app), PathBuf::from("a.png"));
+        assert_eq!(app.recent.history.entries.len(), 4);
+        assert!(app.reapply_history(0).is_err());
+        assert!(app.reapply_history(5).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_unreadable_history_file_is_kept() {
+        let temp_dir = TempDir::new().unwrap();
+        let pictures = PathBuf::from(temp_dir.path());
+        let history_file = temp_dir.path().join("history.yaml");
+        fs::write(&history_file, "entries: [not an entry").unwrap();
+        let mut app = BackgroundPickerApp::from_args(restore_args(&temp_dir, &pictures)).unwrap();
+        assert!(app.recent.error.is_some());
+        
+        app.apply_wallpaper(Path::new("a.png")).unwrap();
+        assert_eq!(fs::read_to_string(&history_file).unwrap(), "entries: [not an entry");
+        assert!(app.undo().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_undo_per_monitor_apply() {
+        let temp_dir = TempDir::new().unwrap();
+        let pictures = PathBuf::from(temp_dir.path());
+        let mut app = BackgroundPickerApp::from_args(restore_args(&temp_dir, &pictures)).unwrap();
+        app.monitors.available = vec![
+            Monitor { name: "DP-1".to_string(), size: (1920, 1080), position: (0, 0) },
+            Monitor { name: "HDMI-1".to_string(), size: (1920, 1080), position: (1920, 0) },
+        ];
+        
+        app.apply_wallpaper(Path::new("both.png")).unwrap();
+        app.monitors.target = Some("HDMI-1".to_string());
+        app.apply_wallpaper(Path::new("side.png")).unwrap();
+        assert_eq!(app.recent.history.entries[1].monitor.as_deref(), Some("HDMI-1"));
+        
+        // Undoing the second apply puts the first image back on that monitor too
+        fs::remove_file(temp_dir.path().join("log.txt")).unwrap();
+        app.undo().unwrap();
+        assert_eq!(logged(&temp_dir), "both.png\n");
+        assert!(app.monitors.assigned.is_empty());
+        assert_eq!(app.current_wallpaper, Some(PathBuf::from("both.png")));
+    }
+}
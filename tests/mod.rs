@@ -1,2 +1,5 @@
+// Both files are also built as test crates of their own, so each declares the shared helpers
+#![allow(clippy::duplicate_mod)]
+
 mod unit_tests;
-mod integration_tests;
\ No newline at end of file
+mod integration_tests;
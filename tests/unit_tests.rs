@@ -1,11 +1,15 @@
-use background_picker::{Args, BackgroundPickerApp, is_image_file, validate_command};
+use background_picker::{Args, BackgroundPickerError, AspectRatioRange, BackgroundPickerApp, FolderNode, HookPolicy, ImageFilter, PickerState, MonitorState, ThumbnailQueue, ThumbnailQuality, is_image_file, natural_cmp, validate_command};
+use background_picker::wallpaper::{Backend, FitMode};
 use clap::Parser;
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::fs::{self, File};
 use tempfile::TempDir;
 use serial_test::serial;
 
+#[path = "common/mod.rs"]
+mod common;
+
 #[cfg(test)]
 mod cli_args_tests {
     use super::*;
@@ -15,11 +19,13 @@ mod cli_args_tests {
         let args = Args::try_parse_from(["background-picker"]).unwrap();
         
         assert_eq!(args.directory, PathBuf::from("."));
-        assert_eq!(args.thumbnail_size, 150);
-        assert_eq!(args.command, "feh --bg-max");
+        assert_eq!(args.thumbnail_size, None);
+        assert_eq!(args.command, None);
+        assert_eq!(args.backend, None);
         assert_eq!(args.selected_image_file, PathBuf::from("selected-background.txt"));
         assert!(!args.debug);
         assert!(!args.pregenerate);
+        assert!(!args.stay_open);
     }
 
     #[test]
@@ -31,15 +37,81 @@ mod cli_args_tests {
             "--command", "gsettings set org.gnome.desktop.background picture-uri",
             "--selected-image-file", "custom-selected.txt",
             "--debug",
-            "--pregenerate"
+            "--pregenerate",
+            "--stay-open",
+            "--backend", "xfce",
+            "--fit", "tile",
+            "--timeout", "5",
+            "--env", "DISPLAY=:1",
+            "--env", "EMPTY=",
+            "--working-dir", "/tmp"
         ]).unwrap();
         
         assert_eq!(args.directory, PathBuf::from("/home/user/pictures"));
-        assert_eq!(args.thumbnail_size, 200);
-        assert_eq!(args.command, "gsettings set org.gnome.desktop.background picture-uri");
+        assert_eq!(args.thumbnail_size, Some(200));
+        assert_eq!(args.command.as_deref(), Some("gsettings set org.gnome.desktop.background picture-uri"));
         assert_eq!(args.selected_image_file, PathBuf::from("custom-selected.txt"));
         assert!(args.debug);
         assert!(args.pregenerate);
+        assert!(args.stay_open);
+        assert_eq!(args.backend, Some(Backend::Xfce));
+        assert_eq!(args.fit, Some(FitMode::Tile));
+        assert_eq!(args.timeout, 5);
+        assert_eq!(args.env, vec![("DISPLAY".to_string(), ":1".to_string()), ("EMPTY".to_string(), String::new())]);
+        assert_eq!(args.working_dir, Some(PathBuf::from("/tmp")));
+    }
+
+    #[test]
+    fn test_args_invalid_env() {
+        assert!(Args::try_parse_from(["background-picker", "--env", "NO_EQUALS"]).is_err());
+        assert!(Args::try_parse_from(["background-picker", "--env", "=value"]).is_err());
+        
+        let args = Args::try_parse_from(["background-picker"]).unwrap();
+        assert_eq!(args.timeout, 30);
+        assert!(args.env.is_empty());
+    }
+
+    #[test]
+    fn test_args_history_modes() {
+        let args = Args::try_parse_from(["background-picker", "--history"]).unwrap();
+        assert_eq!(args.history, Some(None));
+        assert!(args.runs_without_gui());
+        
+        let args = Args::try_parse_from(["background-picker", "--history", "3", "--history-file", "/tmp/h.yaml"]).unwrap();
+        assert_eq!(args.history, Some(Some(3)));
+        assert_eq!(args.history_file, PathBuf::from("/tmp/h.yaml"));
+        
+        let args = Args::try_parse_from(["background-picker"]).unwrap();
+        assert_eq!(args.history, None);
+        assert!(!args.runs_without_gui());
+        
+        // Only one mode at a time
+        assert!(Args::try_parse_from(["background-picker", "--undo", "--redo"]).is_err());
+        assert!(Args::try_parse_from(["background-picker", "--restore", "--history"]).is_err());
+        assert!(Args::try_parse_from(["background-picker", "--undo", "--pregenerate"]).is_err());
+    }
+
+    #[test]
+    fn test_args_hooks() {
+        let args = Args::try_parse_from([
+            "background-picker",
+            "--hook", "wal -i {path}",
+            "--hook", "stop:polybar-msg cmd restart",
+            "--hook", "ignore:notify-send 'Wallpaper changed'",
+            "--hook", "sh -c 'echo a:b'",
+        ]).unwrap();
+        
+        let hooks: Vec<(&str, HookPolicy)> = args.hooks.iter().map(|hook| (hook.command.as_str(), hook.policy)).collect();
+        assert_eq!(hooks, vec![
+            ("wal -i {path}", HookPolicy::Warn),
+            ("polybar-msg cmd restart", HookPolicy::Stop),
+            ("notify-send 'Wallpaper changed'", HookPolicy::Ignore),
+            ("sh -c 'echo a:b'", HookPolicy::Warn),
+        ]);
+        
+        assert!(Args::try_parse_from(["background-picker", "--hook", "stop:"]).is_err());
+        assert!(Args::try_parse_from(["background-picker", "--hook", "wal 'unterminated"]).is_err());
+        assert!(Args::try_parse_from(["background-picker"]).unwrap().hooks.is_empty());
     }
 
     #[test]
@@ -53,8 +125,8 @@ mod cli_args_tests {
         ]).unwrap();
         
         assert_eq!(args.directory, PathBuf::from("/tmp"));
-        assert_eq!(args.thumbnail_size, 100);
-        assert_eq!(args.command, "echo");
+        assert_eq!(args.thumbnail_size, Some(100));
+        assert_eq!(args.command.as_deref(), Some("echo"));
         assert_eq!(args.selected_image_file, PathBuf::from("selected.txt"));
     }
 
@@ -66,6 +138,14 @@ mod cli_args_tests {
         ]);
         
         assert!(result.is_err());
+        
+        // Sizes outside what the zoom slider offers are refused too
+        for size in ["0", "63", "1025", "100000"] {
+            assert!(Args::try_parse_from(["background-picker", "-t", size]).is_err(), "{}", size);
+        }
+        for size in ["64", "1024"] {
+            assert!(Args::try_parse_from(["background-picker", "-t", size]).is_ok(), "{}", size);
+        }
     }
 }
 
@@ -82,24 +162,35 @@ mod selected_image_tests {
         
         let args = Args {
             directory: PathBuf::from("."),
-            thumbnail_size: 150,
-            command: "echo".to_string(),
+            thumbnail_size: None,
+            command: Some("echo".to_string()),
+            backend: None,
             selected_image_file: selected_file.clone(),
             debug: false,
             pregenerate: false,
+            restore: false,
+            undo: false,
+            redo: false,
+            history: None,
+            history_file: temp_dir.path().join("history.yaml"),
+            min_width: None,
+            min_height: None,
+            aspect_ratio: None,
+            fit_screen: false,
+            sort: None,
+            state_file: temp_dir.path().join("state.yaml"),
+            stay_open: false,
+            quality: None,
+            fit: None,
+            span: false,
+            bezel: 0,
+            timeout: 30,
+            env: Vec::new(),
+            working_dir: None,
+            hooks: Vec::new(),
         };
         
-        // Create a minimal app for testing
-        let app = BackgroundPickerApp {
-            args,
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: std::sync::mpsc::channel().0,
-            thumbnail_receiver: std::sync::mpsc::channel().1,
-            thread_pool: rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
-            cache_dir: temp_dir.path().to_path_buf(),
-        };
+        let app = common::test_app(args, temp_dir.path().to_path_buf());
         
         let test_path = PathBuf::from("/path/to/test/image.jpg");
         let result = app.save_selected_image(&test_path);
@@ -108,7 +199,9 @@ mod selected_image_tests {
         assert!(selected_file.exists());
         
         let content = fs::read_to_string(&selected_file).unwrap();
-        assert_eq!(content.trim(), test_path.to_string_lossy());
+        assert!(content.starts_with("version: 1\n"));
+        assert!(content.contains("all: /path/to/test/image.jpg\n"));
+        assert_eq!(app.read_selected_image(), Some(test_path));
     }
 
     #[test]
@@ -120,24 +213,35 @@ mod selected_image_tests {
         
         let args = Args {
             directory: PathBuf::from("."),
-            thumbnail_size: 150,
-            command: "echo".to_string(),
+            thumbnail_size: None,
+            command: Some("echo".to_string()),
+            backend: None,
             selected_image_file: selected_file.clone(),
             debug: false,
             pregenerate: false,
+            restore: false,
+            undo: false,
+            redo: false,
+            history: None,
+            history_file: temp_dir.path().join("history.yaml"),
+            min_width: None,
+            min_height: None,
+            aspect_ratio: None,
+            fit_screen: false,
+            sort: None,
+            state_file: temp_dir.path().join("state.yaml"),
+            stay_open: false,
+            quality: None,
+            fit: None,
+            span: false,
+            bezel: 0,
+            timeout: 30,
+            env: Vec::new(),
+            working_dir: None,
+            hooks: Vec::new(),
         };
         
-        // Create a minimal app for testing
-        let app = BackgroundPickerApp {
-            args,
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: std::sync::mpsc::channel().0,
-            thumbnail_receiver: std::sync::mpsc::channel().1,
-            thread_pool: rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
-            cache_dir: temp_dir.path().to_path_buf(),
-        };
+        let app = common::test_app(args, temp_dir.path().to_path_buf());
         
         let test_path = PathBuf::from("/path/to/image.jpg");
         let result = app.save_selected_image(&test_path);
@@ -145,9 +249,10 @@ mod selected_image_tests {
         assert!(result.is_ok());
         assert!(nested_dir.exists());
         assert!(selected_file.exists());
+        assert_eq!(app.read_selected_image(), Some(test_path));
         
-        let content = fs::read_to_string(&selected_file).unwrap();
-        assert_eq!(content.trim(), test_path.to_string_lossy());
+        // Nothing is left behind from the atomic write
+        assert_eq!(fs::read_dir(&nested_dir).unwrap().count(), 1);
     }
 }
 
@@ -205,6 +310,12 @@ mod utility_tests {
         }
     }
 
+    #[test]
+    fn test_validate_command_unbalanced_quotes() {
+        assert!(validate_command("feh --bg-max 'unterminated").is_err());
+        assert!(validate_command("feh --bg-max 'quoted arg'").is_ok());
+    }
+
     #[test]
     fn test_validate_command_empty() {
         let invalid_commands = vec![
@@ -390,6 +501,44 @@ mod image_processing_tests {
         assert_eq!(thumbnail.pixels.len(), (thumbnail_size * thumbnail_size) as usize);
     }
 
+    #[test]
+    fn test_create_thumbnail_qualities() {
+        // A 1px checkerboard: nearest neighbour keeps stark black and white,
+        // proper filters average it out to grey
+        let checkerboard = RgbImage::from_fn(400, 400, |x, y| {
+            if (x + y) % 2 == 0 { image::Rgb([0, 0, 0]) } else { image::Rgb([255, 255, 255]) }
+        });
+        
+        for quality in ThumbnailQuality::ALL {
+            let thumbnail = BackgroundPickerApp::create_thumbnail(DynamicImage::ImageRgb8(checkerboard.clone()), 50, quality).unwrap();
+            assert_eq!(thumbnail.size, [50, 50]);
+            let centre = thumbnail.pixels[25 * 50 + 25];
+            assert!((100..=155).contains(&centre.r()), "{:?} gave {:?}", quality, centre);
+        }
+    }
+
+    #[test]
+    fn test_cache_paths_per_quality() {
+        let temp_dir = TempDir::new().unwrap();
+        let test_file = temp_dir.path().join("test.jpg");
+        File::create(&test_file).unwrap();
+        let cache_dir = temp_dir.path().join("cache");
+        
+        let paths: Vec<PathBuf> = ThumbnailQuality::ALL.iter()
+            .map(|&quality| BackgroundPickerApp::get_cached_thumbnail_path_for(&test_file, &cache_dir, quality).unwrap())
+            .collect();
+        assert_ne!(paths[0], paths[1]);
+        assert_ne!(paths[1], paths[2]);
+        assert_ne!(paths[0], paths[2]);
+        
+        // The default keeps the spec file name shared with file managers
+        assert_eq!(
+            BackgroundPickerApp::get_cached_thumbnail_path_static(&test_file, &cache_dir).unwrap(),
+            paths[1],
+        );
+        assert_eq!(ThumbnailQuality::default(), ThumbnailQuality::Balanced);
+    }
+
     #[test]
     fn test_create_thumbnail_fast_different_sizes() {
         let test_sizes = vec![32, 64, 128, 256];
@@ -495,24 +644,7 @@ mod file_scanning_tests {
         args.directory = temp_dir.path().to_path_buf();
         args.debug = false;
         
-        // Mock the creation context - this is tricky without egui
-        // For unit tests, we'll test the scan_images method directly by creating a minimal app
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
-        
-        let mut app = BackgroundPickerApp {
-            args: args.clone(),
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: true,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        let mut app = common::test_app(args.clone(), temp_dir.path().join("cache"));
         
         let _ = app.scan_images();
         
@@ -523,23 +655,21 @@ mod file_scanning_tests {
         
         // Check that we have the right folder structure
         // Note: Root folder might be empty if all files are in subdirectories
-        if let Some(root_images) = app.folder_tree.get(".") {
-            assert_eq!(root_images.len(), 3); // image1.jpg, image2.png, image3.JPEG
-        }
-        assert!(app.folder_tree.contains_key("folder1"));
-        assert!(app.folder_tree.contains_key("folder2"));
-        assert!(app.folder_tree.contains_key("nested/subfolder"));
+        assert_eq!(app.folder_tree.images.len(), 3); // image1.jpg, image2.png, image3.JPEG
+        assert!(app.folder_tree.find("folder1").is_some());
+        assert!(app.folder_tree.find("folder2").is_some());
+        assert!(app.folder_tree.find("nested/subfolder").is_some());
         
         // Check folder1 images
-        let folder1_images = app.folder_tree.get("folder1").unwrap();
+        let folder1_images = &app.folder_tree.find("folder1").unwrap().images;
         assert_eq!(folder1_images.len(), 2); // photo1.gif, photo2.bmp
         
         // Check folder2 images
-        let folder2_images = app.folder_tree.get("folder2").unwrap();
+        let folder2_images = &app.folder_tree.find("folder2").unwrap().images;
         assert_eq!(folder2_images.len(), 1); // image.webp
         
         // Check nested folder images
-        let nested_images = app.folder_tree.get("nested/subfolder").unwrap();
+        let nested_images = &app.folder_tree.find("nested/subfolder").unwrap().images;
         assert_eq!(nested_images.len(), 1); // deep.png
         
         assert!(!app.loading);
@@ -553,28 +683,13 @@ mod file_scanning_tests {
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
         args.directory = temp_dir.path().to_path_buf();
         
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
-        
-        let mut app = BackgroundPickerApp {
-            args: args.clone(),
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: true,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        let mut app = common::test_app(args.clone(), temp_dir.path().join("cache"));
         
         let _ = app.scan_images();
         
         let images = app.images.read().unwrap();
         assert_eq!(images.len(), 0);
-        assert!(app.folder_tree.is_empty());
+        assert_eq!(app.folder_tree.total_images(), 0);
         assert!(!app.loading);
     }
 
@@ -586,28 +701,13 @@ mod file_scanning_tests {
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
         args.directory = nonexistent_dir;
         
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
-        
-        let mut app = BackgroundPickerApp {
-            args: args.clone(),
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: true,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: PathBuf::from("/tmp/cache"),
-        };
+        let mut app = common::test_app(args.clone(), PathBuf::from("/tmp/cache"));
         
         let _ = app.scan_images();
         
         let images = app.images.read().unwrap();
         assert_eq!(images.len(), 0);
-        assert!(app.folder_tree.is_empty());
+        assert_eq!(app.folder_tree.total_images(), 0);
         assert!(!app.loading);
     }
 }
@@ -623,24 +723,9 @@ mod error_handling_tests {
         fs::write(&test_image, b"fake image").unwrap();
         
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
-        args.command = "".to_string(); // Empty command
-        
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
+        args.command = Some("".to_string()); // Empty command
         
-        let app = BackgroundPickerApp {
-            args,
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        let app = common::test_app(args, temp_dir.path().join("cache"));
         
         let result = app.set_background(&test_image);
         assert!(result.is_err());
@@ -655,55 +740,58 @@ mod error_handling_tests {
         fs::write(&test_image, b"fake image").unwrap();
         
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
-        args.command = "nonexistent_command_that_should_fail".to_string();
-        
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
+        args.command = Some("nonexistent_command_that_should_fail".to_string());
         
-        let app = BackgroundPickerApp {
-            args,
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        let app = common::test_app(args, temp_dir.path().join("cache"));
         
         let result = app.set_background(&test_image);
-        assert!(result.is_err());
+        match result {
+            Err(BackgroundPickerError::CommandFailed(failure)) => {
+                assert!(failure.command_line.starts_with("nonexistent_command_that_should_fail "));
+                assert!(failure.status.contains("could not be started"));
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
     }
 
     #[test]
     #[serial]
-    fn test_set_background_valid_echo_command() {
+    fn test_set_background_failure_details() {
+        use std::os::unix::fs::PermissionsExt;
+        
         let temp_dir = TempDir::new().unwrap();
-        let test_image = temp_dir.path().join("test.jpg");
+        let test_image = temp_dir.path().join("my wallpaper.jpg");
         fs::write(&test_image, b"fake image").unwrap();
+        let script = temp_dir.path().join("fail.sh");
+        fs::write(&script, "#!/bin/sh\necho \"cannot open display\" >&2\nexit 3\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
         
         let mut args = Args::try_parse_from(["background-picker"]).unwrap();
-        args.command = "echo".to_string(); // Echo should always succeed
+        args.command = Some(format!("{} --bg-fill", script.display()));
         
-        let (sender, _receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
+        let app = common::test_app(args, temp_dir.path().join("cache"));
         
-        let app = BackgroundPickerApp {
-            args,
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: sender,
-            thumbnail_receiver: _receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
+        let Err(BackgroundPickerError::CommandFailed(failure)) = app.set_background(&test_image) else {
+            panic!("expected a command failure");
         };
+        assert_eq!(failure.command_line, format!("{} --bg-fill '{}'", script.display(), test_image.display()));
+        assert!(failure.status.contains('3'), "status was {}", failure.status);
+        assert_eq!(failure.stderr, "cannot open display\n");
+        assert!(failure.to_string().ends_with(": cannot open display"));
+        assert!(failure.details().contains("Stderr:\ncannot open display"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_background_valid_echo_command() {
+        let temp_dir = TempDir::new().unwrap();
+        let test_image = temp_dir.path().join("test.jpg");
+        fs::write(&test_image, b"fake image").unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.command = Some("echo".to_string()); // Echo should always succeed
+        
+        let app = common::test_app(args, temp_dir.path().join("cache"));
         
         let result = app.set_background(&test_image);
         assert!(result.is_ok());
@@ -718,25 +806,1524 @@ mod error_handling_tests {
         // Try to save to a directory that doesn't exist and can't be created
         args.selected_image_file = PathBuf::from("/root/forbidden/selected.txt");
         
-        let (sender, receiver) = std::sync::mpsc::channel();
-        let thread_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
-        
-        let app = BackgroundPickerApp {
-            args,
-            images: Arc::new(RwLock::new(Vec::new())),
-            folder_tree: std::collections::HashMap::new(),
-            loading: false,
-            thumbnail_sender: sender,
-            thumbnail_receiver: receiver,
-            thread_pool,
-            cache_dir: temp_dir.path().join("cache"),
-        };
+        let app = common::test_app(args, temp_dir.path().join("cache"));
         
         let test_path = PathBuf::from("/path/to/image.jpg");
         let result = app.save_selected_image(&test_path);
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod image_filter_tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    #[test]
+    fn test_aspect_ratio_parsing() {
+        let ultrawide: AspectRatioRange = "21:9".parse().unwrap();
+        assert!(ultrawide.contains(2560.0 / 1080.0));
+        assert!(!ultrawide.contains(16.0 / 9.0));
+        
+        let decimal: AspectRatioRange = "1.5".parse().unwrap();
+        assert!(decimal.contains(1.5));
+        
+        let range: AspectRatioRange = "21:9-16:9".parse().unwrap();
+        assert!(range.contains(16.0 / 9.0));
+        assert!(range.contains(2.0));
+        assert!(!range.contains(4.0 / 3.0));
+        
+        assert!("wide".parse::<AspectRatioRange>().is_err());
+        assert!("16:0".parse::<AspectRatioRange>().is_err());
+    }
+
+    #[test]
+    fn test_args_filter_options() {
+        let args = Args::try_parse_from([
+            "background-picker",
+            "--min-width", "3440",
+            "--min-height", "1440",
+            "--aspect-ratio", "21:9",
+            "--fit-screen"
+        ]).unwrap();
+        
+        let filter = ImageFilter::from_args(&args);
+        assert_eq!(filter.min_width, Some(3440));
+        assert_eq!(filter.min_height, Some(1440));
+        assert_eq!(filter.aspect_ratio, Some("21:9".parse().unwrap()));
+        assert!(filter.fit_screen);
+        
+        assert!(Args::try_parse_from(["background-picker", "--aspect-ratio", "tall"]).is_err());
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let inactive = ImageFilter::default();
+        assert!(inactive.matches(None, None));
+        assert!(inactive.matches(Some((10, 10)), None));
+        
+        let min_size = ImageFilter { min_width: Some(1920), min_height: Some(1080), ..Default::default() };
+        assert!(min_size.matches(Some((1920, 1080)), None));
+        assert!(!min_size.matches(Some((1280, 1080)), None));
+        assert!(!min_size.matches(Some((1920, 720)), None));
+        assert!(!min_size.matches(None, None)); // Unknown dimensions never match
+        
+        let ultrawide = ImageFilter { aspect_ratio: Some("21:9".parse().unwrap()), ..Default::default() };
+        assert!(ultrawide.matches(Some((3440, 1440)), None));
+        assert!(!ultrawide.matches(Some((1920, 1080)), None));
+    }
+
+    #[test]
+    fn test_filter_fit_screen() {
+        let filter = ImageFilter { fit_screen: true, ..Default::default() };
+        let screen = Some((2560, 1440));
+        
+        assert!(filter.matches(Some((2560, 1440)), screen));
+        assert!(filter.matches(Some((3840, 2160)), screen));
+        assert!(!filter.matches(Some((1920, 1080)), screen)); // Too small
+        assert!(!filter.matches(Some((3440, 1440)), screen)); // Wrong shape
+        
+        // Without a known screen size only the dimensions requirement applies
+        assert!(filter.matches(Some((640, 480)), None));
+    }
+
+    #[test]
+    #[serial]
+    fn test_scan_images_reads_dimensions() {
+        let temp_dir = TempDir::new().unwrap();
+        DynamicImage::ImageRgb8(RgbImage::new(210, 90))
+            .save(temp_dir.path().join("wide.png"))
+            .unwrap();
+        DynamicImage::ImageRgb8(RgbImage::new(90, 160))
+            .save(temp_dir.path().join("tall.png"))
+            .unwrap();
+        fs::write(temp_dir.path().join("broken.jpg"), b"not an image").unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        
+        app.scan_images().unwrap();
+        
+        let dimensions = |name: &str| {
+            let images = app.images.read().unwrap();
+            images.iter().find(|i| i.relative_path == name).unwrap().dimensions
+        };
+        assert_eq!(dimensions("wide.png"), Some((210, 90)));
+        assert_eq!(dimensions("tall.png"), Some((90, 160)));
+        assert_eq!(dimensions("broken.jpg"), None);
+        
+        let all_indices: Vec<usize> = (0..3).collect();
+        assert_eq!(app.filtered_indices(&all_indices, None).len(), 3);
+        
+        app.filter.aspect_ratio = Some("21:9".parse().unwrap());
+        let visible = app.filtered_indices(&all_indices, None);
+        assert_eq!(visible.len(), 1);
+        assert_eq!(app.images.read().unwrap()[visible[0]].relative_path, "wide.png");
+    }
+}
+
+#[cfg(test)]
+mod folder_tree_tests {
+    use super::*;
+    use std::cmp::Ordering;
+    use std::path::Path;
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp("img2.png", "img10.png"), Ordering::Less);
+        assert_eq!(natural_cmp("img10.png", "img9.png"), Ordering::Greater);
+        assert_eq!(natural_cmp("Alps", "beach"), Ordering::Less); // Case insensitive
+        assert_eq!(natural_cmp("img007", "img7"), Ordering::Less); // Numeric tie broken consistently
+        assert_eq!(natural_cmp("same", "same"), Ordering::Equal);
+        
+        let mut names = vec!["photo 10", "Photo 2", "photo 1", "album"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["album", "photo 1", "Photo 2", "photo 10"]);
+    }
+
+    #[test]
+    fn test_folder_tree_nesting_and_counts() {
+        let mut tree = FolderNode::default();
+        tree.insert(Path::new(""), 0);
+        tree.insert(Path::new("nature/mountains/alps"), 1);
+        tree.insert(Path::new("nature/mountains/alps"), 2);
+        tree.insert(Path::new("nature"), 3);
+        tree.insert(Path::new("city"), 4);
+        
+        assert_eq!(tree.find(".").unwrap().images, vec![0]);
+        assert_eq!(tree.find("nature/mountains/alps").unwrap().images, vec![1, 2]);
+        assert!(tree.find("nature/mountains").unwrap().images.is_empty()); // Only holds a subfolder
+        assert!(tree.find("city").is_some());
+        assert!(tree.find("nature/rivers").is_none());
+        
+        // Root, nature, mountains, alps and city
+        assert_eq!(tree.nodes().len(), 5);
+        
+        let nature = tree.find("nature").unwrap();
+        assert_eq!(nature.total_images(), 3);
+        assert_eq!(nature.children[0].path, "nature/mountains");
+        assert_eq!(tree.total_images(), 5);
+        
+        let visible = vec![false, true, false, true, true];
+        assert_eq!(nature.count_matching(&visible), 2);
+        assert_eq!(tree.count_matching(&visible), 3);
+    }
+
+    #[test]
+    #[serial]
+    fn test_scan_images_sorted_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(temp_dir.path().join("walls/b")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("walls/A")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("Zoo")).unwrap();
+        for name in ["img10.jpg", "img2.jpg", "img1.jpg"] {
+            fs::write(temp_dir.path().join("walls/b").join(name), b"fake").unwrap();
+        }
+        fs::write(temp_dir.path().join("walls/A/x.png"), b"fake").unwrap();
+        fs::write(temp_dir.path().join("Zoo/y.png"), b"fake").unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        
+        app.scan_images().unwrap();
+        
+        let top: Vec<&str> = app.folder_tree.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(top, vec!["walls", "Zoo"]);
+        
+        let walls = app.folder_tree.find("walls").unwrap();
+        let sub: Vec<&str> = walls.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(sub, vec!["A", "b"]);
+        assert_eq!(walls.total_images(), 4);
+        
+        let images = app.images.read().unwrap();
+        let names: Vec<String> = app.folder_tree.find("walls/b").unwrap().images.iter()
+            .map(|&i| images[i].path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["img1.jpg", "img2.jpg", "img10.jpg"]);
+    }
+}
+
+#[cfg(test)]
+mod sort_mode_tests {
+    use super::*;
+    use background_picker::{ImageInfo, ImageSorter, SortMode};
+    use std::time::{Duration, SystemTime};
+
+    fn image(name: &str, size: u64, age_secs: u64, dimensions: Option<(u32, u32)>) -> ImageInfo {
+        ImageInfo {
+            path: PathBuf::from("/walls").join(name),
+            thumbnail: None,
+            relative_path: name.to_string(),
+            loading: false,
+            thumbnail_failed: false,
+            dimensions,
+            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 - age_secs)),
+            file_size: size,
+        }
+    }
+
+    fn sorted_names(images: &[ImageInfo], sorter: &ImageSorter) -> Vec<String> {
+        let mut tree = FolderNode::default();
+        for index in 0..images.len() {
+            tree.insert(std::path::Path::new(""), index);
+        }
+        tree.sort(images, sorter);
+        tree.images.iter().map(|&i| images[i].relative_path.clone()).collect()
+    }
+
+    fn sample_images() -> Vec<ImageInfo> {
+        vec![
+            image("b10.jpg", 300, 50, Some((1920, 1080))),
+            image("b2.jpg", 100, 10, Some((3440, 1440))),
+            image("a.jpg", 200, 30, Some((800, 1200))),
+            image("c.jpg", 50, 5, None),
+        ]
+    }
+
+    #[test]
+    fn test_sort_modes() {
+        let images = sample_images();
+        let sort = |mode| sorted_names(&images, &ImageSorter::new(mode, &[], 0));
+        
+        assert_eq!(sort(SortMode::Name), vec!["a.jpg", "b2.jpg", "b10.jpg", "c.jpg"]);
+        assert_eq!(sort(SortMode::Modified), vec!["c.jpg", "b2.jpg", "a.jpg", "b10.jpg"]);
+        assert_eq!(sort(SortMode::Size), vec!["b10.jpg", "a.jpg", "b2.jpg", "c.jpg"]);
+        assert_eq!(sort(SortMode::Resolution), vec!["b2.jpg", "b10.jpg", "a.jpg", "c.jpg"]);
+        assert_eq!(sort(SortMode::AspectRatio), vec!["b2.jpg", "b10.jpg", "a.jpg", "c.jpg"]);
+    }
+
+    #[test]
+    fn test_sort_recently_used() {
+        let images = sample_images();
+        let recently_used = vec![PathBuf::from("/walls/c.jpg"), PathBuf::from("/walls/b10.jpg")];
+        let sorter = ImageSorter::new(SortMode::RecentlyUsed, &recently_used, 0);
+        
+        // Used images first by recency, the rest by name
+        assert_eq!(sorted_names(&images, &sorter), vec!["c.jpg", "b10.jpg", "a.jpg", "b2.jpg"]);
+    }
+
+    #[test]
+    fn test_sort_random_is_stable_per_seed() {
+        let images: Vec<ImageInfo> = (0..20).map(|i| image(&format!("{}.jpg", i), 0, 0, None)).collect();
+        
+        let first = sorted_names(&images, &ImageSorter::new(SortMode::Random, &[], 42));
+        let again = sorted_names(&images, &ImageSorter::new(SortMode::Random, &[], 42));
+        let reshuffled = sorted_names(&images, &ImageSorter::new(SortMode::Random, &[], 43));
+        
+        assert_eq!(first, again);
+        assert_ne!(first, reshuffled);
+        assert_ne!(first, sorted_names(&images, &ImageSorter::new(SortMode::Name, &[], 42)));
+    }
+
+    #[test]
+    fn test_args_sort_option() {
+        let args = Args::try_parse_from(["background-picker", "--sort", "aspect-ratio"]).unwrap();
+        assert_eq!(args.sort, Some(SortMode::AspectRatio));
+        
+        let args = Args::try_parse_from(["background-picker"]).unwrap();
+        assert_eq!(args.sort, None);
+        assert_eq!(args.state_file, PathBuf::from("background-picker-state.yaml"));
+        
+        assert!(Args::try_parse_from(["background-picker", "--sort", "colour"]).is_err());
+    }
+
+    #[test]
+    fn test_picker_state_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("nested/state.yaml");
+        
+        // Missing file gives defaults
+        assert_eq!(PickerState::load(&state_file), PickerState::default());
+        
+        let mut state = PickerState { sort_mode: SortMode::Modified, ..Default::default() };
+        state.record_used(std::path::Path::new("/walls/a.jpg"));
+        state.record_used(std::path::Path::new("/walls/b.jpg"));
+        state.record_used(std::path::Path::new("/walls/a.jpg"));
+        assert_eq!(state.recently_used, vec![PathBuf::from("/walls/a.jpg"), PathBuf::from("/walls/b.jpg")]);
+        
+        state.save(&state_file).unwrap();
+        assert_eq!(PickerState::load(&state_file), state);
+        
+        // Unreadable files fall back to defaults rather than failing
+        fs::write(&state_file, "sort_mode: [not, valid").unwrap();
+        assert_eq!(PickerState::load(&state_file), PickerState::default());
+    }
+}
+
+#[cfg(test)]
+mod search_tests {
+    use super::*;
+    use background_picker::fuzzy_match;
+
+    #[test]
+    fn test_fuzzy_match() {
+        let result = fuzzy_match("aurora", "nature/Aurora_Borealis.jpg").unwrap();
+        assert_eq!(result.positions, vec![7, 8, 9, 10, 11, 12]);
+        
+        // Characters may be spread out
+        assert!(fuzzy_match("ntrbrl", "nature/aurora_borealis.jpg").is_some());
+        assert!(fuzzy_match("zebra", "nature/aurora_borealis.jpg").is_none());
+        
+        // Every term has to match somewhere
+        assert!(fuzzy_match("nature bor", "nature/aurora_borealis.jpg").is_some());
+        assert!(fuzzy_match("nature city", "nature/aurora_borealis.jpg").is_none());
+        
+        // Contiguous matches score higher than scattered ones
+        let tight = fuzzy_match("sky", "photos/sky.png").unwrap();
+        let loose = fuzzy_match("sky", "photos/s_k_y.png").unwrap();
+        assert!(tight.score > loose.score);
+    }
+
+    #[test]
+    #[serial]
+    fn test_search_visibility_and_results() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(temp_dir.path().join("nature")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("city")).unwrap();
+        fs::write(temp_dir.path().join("nature/aurora.jpg"), b"fake").unwrap();
+        fs::write(temp_dir.path().join("nature/forest.jpg"), b"fake").unwrap();
+        fs::write(temp_dir.path().join("city/a_u_r_o_r_a.jpg"), b"fake").unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.scan_images().unwrap();
+        
+        assert!(app.visibility_mask(None).iter().all(|&v| v));
+        assert!(!app.is_narrowed());
+        
+        app.search.query = "aurora".to_string();
+        let visible = app.visibility_mask(None);
+        assert_eq!(visible.iter().filter(|&&v| v).count(), 2);
+        assert!(app.is_narrowed());
+        assert_eq!(app.folder_tree.find("nature").unwrap().count_matching(&visible), 1);
+        
+        // The contiguous match ranks above the scattered one
+        let results = app.search_results(&visible);
+        let images = app.images.read().unwrap();
+        let names: Vec<&str> = results.iter().map(|&i| images[i].relative_path.as_str()).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].ends_with("aurora.jpg"), "unexpected order: {:?}", names);
+        drop(images);
+        
+        app.search.query = "  ".to_string();
+        assert!(app.visibility_mask(None).iter().all(|&v| v));
+    }
+}
+
+#[cfg(test)]
+mod preview_tests {
+    use super::*;
+    use background_picker::{format_age, format_file_size};
+    use image::RgbImage;
+    use std::time::Duration;
+
+    #[test]
+    fn test_load_preview_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("large.png");
+        RgbImage::new(400, 200).save(&path).unwrap();
+        
+        // Downscaled to fit, keeping the aspect ratio
+        let preview = BackgroundPickerApp::load_preview_image(&path, (100, 100)).unwrap();
+        assert_eq!(preview.size, [100, 50]);
+        
+        // Small images are never upscaled
+        let preview = BackgroundPickerApp::load_preview_image(&path, (1920, 1080)).unwrap();
+        assert_eq!(preview.size, [400, 200]);
+        
+        fs::write(temp_dir.path().join("broken.png"), b"not an image").unwrap();
+        assert!(BackgroundPickerApp::load_preview_image(&temp_dir.path().join("broken.png"), (100, 100)).is_err());
+    }
+
+    #[test]
+    fn test_format_metadata() {
+        assert_eq!(format_file_size(512), "512 B");
+        assert_eq!(format_file_size(1536), "1.5 KB");
+        assert_eq!(format_file_size(3 * 1024 * 1024), "3.0 MB");
+        
+        assert_eq!(format_age(Duration::from_secs(5)), "just now");
+        assert_eq!(format_age(Duration::from_secs(60)), "1 minute ago");
+        assert_eq!(format_age(Duration::from_secs(3 * 86400)), "3 days ago");
+    }
+}
+
+#[cfg(test)]
+mod try_on_tests {
+    use super::*;
+    use background_picker::TryOn;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    #[serial]
+    fn test_try_on_and_revert() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = temp_dir.path().join("log.txt");
+        let script = temp_dir.path().join("set-wallpaper.sh");
+        fs::write(&script, format!("#!/bin/sh\necho \"$1\" >> {}\n", log.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.command = Some(script.display().to_string());
+        args.selected_image_file = temp_dir.path().join("selected.txt");
+        args.state_file = temp_dir.path().join("state.yaml");
+        args.history_file = temp_dir.path().join("history.yaml");
+        fs::write(&args.selected_image_file, "/walls/original.jpg\n").unwrap();
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        
+        // Trying several images keeps the original to go back to
+        app.try_wallpaper(std::path::Path::new("/walls/a.jpg")).unwrap();
+        app.try_wallpaper(std::path::Path::new("/walls/b.jpg")).unwrap();
+        assert_eq!(app.try_on, Some(TryOn {
+            current: PathBuf::from("/walls/b.jpg"),
+            previous: Some(PathBuf::from("/walls/original.jpg")),
+            monitor: None,
+        }));
+        
+        // Trying never touches the selection file
+        assert_eq!(app.read_selected_image(), Some(PathBuf::from("/walls/original.jpg")));
+        
+        app.revert_try_on().unwrap();
+        assert!(app.try_on.is_none());
+        assert_eq!(fs::read_to_string(&log).unwrap(), "/walls/a.jpg\n/walls/b.jpg\n/walls/original.jpg\n");
+        
+        // Applying commits the tried image, so there is nothing left to revert
+        app.try_wallpaper(std::path::Path::new("/walls/c.jpg")).unwrap();
+        app.apply_wallpaper(std::path::Path::new("/walls/c.jpg")).unwrap();
+        assert!(app.try_on.is_none());
+        assert_eq!(app.read_selected_image(), Some(PathBuf::from("/walls/c.jpg")));
+        assert_eq!(app.current_wallpaper, Some(PathBuf::from("/walls/c.jpg")));
+    }
+
+    #[test]
+    #[serial]
+    fn test_failed_revert_keeps_try_on() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("set-wallpaper.sh");
+        fs::write(&script, "#!/bin/sh
+case \"$1\" in *original*) exit 1;; esac\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.command = Some(script.display().to_string());
+        args.selected_image_file = temp_dir.path().join("selected.txt");
+        fs::write(&args.selected_image_file, "/walls/original.jpg\n").unwrap();
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        
+        app.try_wallpaper(std::path::Path::new("/walls/a.jpg")).unwrap();
+        assert!(app.revert_try_on().is_err());
+        assert_eq!(app.try_on.as_ref().map(|try_on| try_on.current.clone()), Some(PathBuf::from("/walls/a.jpg")));
+        
+        // Once the command works again the revert can be retried
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        app.revert_try_on().unwrap();
+        assert!(app.try_on.is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_close_during_try_waits_then_reverts() {
+        use eframe::egui::{self, ViewportCommand};
+        
+        let temp_dir = TempDir::new().unwrap();
+        let log = temp_dir.path().join("log.txt");
+        let script = temp_dir.path().join("set-wallpaper.sh");
+        fs::write(&script, format!("#!/bin/sh\necho \"$1\" >> {}\n", log.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.command = Some(script.display().to_string());
+        args.selected_image_file = temp_dir.path().join("selected.txt");
+        fs::write(&args.selected_image_file, "/walls/original.jpg\n").unwrap();
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        
+        let ctx = egui::Context::default();
+        let sent = |output: &egui::FullOutput, command: ViewportCommand| {
+            output.viewport_output.values().any(|viewport| viewport.commands.contains(&command))
+        };
+        
+        // The try is still running, so the close is put off
+        let output = ctx.run(Default::default(), |ctx| {
+            app.try_from_ui(ctx, std::path::Path::new("/walls/a.jpg"));
+            app.handle_close_request(ctx);
+        });
+        assert!(sent(&output, ViewportCommand::CancelClose));
+        assert!(app.closing);
+        
+        // Once it finishes the window is closed again
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let output = loop {
+            let output = ctx.run(Default::default(), |ctx| app.poll_pending_command(ctx));
+            if app.pending.is_none() {
+                break output;
+            }
+            assert!(std::time::Instant::now() < deadline, "the try never finished");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert!(sent(&output, ViewportCommand::Close));
+        assert!(!app.closing);
+        assert!(app.try_on.is_some());
+        
+        // and this time the tried wallpaper is reverted on the way out
+        let _ = ctx.run(Default::default(), |ctx| app.handle_close_request(ctx));
+        assert!(app.try_on.is_none());
+        assert_eq!(fs::read_to_string(&log).unwrap(), "/walls/a.jpg\n/walls/original.jpg\n");
+    }
+}
+
+#[cfg(test)]
+mod zoom_tests {
+    use super::*;
+    use background_picker::{ThumbnailBucket, MAX_THUMBNAIL_SIZE, MIN_THUMBNAIL_SIZE};
+
+    fn app_with_cache_dir(temp_dir: &TempDir, cache_dir: PathBuf) -> BackgroundPickerApp {
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        
+        common::test_app(args, cache_dir)
+    }
+
+    #[test]
+    fn test_bucket_for_size() {
+        assert_eq!(ThumbnailBucket::for_size(64), ThumbnailBucket::Normal);
+        assert_eq!(ThumbnailBucket::for_size(128), ThumbnailBucket::Normal);
+        assert_eq!(ThumbnailBucket::for_size(150), ThumbnailBucket::Large);
+        assert_eq!(ThumbnailBucket::for_size(300), ThumbnailBucket::XLarge);
+        assert_eq!(ThumbnailBucket::for_size(1024), ThumbnailBucket::XxLarge);
+        assert_eq!(ThumbnailBucket::for_size(4096), ThumbnailBucket::XxLarge);
+        assert_eq!(ThumbnailBucket::XLarge.dir_name(), "x-large");
+    }
+
+    #[test]
+    fn test_bucket_cache_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        
+        // The spec layout switches to the sibling directory for the bucket
+        let mut app = app_with_cache_dir(&temp_dir, temp_dir.path().join("thumbnails/normal"));
+        app.thumbnail_size = 100;
+        assert_eq!(app.bucket_cache_dir(), temp_dir.path().join("thumbnails/normal"));
+        app.thumbnail_size = 400;
+        assert_eq!(app.bucket_cache_dir(), temp_dir.path().join("thumbnails/x-large"));
+        
+        // Custom cache directories are used as they are
+        let app = app_with_cache_dir(&temp_dir, temp_dir.path().join("cache"));
+        assert_eq!(app.bucket_cache_dir(), temp_dir.path().join("cache"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_thumbnail_size() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("a.jpg"), b"fake").unwrap();
+        let mut app = app_with_cache_dir(&temp_dir, temp_dir.path().join("cache"));
+        app.scan_images().unwrap();
+        app.images.write().unwrap()[0].loading = true;
+        
+        // Same bucket: nothing to reload
+        app.set_thumbnail_size(200);
+        assert_eq!(app.thumbnail_size, 200);
+        assert_eq!(app.state.thumbnail_size, Some(200));
+        assert!(app.images.read().unwrap()[0].loading);
+        
+        // New bucket: thumbnails are requested again
+        app.set_thumbnail_size(600);
+        assert!(!app.images.read().unwrap()[0].loading);
+        
+        app.set_thumbnail_size(1);
+        assert_eq!(app.thumbnail_size, MIN_THUMBNAIL_SIZE);
+        app.set_thumbnail_size(100_000);
+        assert_eq!(app.thumbnail_size, MAX_THUMBNAIL_SIZE);
+    }
+
+    #[test]
+    #[serial]
+    fn test_explicit_size_beats_remembered_zoom() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        PickerState { thumbnail_size: Some(400), ..Default::default() }.save(&state_file).unwrap();
+        
+        let app_for = |size: &[&str]| {
+            let mut args = Args::try_parse_from([&["background-picker"], size].concat()).unwrap();
+            args.directory = temp_dir.path().to_path_buf();
+            args.state_file = state_file.clone();
+            args.history_file = temp_dir.path().join("history.yaml");
+            BackgroundPickerApp::from_args(args).unwrap()
+        };
+        assert_eq!(app_for(&[]).thumbnail_size, 400);
+        assert_eq!(app_for(&["-t", "150"]).thumbnail_size, 150);
+    }
+
+    #[test]
+    #[serial]
+    fn test_results_for_another_bucket_are_dropped() {
+        use background_picker::ThumbnailJob;
+        
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("a.jpg"), b"fake").unwrap();
+        let mut app = app_with_cache_dir(&temp_dir, temp_dir.path().join("cache"));
+        app.scan_images().unwrap();
+        app.images.write().unwrap()[0].loading = true;
+        
+        let ctx = eframe::egui::Context::default();
+        let job = |size| ThumbnailJob {
+            index: 0,
+            path: temp_dir.path().join("a.jpg"),
+            size,
+            cache_dir: temp_dir.path().join("cache"),
+            quality: ThumbnailQuality::default(),
+        };
+        let color_image = || Some(eframe::egui::ColorImage::new([4, 4], eframe::egui::Color32::WHITE));
+        
+        // Decoded before zooming into the next bucket
+        app.thumbnail_sender.send((job(ThumbnailBucket::Normal.pixels()), color_image())).unwrap();
+        app.process_thumbnail_results(&ctx);
+        assert!(app.images.read().unwrap()[0].thumbnail.is_none());
+        assert!(!app.images.read().unwrap()[0].loading);
+        
+        app.thumbnail_sender.send((job(app.thumbnail_bucket().pixels()), color_image())).unwrap();
+        app.process_thumbnail_results(&ctx);
+        assert!(app.images.read().unwrap()[0].thumbnail.is_some());
+    }
+}
+
+#[cfg(test)]
+mod thumbnail_queue_tests {
+    use super::*;
+    use background_picker::ThumbnailJob;
+
+    fn job(index: usize) -> ThumbnailJob {
+        ThumbnailJob {
+            index,
+            path: PathBuf::from(format!("/walls/{}.jpg", index)),
+            size: 256,
+            cache_dir: PathBuf::from("/cache"),
+            quality: ThumbnailQuality::default(),
+        }
+    }
+
+    #[test]
+    fn test_latest_requests_run_first() {
+        let queue = ThumbnailQueue::default();
+        queue.begin_frame();
+        assert!(queue.request(job(1)));
+        assert!(queue.request(job(2)));
+        assert!(!queue.request(job(1)), "already queued jobs need no new worker");
+        
+        // Next frame the user scrolled: 3 and 2 are on screen, in that order
+        queue.begin_frame();
+        assert!(queue.request(job(3)));
+        queue.request(job(2));
+        
+        assert_eq!(queue.pop().map(|j| j.index), Some(3));
+        assert_eq!(queue.pop().map(|j| j.index), Some(2));
+        assert_eq!(queue.pop().map(|j| j.index), Some(1));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_cancel_stale_jobs() {
+        let queue = ThumbnailQueue::default();
+        queue.begin_frame();
+        queue.request(job(1));
+        queue.request(job(2));
+        assert!(queue.cancel_stale().is_empty());
+        
+        queue.begin_frame();
+        queue.request(job(2));
+        assert_eq!(queue.cancel_stale(), vec![1]);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_running_jobs_are_not_queued_again() {
+        let queue = ThumbnailQueue::default();
+        queue.begin_frame();
+        assert!(queue.request(job(1)));
+        assert_eq!(queue.pop().map(|j| j.index), Some(1));
+        
+        // The tile keeps asking every frame while its thumbnail is decoded
+        queue.begin_frame();
+        assert!(!queue.request(job(1)));
+        assert_eq!(queue.pop(), None);
+        
+        queue.finish(1);
+        assert!(queue.request(job(1)));
+    }
+
+    #[test]
+    #[serial]
+    fn test_failed_thumbnail_is_not_retried() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("broken.jpg"), b"not a jpeg").unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.scan_images().unwrap();
+        
+        let ctx = eframe::egui::Context::default();
+        app.load_thumbnail(&ctx, 0);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while app.images.read().unwrap()[0].loading && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            app.process_thumbnail_results(&ctx);
+        }
+        
+        {
+            let images = app.images.read().unwrap();
+            assert!(!images[0].loading);
+            assert!(images[0].thumbnail_failed);
+        }
+        app.load_thumbnail(&ctx, 0);
+        assert!(app.thumbnail_queue.is_empty());
+        assert!(!app.images.read().unwrap()[0].loading);
+    }
+
+    #[test]
+    #[serial]
+    fn test_cancelled_jobs_reset_loading() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("a.jpg"), b"fake").unwrap();
+        fs::write(temp_dir.path().join("b.jpg"), b"fake").unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.scan_images().unwrap();
+        
+        // Queue both directly so no worker picks them up
+        app.thumbnail_queue.begin_frame();
+        for index in 0..2 {
+            app.images.write().unwrap()[index].loading = true;
+            app.thumbnail_queue.request(job(index));
+        }
+        
+        app.thumbnail_queue.begin_frame();
+        app.thumbnail_queue.request(job(1));
+        app.cancel_stale_thumbnails();
+        
+        let images = app.images.read().unwrap();
+        assert!(!images[0].loading);
+        assert!(images[1].loading);
+    }
+
+    #[test]
+    #[serial]
+    fn test_finished_thumbnail_wakes_ui() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        
+        let temp_dir = TempDir::new().unwrap();
+        image::RgbImage::new(64, 48).save(temp_dir.path().join("a.png")).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.scan_images().unwrap();
+        
+        let ctx = eframe::egui::Context::default();
+        let woken = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&woken);
+        ctx.set_request_repaint_callback(move |_| flag.store(true, Ordering::SeqCst));
+        
+        app.load_thumbnail(&ctx, 0);
+        let (job, _) = app.thumbnail_receiver.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(job.index, 0);
+        
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !woken.load(Ordering::SeqCst) && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(woken.load(Ordering::SeqCst));
+    }
+}
+
+#[cfg(test)]
+mod tile_layout_tests {
+    use background_picker::{crop_uv, letterbox_rect};
+    use eframe::egui::{pos2, vec2, Rect};
+
+    #[test]
+    fn test_letterbox_rect() {
+        let cell = Rect::from_min_size(pos2(10.0, 10.0), vec2(100.0, 100.0));
+        
+        // Landscape: full width, bars above and below
+        let wide = letterbox_rect(cell, 2.0);
+        assert_eq!(wide, Rect::from_min_size(pos2(10.0, 35.0), vec2(100.0, 50.0)));
+        
+        // Portrait: full height, bars at the sides
+        let tall = letterbox_rect(cell, 0.5);
+        assert_eq!(tall, Rect::from_min_size(pos2(35.0, 10.0), vec2(50.0, 100.0)));
+        
+        assert_eq!(letterbox_rect(cell, 1.0), cell);
+        assert_eq!(letterbox_rect(cell, 0.0), cell);
+    }
+
+    #[test]
+    fn test_crop_uv() {
+        assert_eq!(crop_uv(2.0), Rect::from_min_max(pos2(0.25, 0.0), pos2(0.75, 1.0)));
+        assert_eq!(crop_uv(0.5), Rect::from_min_max(pos2(0.0, 0.25), pos2(1.0, 0.75)));
+        assert_eq!(crop_uv(1.0), Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)));
+        assert_eq!(crop_uv(f32::NAN), Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)));
+    }
+}
+
+#[cfg(test)]
+mod command_template_tests {
+    use super::*;
+    use background_picker::{build_command, expand_command, file_uri, CommandTarget};
+    use std::ffi::OsString;
+
+    fn target() -> CommandTarget {
+        CommandTarget {
+            path: PathBuf::from("/walls/My Pictures/sky 1.jpg"),
+            monitor: Some("DP-1".to_string()),
+            size: Some((2560, 1440)),
+            fit: FitMode::Fill,
+            previous: None,
+        }
+    }
+
+    fn words(args: &[OsString]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_path_appended_without_placeholders() {
+        let args = build_command("feh --bg-max", &target()).unwrap();
+        assert_eq!(words(&args), vec!["feh", "--bg-max", "/walls/My Pictures/sky 1.jpg"]);
+        
+        // Quoted arguments stay together
+        let args = build_command("my-setter --title 'Two words'", &target()).unwrap();
+        assert_eq!(words(&args), vec!["my-setter", "--title", "Two words", "/walls/My Pictures/sky 1.jpg"]);
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let args = build_command("swww img {path} --transition-type wipe", &target()).unwrap();
+        assert_eq!(words(&args), vec!["swww", "img", "/walls/My Pictures/sky 1.jpg", "--transition-type", "wipe"]);
+        
+        let args = build_command("gsettings set org.gnome.desktop.background picture-uri {uri}", &target()).unwrap();
+        assert_eq!(words(&args)[4], "file:///walls/My%20Pictures/sky%201.jpg");
+        
+        let args = build_command("swaybg -o {monitor} -i {path} --size={width}x{height} --dir {dir}", &target()).unwrap();
+        assert_eq!(
+            words(&args),
+            vec!["swaybg", "-o", "DP-1", "-i", "/walls/My Pictures/sky 1.jpg", "--size=2560x1440", "--dir", "/walls/My Pictures"],
+        );
+        
+        // Braces that aren't placeholders are kept
+        let args = build_command("my-setter --json={a:1} --out={{monitor}}", &target()).unwrap();
+        assert_eq!(words(&args)[1..3], ["--json={a:1}", "--out={DP-1}"]);
+    }
+
+    #[test]
+    fn test_placeholder_text_in_values_is_not_expanded() {
+        let target = CommandTarget { path: PathBuf::from("/walls/{monitor} at {width}.png"), ..Default::default() };
+        let args = build_command("swaybg -i {path} --name={path}", &target).unwrap();
+        assert_eq!(words(&args), vec!["swaybg", "-i", "/walls/{monitor} at {width}.png", "--name=/walls/{monitor} at {width}.png"]);
+        
+        let args = build_command("my-setter --dir={dir} --uri={uri}", &CommandTarget { path: PathBuf::from("/{dir}/a.png"), ..Default::default() }).unwrap();
+        assert_eq!(words(&args)[1..3], ["--dir=/{dir}", "--uri=file:///%7Bdir%7D/a.png"]);
+    }
+
+    #[test]
+    fn test_fit_placeholder() {
+        let args = build_command("swaybg -m {fit} -i {path}", &target()).unwrap();
+        assert_eq!(words(&args), vec!["swaybg", "-m", "fill", "-i", "/walls/My Pictures/sky 1.jpg"]);
+        
+        // Only mentioning the image itself stops the path being appended
+        let args = build_command("my-setter --mode={fit} --output {monitor}", &target()).unwrap();
+        assert_eq!(words(&args), vec!["my-setter", "--mode=fill", "--output", "DP-1", "/walls/My Pictures/sky 1.jpg"]);
+        
+        let names: Vec<&str> = FitMode::ALL.iter().map(|fit| fit.name()).collect();
+        assert_eq!(names, vec!["fill", "fit", "center", "tile", "stretch"]);
+    }
+
+    #[test]
+    fn test_previous_placeholder() {
+        let mut target = target();
+        target.previous = Some(PathBuf::from("/walls/old one.png"));
+        
+        // Hooks don't get the path appended
+        let args = expand_command("notify-send 'Wallpaper changed' 'was {previous}'", &target).unwrap();
+        assert_eq!(words(&args), vec!["notify-send", "Wallpaper changed", "was /walls/old one.png"]);
+        let args = build_command("my-setter --from {previous}", &target).unwrap();
+        assert_eq!(words(&args), vec!["my-setter", "--from", "/walls/old one.png", "/walls/My Pictures/sky 1.jpg"]);
+        
+        // Nothing was set before
+        target.previous = None;
+        let args = expand_command("wal --previous={previous} {previous}", &target).unwrap();
+        assert_eq!(words(&args), vec!["wal", "--previous=", ""]);
+    }
+
+    #[test]
+    fn test_unknown_values_and_bad_templates() {
+        let target = CommandTarget { path: PathBuf::from("/walls/a.jpg"), ..Default::default() };
+        assert!(build_command("swaybg -o {monitor} -i {path}", &target).is_err());
+        assert!(build_command("setter {width}", &target).is_err());
+        assert!(build_command("feh 'unterminated", &target).is_err());
+        assert!(build_command("  ", &target).is_err());
+    }
+
+    #[test]
+    fn test_file_uri() {
+        assert_eq!(file_uri(std::path::Path::new("/a/b-c_d.e~f.png")), "file:///a/b-c_d.e~f.png");
+        assert_eq!(file_uri(std::path::Path::new("/a/ä #1.png")), "file:///a/%C3%A4%20%231.png");
+    }
+}
+
+#[cfg(test)]
+mod navigation_tests {
+    use super::*;
+    use background_picker::{navigate_grid, Direction, GridSection};
+
+    fn sections() -> Vec<GridSection> {
+        vec![
+            // 0 1 2
+            // 3 4
+            GridSection { folder: "a".to_string(), indices: vec![0, 1, 2, 3, 4], columns: 3 },
+            GridSection { folder: "empty".to_string(), indices: vec![], columns: 3 },
+            // 5 6
+            GridSection { folder: "b".to_string(), indices: vec![5, 6], columns: 3 },
+        ]
+    }
+
+    #[test]
+    fn test_navigate_within_grid() {
+        let sections = sections();
+        
+        assert_eq!(navigate_grid(&sections, None, Direction::Down), Some(0));
+        assert_eq!(navigate_grid(&sections, Some(0), Direction::Right), Some(1));
+        assert_eq!(navigate_grid(&sections, Some(2), Direction::Right), Some(3));
+        assert_eq!(navigate_grid(&sections, Some(3), Direction::Left), Some(2));
+        assert_eq!(navigate_grid(&sections, Some(1), Direction::Down), Some(4));
+        assert_eq!(navigate_grid(&sections, Some(4), Direction::Up), Some(1));
+        
+        // A short last row clamps to its last tile
+        assert_eq!(navigate_grid(&sections, Some(2), Direction::Down), Some(4));
+        
+        // Edges of the whole grid keep the selection
+        assert_eq!(navigate_grid(&sections, Some(0), Direction::Left), Some(0));
+        assert_eq!(navigate_grid(&sections, Some(1), Direction::Up), Some(1));
+        assert_eq!(navigate_grid(&sections, Some(6), Direction::Right), Some(6));
+        
+        // Unknown selections restart at the top
+        assert_eq!(navigate_grid(&sections, Some(42), Direction::Right), Some(0));
+        assert_eq!(navigate_grid(&[], None, Direction::Right), None);
+    }
+
+    #[test]
+    fn test_visible_rows() {
+        use background_picker::visible_rows;
+        
+        // Rows 100px apart starting at y=0, viewport 250..550
+        assert_eq!(visible_rows(0.0, 100.0, 3000, 250.0, 550.0, 0), 2..6);
+        assert_eq!(visible_rows(0.0, 100.0, 3000, 250.0, 550.0, 2), 0..8);
+        
+        // Grid entirely below or above the viewport
+        assert_eq!(visible_rows(1000.0, 100.0, 10, 0.0, 500.0, 1), 0..0);
+        assert!(visible_rows(-5000.0, 100.0, 10, 0.0, 500.0, 1).is_empty());
+        
+        // Never past the last row
+        assert_eq!(visible_rows(0.0, 100.0, 4, 0.0, 1000.0, 2), 0..4);
+        assert_eq!(visible_rows(0.0, 100.0, 0, 0.0, 1000.0, 2), 0..0);
+    }
+
+    #[test]
+    fn test_navigate_across_folders() {
+        let sections = sections();
+        
+        // Empty sections are skipped
+        assert_eq!(navigate_grid(&sections, Some(4), Direction::Right), Some(5));
+        assert_eq!(navigate_grid(&sections, Some(5), Direction::Left), Some(4));
+        
+        // Vertical moves keep the column where possible
+        assert_eq!(navigate_grid(&sections, Some(4), Direction::Down), Some(6));
+        assert_eq!(navigate_grid(&sections, Some(3), Direction::Down), Some(5));
+        assert_eq!(navigate_grid(&sections, Some(6), Direction::Up), Some(4));
+        assert_eq!(navigate_grid(&sections, Some(2), Direction::Down), Some(4));
+    }
+
+    #[test]
+    #[serial]
+    fn test_folder_grid_order_and_favorites() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(temp_dir.path().join("nature/snow")).unwrap();
+        fs::write(temp_dir.path().join("top.jpg"), b"fake").unwrap();
+        fs::write(temp_dir.path().join("nature/forest.jpg"), b"fake").unwrap();
+        fs::write(temp_dir.path().join("nature/snow/peak.jpg"), b"fake").unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.directory = temp_dir.path().to_path_buf();
+        args.state_file = temp_dir.path().join("state.yaml");
+        args.history_file = temp_dir.path().join("history.yaml");
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.scan_images().unwrap();
+        
+        // Root first, then subfolders before their parent's own images
+        let visible = app.visibility_mask(None);
+        let folders: Vec<String> = app.folder_grid_order(&visible).into_iter().map(|(folder, _)| folder).collect();
+        assert_eq!(folders, vec![".", "nature/snow", "nature"]);
+        
+        // Favorites survive a reload of the state file
+        app.state.favorites.insert(3, temp_dir.path().join("top.jpg"));
+        app.state.save(&app.args.state_file).unwrap();
+        let loaded = PickerState::load(&app.args.state_file);
+        assert_eq!(loaded.favorites.get(&3), Some(&temp_dir.path().join("top.jpg")));
+    }
+}
+
+#[cfg(test)]
+mod monitor_tests {
+    use super::*;
+    use background_picker::{Selection, SelectionFile};
+    use std::path::Path;
+    use background_picker::wallpaper::{parse_hyprctl_monitors, parse_wlr_randr, parse_xrandr, render_span, span_slices, Monitor, SpanPlan, SpanSlice};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn monitor(name: &str, size: (u32, u32), position: (i32, i32)) -> Monitor {
+        Monitor { name: name.to_string(), size, position }
+    }
+
+    #[test]
+    fn test_parse_xrandr() {
+        let output = "\
+Screen 0: minimum 320 x 200, current 6400 x 1440, maximum 16384 x 16384
+DP-1 connected 2560x1440+0+0 (normal left inverted right x axis y axis) 597mm x 336mm
+   2560x1440     59.95*+
+DP-2 disconnected (normal left inverted right x axis y axis)
+HDMI-1 connected primary 1920x1080+2560+0 (normal left inverted right x axis y axis) 527mm x 296mm
+   1920x1080     60.00*+
+DP-3 connected 1920x1080+4480+0 (normal left inverted right x axis y axis) 527mm x 296mm
+eDP-1 connected (normal left inverted right x axis y axis)
+";
+        // Xinerama puts the primary output first, whatever its position
+        assert_eq!(parse_xrandr(output), vec![
+            monitor("HDMI-1", (1920, 1080), (2560, 0)),
+            monitor("DP-1", (2560, 1440), (0, 0)),
+            monitor("DP-3", (1920, 1080), (4480, 0)),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_wlr_randr() {
+        let output = "\
+DP-1 \"Dell Inc. DELL U2718Q (DP-1)\"
+  Enabled: yes
+  Modes:
+    1920x1080 px, 60.000000 Hz
+    3840x2160 px, 59.997002 Hz (preferred, current)
+  Position: 0,0
+  Scale: 1.000000
+HDMI-A-1 \"Unknown\"
+  Enabled: no
+  Modes:
+    1920x1080 px, 60.000000 Hz (preferred)
+eDP-1 \"Sharp\"
+  Enabled: yes
+  Modes:
+    1920x1200 px, 60.000000 Hz (current)
+  Position: 3840,960
+";
+        assert_eq!(parse_wlr_randr(output), vec![
+            monitor("DP-1", (3840, 2160), (0, 0)),
+            monitor("eDP-1", (1920, 1200), (3840, 960)),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_hyprctl_monitors() {
+        let output = "\
+Monitor DP-1 (ID 0):
+\t2560x1440@143.99800 at 0x0
+\tdescription: Some monitor
+Monitor HDMI-A-1 (ID 1):
+\t1920x1080@60.00000 at 2560x180
+";
+        assert_eq!(parse_hyprctl_monitors(output), vec![
+            monitor("DP-1", (2560, 1440), (0, 0)),
+            monitor("HDMI-A-1", (1920, 1080), (2560, 180)),
+        ]);
+    }
+
+    #[test]
+    fn test_selection_file_format() {
+        // Files from older versions hold a single path
+        let legacy = SelectionFile::parse(b"/walls/a.jpg\n");
+        assert_eq!(legacy, SelectionFile { selection: Selection::single(Path::new("/walls/a.jpg")), fit: None, timestamp: None });
+        
+        // ...or a path per monitor
+        let mut selection = legacy.selection.clone();
+        selection.assign(Some("DP-1"), Path::new("/walls/b.jpg"));
+        assert_eq!(SelectionFile::parse(b"/walls/a.jpg\nDP-1\t/walls/b.jpg").selection, selection);
+        
+        let file = SelectionFile { selection: selection.clone(), fit: Some(FitMode::Tile), timestamp: Some(1_700_000_000) };
+        let contents = file.to_file_contents().unwrap();
+        assert_eq!(
+            contents,
+            "version: 1\ntimestamp: 1700000000\nfit: tile\nall: /walls/a.jpg\nmonitors:\n  DP-1: /walls/b.jpg\n"
+        );
+        assert_eq!(SelectionFile::parse(contents.as_bytes()), file);
+        assert_eq!(selection.for_monitor("DP-1"), Some(Path::new("/walls/b.jpg")));
+        assert_eq!(selection.for_monitor("HDMI-1"), Some(Path::new("/walls/a.jpg")));
+        assert!(selection.contains(Path::new("/walls/b.jpg")));
+        
+        // Choosing an image for every monitor drops the per-monitor ones
+        selection.assign(None, Path::new("/walls/c.jpg"));
+        assert_eq!(selection, Selection::single(Path::new("/walls/c.jpg")));
+    }
+
+    #[test]
+    fn test_selection_file_keeps_path_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        
+        let odd = PathBuf::from(OsStr::from_bytes(b"/walls/caf\xe9 100%25 \xff\xfe.jpg"));
+        let mut selection = Selection::single(&odd);
+        selection.assign(Some("DP-1"), Path::new("/walls/ä\tb.jpg"));
+        let file = SelectionFile { selection, fit: Some(FitMode::Fill), timestamp: Some(0) };
+        
+        let contents = file.to_file_contents().unwrap();
+        assert!(contents.contains("/walls/caf%E9 100%2525 %FF%FE.jpg"));
+        assert_eq!(SelectionFile::parse(contents.as_bytes()), file);
+        
+        // Legacy files are read byte for byte too
+        assert_eq!(SelectionFile::parse(b"/walls/caf\xe9 100%25 \xff\xfe.jpg\n").selection.all, Some(odd));
+        
+        // A structured file that doesn't parse isn't misread as a legacy path
+        let broken = SelectionFile::parse(b"version: 1\ntimestamp: 0\nfit: fit\nall: /walls/%G1.jpg\n");
+        assert_eq!(broken, SelectionFile::default());
+        
+        // Nor is one written by a newer version
+        let newer = SelectionFile::parse(b"version: 2\ntimestamp: 0\nfit: fit\nall: /walls/a.jpg\n");
+        assert_eq!(newer, SelectionFile::default());
+        let current = SelectionFile::parse(b"version: 1\ntimestamp: 0\nfit: fit\nall: /walls/a.jpg\n");
+        assert_eq!(current.selection.all, Some(PathBuf::from("/walls/a.jpg")));
+    }
+
+    #[test]
+    #[serial]
+    fn test_save_selection_is_atomic_and_follows_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let real = temp_dir.path().join("dotfiles/selected.txt");
+        fs::create_dir_all(real.parent().unwrap()).unwrap();
+        fs::write(&real, "/walls/old.jpg").unwrap();
+        fs::set_permissions(&real, fs::Permissions::from_mode(0o600)).unwrap();
+        let link = temp_dir.path().join("selected.txt");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.selected_image_file = link.clone();
+        let state = PickerState { fit: FitMode::Center, ..Default::default() };
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.state = state;
+        
+        // The legacy file is read, then replaced with the structured format
+        assert_eq!(app.read_selected_image(), Some(PathBuf::from("/walls/old.jpg")));
+        app.save_selected_image(Path::new("/walls/new.jpg")).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::metadata(&real).unwrap().permissions().mode() & 0o777, 0o600);
+        
+        let file = app.read_selection_file();
+        assert_eq!(file.selection, Selection::single(Path::new("/walls/new.jpg")));
+        assert_eq!(file.fit, Some(FitMode::Center));
+        assert!(file.timestamp.unwrap() > 1_700_000_000);
+        assert_eq!(fs::read_dir(real.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_apply_runs_hooks() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = temp_dir.path().join("log.txt");
+        
+        let mut args = Args::try_parse_from([
+            "background-picker",
+            "--command", "true",
+            "--hook", &format!("sh -c 'echo \"hook $0 $1\" >> {}' {{path}} {{previous}}", log.display()),
+            "--hook", "false",
+            "--hook", &format!("sh -c 'echo second >> {}'", log.display()),
+        ]).unwrap();
+        args.selected_image_file = temp_dir.path().join("selected.txt");
+        args.state_file = temp_dir.path().join("state.yaml");
+        args.history_file = temp_dir.path().join("history.yaml");
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.current_wallpaper = Some(PathBuf::from("/walls/original.jpg"));
+        app.monitors = MonitorState {
+            available: vec![monitor("DP-1", (2560, 1440), (0, 0)), monitor("HDMI-1", (1920, 1080), (2560, 0))],
+            target: Some("HDMI-1".to_string()),
+            span: false,
+            assigned: Default::default(),
+        };
+        
+        // The failing hook warns, but the wallpaper is kept and later hooks still run
+        let result = app.apply_wallpaper(Path::new("/walls/side.jpg"));
+        assert!(matches!(result, Err(BackgroundPickerError::CommandFailed(_))));
+        assert_eq!(app.monitors.assigned.get("HDMI-1"), Some(&PathBuf::from("/walls/side.jpg")));
+        assert_eq!(fs::read_to_string(&log).unwrap(), "hook /walls/side.jpg /walls/original.jpg\nsecond\n");
+        
+        // A stop hook skips the rest; ignored failures don't count
+        fs::remove_file(&log).unwrap();
+        app.args.hooks[1].policy = HookPolicy::Stop;
+        app.monitors.target = None;
+        assert!(app.apply_wallpaper(Path::new("/walls/all.jpg")).is_err());
+        assert_eq!(fs::read_to_string(&log).unwrap(), "hook /walls/all.jpg /walls/original.jpg\n");
+        
+        fs::remove_file(&log).unwrap();
+        app.args.hooks[1].policy = HookPolicy::Ignore;
+        app.apply_wallpaper(Path::new("/walls/next.jpg")).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "hook /walls/next.jpg /walls/all.jpg\nsecond\n");
+    }
+
+    #[test]
+    #[serial]
+    fn test_apply_and_try_per_monitor() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = temp_dir.path().join("log.txt");
+        let script = temp_dir.path().join("set-wallpaper.sh");
+        fs::write(&script, format!("#!/bin/sh\necho \"$1 $2\" >> {}\n", log.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker"]).unwrap();
+        args.command = Some(format!("{} {{monitor}} {{path}}", script.display()));
+        args.selected_image_file = temp_dir.path().join("selected.txt");
+        args.state_file = temp_dir.path().join("state.yaml");
+        args.history_file = temp_dir.path().join("history.yaml");
+        fs::write(&args.selected_image_file, "/walls/original.jpg").unwrap();
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.current_wallpaper = Some(PathBuf::from("/walls/original.jpg"));
+        app.monitors = MonitorState {
+            available: vec![monitor("DP-1", (2560, 1440), (0, 0)), monitor("HDMI-1", (1920, 1080), (2560, 0))],
+            target: Some("HDMI-1".to_string()),
+            span: false,
+            assigned: Default::default(),
+        };
+        
+        // Every monitor is set, so backends that redraw all outputs keep the others
+        app.apply_wallpaper(Path::new("/walls/side.jpg")).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "DP-1 /walls/original.jpg\nHDMI-1 /walls/side.jpg\n");
+        let mut expected = Selection::single(Path::new("/walls/original.jpg"));
+        expected.assign(Some("HDMI-1"), Path::new("/walls/side.jpg"));
+        assert_eq!(app.read_selection(), expected);
+        assert_eq!(app.monitors.assigned.get("HDMI-1"), Some(&PathBuf::from("/walls/side.jpg")));
+        
+        // Trying on one monitor and reverting puts the committed images back
+        fs::remove_file(&log).unwrap();
+        app.monitors.target = Some("DP-1".to_string());
+        app.try_wallpaper(Path::new("/walls/maybe.jpg")).unwrap();
+        app.revert_try_on().unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "DP-1 /walls/maybe.jpg\nHDMI-1 /walls/side.jpg\nDP-1 /walls/original.jpg\nHDMI-1 /walls/side.jpg\n"
+        );
+    }
+
+    #[test]
+    fn test_span_slices_side_by_side() {
+        // Three 1920x1080 screens and a 5760x1080 panorama: one third each
+        let monitors = [
+            monitor("DP-1", (1920, 1080), (0, 0)),
+            monitor("DP-2", (1920, 1080), (1920, 0)),
+            monitor("DP-3", (1920, 1080), (3840, 0)),
+        ];
+        let slices = span_slices((5760, 1080), &monitors, 0);
+        assert_eq!(slices, vec![
+            SpanSlice { monitor: "DP-1".to_string(), source: (0, 0, 1920, 1080), size: (1920, 1080) },
+            SpanSlice { monitor: "DP-2".to_string(), source: (1920, 0, 1920, 1080), size: (1920, 1080) },
+            SpanSlice { monitor: "DP-3".to_string(), source: (3840, 0, 1920, 1080), size: (1920, 1080) },
+        ]);
+        
+        // Bezels hide a strip of the image between screens, and the wider
+        // layout crops the top and bottom instead of the sides
+        let slices = span_slices((5760, 1080), &monitors, 60);
+        let scale: f64 = 5760.0 / 5880.0;
+        assert_eq!(slices[0].source.0, 0);
+        assert_eq!(slices[1].source.0, (1980.0 * scale).round() as u32);
+        assert_eq!(slices[2].source.0, (3960.0 * scale).round() as u32);
+        assert!(slices[1].source.1 > 0);
+        assert!(slices.iter().all(|slice| slice.size == (1920, 1080)));
+    }
+
+    #[test]
+    fn test_span_slices_mixed_resolutions() {
+        // A 4K screen next to a 1080p one share one scale, so the smaller
+        // screen shows a proportionally smaller part of the image
+        let monitors = [
+            monitor("DP-1", (3840, 2160), (0, 0)),
+            monitor("HDMI-1", (1920, 1080), (3840, 0)),
+        ];
+        let slices = span_slices((2880, 1080), &monitors, 0);
+        assert_eq!(slices[0].source, (0, 0, 1920, 1080));
+        assert_eq!(slices[1].source, (1920, 0, 960, 540));
+        assert_eq!(slices[1].size, (1920, 1080));
+    }
+
+    #[test]
+    fn test_render_span_caches_slices() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("wide.png");
+        image::RgbImage::from_fn(400, 100, |x, _| if x < 200 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) })
+            .save(&source)
+            .unwrap();
+        let cache_dir = temp_dir.path().join("span");
+        let monitors = [monitor("LEFT", (80, 40), (0, 0)), monitor("RIGHT", (80, 40), (80, 0))];
+        
+        // Planning names the files without cutting anything
+        let plan = SpanPlan::new(&source, &monitors, 0, &cache_dir).unwrap();
+        assert!(!cache_dir.exists());
+        
+        let slices = render_span(&source, &monitors, 0, &cache_dir).unwrap();
+        assert_eq!(plan.files(), slices);
+        assert_eq!(slices.len(), 2);
+        assert_eq!(slices[0].0, "LEFT");
+        let left = image::open(&slices[0].1).unwrap().to_rgb8();
+        let right = image::open(&slices[1].1).unwrap().to_rgb8();
+        assert_eq!(left.dimensions(), (80, 40));
+        assert_eq!(left.get_pixel(10, 20), &image::Rgb([255, 0, 0]));
+        assert_eq!(right.get_pixel(70, 20), &image::Rgb([0, 0, 255]));
+        
+        // The same image on the same layout reuses the cached files
+        let modified = fs::metadata(&slices[0].1).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(render_span(&source, &monitors, 0, &cache_dir).unwrap(), slices);
+        assert_eq!(fs::metadata(&slices[0].1).unwrap().modified().unwrap(), modified);
+        
+        // A different layout gets its own slices
+        let other = render_span(&source, &monitors, 20, &cache_dir).unwrap();
+        assert_ne!(other[1].1, slices[1].1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_apply_spanned_wallpaper() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = temp_dir.path().join("log.txt");
+        let script = temp_dir.path().join("set-wallpaper.sh");
+        fs::write(&script, format!("#!/bin/sh\necho \"$1 $2\" >> {}\n", log.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let source = temp_dir.path().join("wide.png");
+        image::RgbImage::new(200, 50).save(&source).unwrap();
+        
+        let mut args = Args::try_parse_from(["background-picker", "--span", "--bezel", "10"]).unwrap();
+        args.command = Some(format!("{} {{monitor}} {{path}}", script.display()));
+        args.selected_image_file = temp_dir.path().join("selected.txt");
+        args.state_file = temp_dir.path().join("state.yaml");
+        args.history_file = temp_dir.path().join("history.yaml");
+        
+        let mut app = common::test_app(args, temp_dir.path().join("cache"));
+        app.monitors = MonitorState {
+            available: vec![monitor("DP-1", (100, 50), (0, 0)), monitor("DP-2", (100, 50), (100, 0))],
+            target: None,
+            span: true,
+            assigned: Default::default(),
+        };
+        
+        app.apply_wallpaper(&source).unwrap();
+        
+        // Each monitor gets its own slice, and the source stays the current wallpaper
+        let calls = fs::read_to_string(&log).unwrap();
+        let calls: Vec<&str> = calls.lines().collect();
+        assert_eq!(calls.len(), 2);
+        for (call, name) in calls.iter().zip(["DP-1", "DP-2"]) {
+            let slice = call.strip_prefix(&format!("{} ", name)).unwrap();
+            assert!(slice.starts_with(&app.span_cache_dir().display().to_string()));
+            assert_eq!(image::image_dimensions(slice).unwrap(), (100, 50));
+        }
+        assert_eq!(app.current_wallpaper, Some(source.clone()));
+        assert_eq!(app.read_selection().monitors.len(), 2);
+        
+        // Spanning another image drops the slices it replaces
+        let first: Vec<PathBuf> = app.read_selection().monitors.into_values().collect();
+        let other = temp_dir.path().join("other.png");
+        image::RgbImage::new(400, 100).save(&other).unwrap();
+        app.apply_wallpaper(&other).unwrap();
+        let span_dir = app.span_cache_dir();
+        let slice_count = || fs::read_dir(&span_dir).unwrap().count();
+        assert!(first.iter().all(|slice| !slice.exists()));
+        assert_eq!(slice_count(), 2);
+        
+        // and undoing back to the first image cuts them again
+        app.undo().unwrap();
+        assert!(first.iter().all(|slice| slice.exists()));
+        assert_eq!(slice_count(), 2);
+    }
+}
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+    use background_picker::{format_history, History, HistoryEntry, HISTORY_LIMIT};
+
+    fn entry(path: &str, monitor: Option<&str>) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: 0,
+            path: PathBuf::from(path),
+            backend: "feh".to_string(),
+            monitor: monitor.map(str::to_owned),
+            span: false,
+        }
+    }
+
+    #[test]
+    fn test_record_drops_undone_entries() {
+        let mut history = History::default();
+        history.record(entry("/walls/a.jpg", None));
+        history.record(entry("/walls/b.jpg", None));
+        history.record(entry("/walls/c.jpg", None));
+        assert_eq!(history.position(), 3);
+        
+        history.undone = 2;
+        assert_eq!(history.position(), 1);
+        history.record(entry("/walls/d.jpg", None));
+        let paths: Vec<&str> = history.entries.iter().map(|e| e.path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["/walls/a.jpg", "/walls/d.jpg"]);
+        assert_eq!(history.undone, 0);
+    }
+
+    #[test]
+    fn test_in_effect_starts_at_last_full_apply() {
+        let mut history = History::default();
+        history.record(entry("/walls/a.jpg", Some("DP-1")));
+        history.record(entry("/walls/b.jpg", None));
+        history.record(entry("/walls/c.jpg", Some("DP-1")));
+        history.record(entry("/walls/d.jpg", None));
+        
+        let paths = |entries: &[HistoryEntry]| entries.iter().map(|e| e.path.to_str().unwrap().to_owned()).collect::<Vec<_>>();
+        assert_eq!(paths(history.in_effect(3)), vec!["/walls/b.jpg", "/walls/c.jpg"]);
+        assert_eq!(paths(history.in_effect(1)), vec!["/walls/a.jpg"]);
+        assert_eq!(paths(history.in_effect(4)), vec!["/walls/d.jpg"]);
+        assert!(history.in_effect(0).is_empty());
+    }
+
+    #[test]
+    fn test_history_file_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("nested/history.yaml");
+        assert_eq!(History::load(&file).unwrap(), History::default());
+        
+        let mut history = History::default();
+        history.record(entry("/walls/a.jpg", None));
+        history.record(HistoryEntry { span: true, ..entry("/walls/pano.jpg", None) });
+        history.record(entry("/walls/b.jpg", Some("HDMI-1")));
+        history.undone = 1;
+        history.save(&file).unwrap();
+        assert_eq!(History::load(&file).unwrap(), history);
+        
+        // Unset optional fields are left out
+        let contents = fs::read_to_string(&file).unwrap();
+        assert_eq!(contents.matches("monitor:").count(), 1);
+        assert_eq!(contents.matches("span:").count(), 1);
+    }
+
+    #[test]
+    fn test_history_load_rejects_unparsable_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("history.yaml");
+        fs::write(&file, "entries: [not an entry").unwrap();
+        assert!(matches!(History::load(&file), Err(BackgroundPickerError::LoadHistory(_))));
+    }
+
+    #[test]
+    fn test_history_keeps_newest_entries_up_to_limit() {
+        let mut history = History::default();
+        for i in 0..HISTORY_LIMIT + 3 {
+            history.record(entry(&format!("/walls/{}.jpg", i), None));
+        }
+        assert_eq!(history.entries.len(), HISTORY_LIMIT);
+        assert_eq!(history.entries[0].path, PathBuf::from("/walls/3.jpg"));
+        assert_eq!(history.entries.last().unwrap().path, PathBuf::from(format!("/walls/{}.jpg", HISTORY_LIMIT + 2)));
+    }
+
+    #[test]
+    fn test_format_history() {
+        assert_eq!(format_history(&History::default()), "No wallpapers applied yet\n");
+        
+        let mut history = History::default();
+        history.record(entry("/walls/a.jpg", None));
+        history.record(entry("/walls/b.jpg", Some("HDMI-1")));
+        history.undone = 1;
+        
+        let listing = format_history(&history);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("   1  "));
+        assert!(lines[0].contains("HDMI-1"));
+        assert!(lines[0].ends_with("/walls/b.jpg  (undone)"));
+        assert!(lines[1].starts_with("   2  "));
+        assert!(lines[1].contains("all monitors"));
+        assert!(lines[1].ends_with("/walls/a.jpg"));
+    }
+}